@@ -1,21 +1,283 @@
 use js_sys::Reflect;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_downcast::DowncastJS;
 
+use crate::glob;
+use crate::idb;
+use crate::path;
+use crate::sandbox::Sandbox;
+use crate::snapshot::{NodeKind, Snapshot, SnapshotNode};
+use crate::streams::{self, Chunk, ReadableStream, WritableStream};
+use crate::watch::{FSWatcher, FsEvent, Watchers};
 use wasmer_vfs::mem_fs::FileSystem as MemoryFilesystem;
 use wasmer_vfs::{
-    DirEntry, FileSystem, FileType, FsError, Metadata, OpenOptions, ReadDir, VirtualFile,
+    DirEntry, FileOpener, FileSystem, FileType, FsError, Metadata, OpenOptions, OpenOptionsConfig,
+    ReadDir, VirtualFile,
 };
 
+/// The maximum number of symlinks followed when resolving a path
+const MAX_SYMLINKS: usize = 40;
+
+#[wasm_bindgen(typescript_custom_section)]
+const MEMFS_OPTIONS_TYPE_DEFINITION: &str = r#"
+/** Options used when creating a new MemFS. */
+export type MemFSOptions = {
+    /** The user id the filesystem is accessed as, which owns new files (0 by default). */
+    readonly uid?: number;
+    /** The group id the filesystem is accessed as, which owns new files (0 by default). */
+    readonly gid?: number;
+    /** The absolute path relative paths start from, `/` by default. */
+    readonly cwd?: string;
+    /** Deny writes that the permissions don't allow, for the guest and JS alike. */
+    readonly enforcePermissions?: boolean;
+    /** The maximum number of bytes the files can hold together. */
+    readonly maxBytes?: number;
+    /** The maximum number of files, directories and links. */
+    readonly maxFiles?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "MemFSOptions")]
+    pub type MemFSOptions;
+}
+
 #[wasm_bindgen]
 #[derive(Debug, Clone, DowncastJS)]
 pub struct MemFS {
     inner: Arc<MemoryFilesystem>,
+    nodes: Arc<Mutex<Nodes>>,
+    user: User,
+    limits: Limits,
+    /// The directory relative paths start from
+    cwd: PathBuf,
+    watchers: Watchers,
+    /// The preopens of the guest, for the copy that wasmer-wasi resolves the
+    /// guest paths with
+    sandbox: Option<Arc<Sandbox>>,
+}
+
+/// What a `MemFS` keeps track of about its files and directories, on top of
+/// what the underlying filesystem supports.
+///
+/// Everything is keyed by path, with all the symlinks in the parents resolved.
+/// Links are stored as files in the underlying filesystem, so they show up
+/// when reading their directory.
+#[derive(Debug, Default)]
+struct Nodes {
+    /// The targets of the symbolic links
+    symlinks: HashMap<PathBuf, PathBuf>,
+    /// The paths that the hard links point to, where the file is stored
+    hard_links: HashMap<PathBuf, PathBuf>,
+    /// The permissions that were changed from the defaults
+    permissions: HashMap<PathBuf, Permissions>,
+    /// The timestamps that were set explicitly
+    times: HashMap<PathBuf, Times>,
+    /// The contents of the files shared with other filesystems
+    shared: HashMap<PathBuf, Shared>,
+    /// The paths of the open files by id, which follow the files when they are
+    /// renamed, until they are removed
+    open_files: HashMap<u64, PathBuf>,
+    next_open_file: u64,
+    usage: Usage,
+    /// Whether a write went past the limits since it was last checked, as
+    /// wasmer-wasi gives the guest `EIO` for every failed write
+    full: bool,
+}
+
+impl Nodes {
+    /// Updates the nodes after the file or directory at `from` was renamed to `to`
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let rename = |path: &Path| match path.strip_prefix(from) {
+            Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+            Ok(rest) => to.join(rest),
+            Err(_) => path.to_path_buf(),
+        };
+        self.symlinks = self
+            .symlinks
+            .drain()
+            .map(|(path, target)| (rename(&path), target))
+            .collect();
+        self.hard_links = self
+            .hard_links
+            .drain()
+            .map(|(path, target)| (rename(&path), rename(&target)))
+            .collect();
+        self.permissions = self
+            .permissions
+            .drain()
+            .map(|(path, permissions)| (rename(&path), permissions))
+            .collect();
+        self.times = self
+            .times
+            .drain()
+            .map(|(path, times)| (rename(&path), times))
+            .collect();
+        self.shared = self
+            .shared
+            .drain()
+            .map(|(path, shared)| (rename(&path), shared))
+            .collect();
+        for path in self.open_files.values_mut() {
+            *path = rename(path);
+        }
+    }
+
+    /// Applies what is tracked here about the resolved `path` to the metadata
+    /// of the underlying filesystem
+    fn apply(&self, path: &Path, mut metadata: Metadata) -> Metadata {
+        if let Some(times) = self.times.get(path) {
+            metadata.accessed = times.accessed.unwrap_or(metadata.accessed);
+            metadata.modified = times.modified.unwrap_or(metadata.modified);
+        }
+        if let Some(shared) = self.shared.get(path) {
+            metadata.len = shared.contents.len() as u64;
+        }
+        metadata
+    }
+
+    /// Checks that `files` more nodes and `bytes` more bytes fit in `limits`
+    fn check_usage(&self, limits: &Limits, files: i64, bytes: i64) -> Result<(), FsError> {
+        let exceeds = |used: u64, delta: i64, max: Option<u64>| {
+            delta > 0 && max.map_or(false, |max| used + delta as u64 > max)
+        };
+        if exceeds(self.usage.files, files, limits.max_files)
+            || exceeds(self.usage.bytes, bytes, limits.max_bytes)
+        {
+            // The guest gets `ENOSPC` for this error
+            Err(FsError::WriteZero)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn update_usage(&mut self, files: i64, bytes: i64) {
+        let update = |used: u64, delta: i64| {
+            if delta >= 0 {
+                used.saturating_add(delta as u64)
+            } else {
+                used.saturating_sub(delta.unsigned_abs())
+            }
+        };
+        self.usage.files = update(self.usage.files, files);
+        self.usage.bytes = update(self.usage.bytes, bytes);
+    }
+
+    /// Forgets about the file or directory at `path`
+    fn remove(&mut self, path: &Path) {
+        self.symlinks.remove(path);
+        self.hard_links.remove(path);
+        self.permissions.remove(path);
+        self.times.remove(path);
+        self.shared.remove(path);
+        self.open_files.retain(|_, open| open != path);
+    }
+
+    /// Keeps track of the path of a file being opened, returning its id
+    fn open_file(&mut self, path: PathBuf) -> u64 {
+        let id = self.next_open_file;
+        self.next_open_file += 1;
+        self.open_files.insert(id, path);
+        id
+    }
+}
+
+/// The contents of a file, shared with other filesystems until it is changed
+#[derive(Debug, Clone)]
+struct Shared {
+    contents: Arc<Vec<u8>>,
+    /// Whether the contents were also written to the underlying filesystem,
+    /// otherwise the file is empty there
+    materialized: bool,
+}
+
+/// Unix-style permissions and ownership
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Permissions {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl Permissions {
+    const READ: u32 = 0o4;
+    const WRITE: u32 = 0o2;
+    const EXECUTE: u32 = 0o1;
+
+    /// Whether `user` may access the node with all of the `access` bits
+    /// (a combination of `READ`, `WRITE` and `EXECUTE`)
+    fn allows(&self, user: &User, access: u32) -> bool {
+        if user.uid == 0 {
+            // root can read and write anything, and execute what anyone can
+            return access & Self::EXECUTE == 0 || self.mode & 0o111 != 0;
+        }
+        let granted = if user.uid == self.uid {
+            self.mode >> 6
+        } else if user.gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        granted & access == access
+    }
 }
 
-fn metadata_to_object(metadata: &Metadata) -> Result<js_sys::Object, JsValue> {
+/// Timestamps set explicitly, in nanoseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, Default)]
+struct Times {
+    accessed: Option<u64>,
+    modified: Option<u64>,
+}
+
+/// The number of nodes and bytes of file contents stored in a `MemFS`
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    files: u64,
+    bytes: u64,
+}
+
+/// The quotas of a `MemFS`
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    max_files: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+/// The user that a `MemFS` is accessed as
+#[derive(Debug, Clone, Copy, Default)]
+struct User {
+    uid: u32,
+    gid: u32,
+    /// Whether writes are denied when the permissions don't allow them
+    enforce_permissions: bool,
+}
+
+/// Gets the number or bigint `object[key]`, if it is set
+fn get_u64(object: &JsValue, key: &str) -> Result<Option<u64>, JsValue> {
+    let value = Reflect::get(object, &key.into())?;
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    js_sys::BigInt::new(&value)
+        .ok()
+        .and_then(|value| u64::try_from(value).ok())
+        .map(Some)
+        .ok_or_else(|| {
+            js_sys::Error::new(&format!("The `{}` must be a positive integer", key)).into()
+        })
+}
+
+fn metadata_to_object(
+    metadata: &Metadata,
+    permissions: &Permissions,
+) -> Result<js_sys::Object, JsValue> {
     let metadata_obj = js_sys::Object::new();
     Reflect::set(
         &metadata_obj,
@@ -26,6 +288,9 @@ fn metadata_to_object(metadata: &Metadata) -> Result<js_sys::Object, JsValue> {
     Reflect::set(&metadata_obj, &"created".into(), &metadata.created.into())?;
     Reflect::set(&metadata_obj, &"modified".into(), &metadata.modified.into())?;
     // Reflect::set(&metadata_obj, &"len".into(), &metadata.len.into())?;
+    Reflect::set(&metadata_obj, &"mode".into(), &permissions.mode.into())?;
+    Reflect::set(&metadata_obj, &"uid".into(), &permissions.uid.into())?;
+    Reflect::set(&metadata_obj, &"gid".into(), &permissions.gid.into())?;
     Ok(metadata_obj)
 }
 
@@ -41,7 +306,10 @@ fn filetype_to_object(filetype: &FileType) -> Result<js_sys::Object, JsValue> {
     Ok(filetype_obj)
 }
 
-fn direntry_to_object(direntry: &DirEntry) -> Result<js_sys::Object, JsValue> {
+fn direntry_to_object(
+    direntry: &DirEntry,
+    permissions: &Permissions,
+) -> Result<js_sys::Object, JsValue> {
     let direntry_obj = js_sys::Object::new();
     Reflect::set(
         &direntry_obj,
@@ -51,7 +319,7 @@ fn direntry_to_object(direntry: &DirEntry) -> Result<js_sys::Object, JsValue> {
     Reflect::set(
         &direntry_obj,
         &"metadata".into(),
-        &metadata_to_object(direntry.metadata.as_ref().unwrap())?.into(),
+        &metadata_to_object(direntry.metadata.as_ref().unwrap(), permissions)?.into(),
     )?;
     Ok(direntry_obj)
 }
@@ -60,9 +328,37 @@ fn direntry_to_object(direntry: &DirEntry) -> Result<js_sys::Object, JsValue> {
 #[wasm_bindgen]
 impl MemFS {
     #[wasm_bindgen(constructor)]
-    pub fn new() -> Result<MemFS, JsValue> {
+    pub fn new(options: Option<MemFSOptions>) -> Result<MemFS, JsValue> {
+        let options: JsValue = options.map(Into::into).unwrap_or(JsValue::UNDEFINED);
+        let get = |key: &str| -> Result<JsValue, JsValue> {
+            if options.is_undefined() {
+                Ok(JsValue::UNDEFINED)
+            } else {
+                Reflect::get(&options, &key.into())
+            }
+        };
+        let user = User {
+            uid: get("uid")?.as_f64().unwrap_or(0.0) as u32,
+            gid: get("gid")?.as_f64().unwrap_or(0.0) as u32,
+            enforce_permissions: get("enforcePermissions")?.as_bool().unwrap_or(false),
+        };
+        let limits = Limits {
+            max_files: get("maxFiles")?.as_f64().map(|max| max as u64),
+            max_bytes: get("maxBytes")?.as_f64().map(|max| max as u64),
+        };
+        let cwd = match get("cwd")?.as_string() {
+            Some(cwd) if cwd.starts_with('/') => path::normalize(Path::new("/"), Path::new(&cwd)),
+            Some(_) => return Err(js_sys::Error::new("The `cwd` must be an absolute path").into()),
+            None => PathBuf::from("/"),
+        };
         Ok(MemFS {
             inner: Arc::new(MemoryFilesystem::default()),
+            nodes: Arc::new(Mutex::new(Nodes::default())),
+            user,
+            limits,
+            cwd,
+            watchers: Watchers::default(),
+            sandbox: None,
         })
     }
 
@@ -73,53 +369,341 @@ impl MemFS {
     #[wasm_bindgen(js_name = readDir)]
     pub fn js_read_dir(&self, path: &str) -> Result<js_sys::Array, JsValue> {
         let dir_entries = self
-            .inner
             .read_dir(&PathBuf::from(path))
             .map_err(|e| js_sys::Error::new(&format!("Error when reading the dir: {}`", e)))?;
         dir_entries
             .map(|entry| {
-                let entry = entry
+                let mut entry = entry
+                    .map_err(|e| js_sys::Error::new(&format!("Failed to get entry: {}`", e)))?;
+                // Links are listed as what they are rather than what they point to
+                entry.metadata = self.symlink_metadata(&entry.path);
+                let permissions = self
+                    .node_permissions(&entry.path, false)
                     .map_err(|e| js_sys::Error::new(&format!("Failed to get entry: {}`", e)))?;
-                direntry_to_object(&entry)
+                direntry_to_object(&entry, &permissions)
             })
             .collect::<Result<js_sys::Array, JsValue>>()
     }
 
     #[wasm_bindgen(js_name = createDir)]
     pub fn js_create_dir(&self, path: &str) -> Result<(), JsValue> {
-        self.inner
-            .create_dir(&PathBuf::from(path))
+        self.create_dir(&PathBuf::from(path))
             .map_err(|e| js_sys::Error::new(&format!("Error when creating the dir: {}`", e)).into())
     }
 
     #[wasm_bindgen(js_name = removeDir)]
     pub fn js_remove_dir(&self, path: &str) -> Result<(), JsValue> {
-        self.inner
-            .remove_dir(&PathBuf::from(path))
+        self.remove_dir(&PathBuf::from(path))
             .map_err(|e| js_sys::Error::new(&format!("Error when removing the dir: {}`", e)).into())
     }
 
     #[wasm_bindgen(js_name = removeFile)]
     pub fn js_remove_file(&self, path: &str) -> Result<(), JsValue> {
-        self.inner.remove_file(&PathBuf::from(path)).map_err(|e| {
+        self.remove_file(&PathBuf::from(path)).map_err(|e| {
             js_sys::Error::new(&format!("Error when removing the file: {}`", e)).into()
         })
     }
 
     #[wasm_bindgen(js_name = rename)]
     pub fn js_rename(&self, path: &str, to: &str) -> Result<(), JsValue> {
-        self.inner
-            .rename(&PathBuf::from(path), &PathBuf::from(to))
+        self.rename(&PathBuf::from(path), &PathBuf::from(to))
             .map_err(|e| js_sys::Error::new(&format!("Error when renaming: {}`", e)).into())
     }
 
     #[wasm_bindgen(js_name = metadata)]
     pub fn js_metadata(&self, path: &str) -> Result<js_sys::Object, JsValue> {
-        let metadata = self
-            .inner
+        let (metadata, permissions) = self
             .metadata(&PathBuf::from(path))
+            .and_then(|metadata| Ok((metadata, self.node_permissions(Path::new(path), true)?)))
             .map_err(|e| js_sys::Error::new(&format!("Error when creating the dir: {}`", e)))?;
-        metadata_to_object(&metadata)
+        metadata_to_object(&metadata, &permissions)
+    }
+
+    /// Get the metadata of `path`, without following it if it is a symlink
+    #[wasm_bindgen(js_name = symlinkMetadata)]
+    pub fn js_symlink_metadata(&self, path: &str) -> Result<js_sys::Object, JsValue> {
+        let (metadata, permissions) = self
+            .symlink_metadata(Path::new(path))
+            .and_then(|metadata| Ok((metadata, self.node_permissions(Path::new(path), false)?)))
+            .map_err(|e| js_sys::Error::new(&format!("Error when getting the metadata: {}`", e)))?;
+        metadata_to_object(&metadata, &permissions)
+    }
+
+    /// Change the permission bits of `path`
+    #[wasm_bindgen(js_name = chmod)]
+    pub fn js_chmod(&self, path: &str, mode: u32) -> Result<(), JsValue> {
+        self.set_permissions(Path::new(path), |permissions| {
+            permissions.mode = mode & 0o7777
+        })
+        .map_err(|e| js_sys::Error::new(&format!("Error when changing the mode: {}`", e)).into())
+    }
+
+    /// Change the owner of `path`
+    #[wasm_bindgen(js_name = chown)]
+    pub fn js_chown(&self, path: &str, uid: u32, gid: u32) -> Result<(), JsValue> {
+        if self.user.enforce_permissions && self.user.uid != 0 {
+            return Err(js_sys::Error::new(&format!(
+                "Error when changing the owner: {}`",
+                FsError::PermissionDenied
+            ))
+            .into());
+        }
+        self.set_permissions(Path::new(path), |permissions| {
+            permissions.uid = uid;
+            permissions.gid = gid;
+        })
+        .map_err(|e| js_sys::Error::new(&format!("Error when changing the owner: {}`", e)).into())
+    }
+
+    /// Set the access and modification times of `path`, in nanoseconds since
+    /// the Unix epoch. The times that aren't given are left as they are.
+    #[wasm_bindgen(js_name = setTimes)]
+    pub fn js_set_times(&self, path: &str, times: JsValue) -> Result<(), JsValue> {
+        let accessed = get_u64(&times, "accessed")?;
+        let modified = get_u64(&times, "modified")?;
+        self.set_times(Path::new(path), accessed, modified)
+            .map_err(|e| {
+                js_sys::Error::new(&format!("Error when setting the times: {}`", e)).into()
+            })
+    }
+
+    /// Find the paths matching `pattern`, where `*` and `?` match characters
+    /// in a name, `**` matches any number of directories and `{a,b}` matches
+    /// either alternative. Relative patterns start from the root.
+    pub fn glob(&self, pattern: &str) -> js_sys::Array {
+        let mut paths = BTreeSet::new();
+        for pattern in glob::expand_braces(pattern) {
+            let segments = pattern
+                .split('/')
+                .filter(|segment| !segment.is_empty() && *segment != ".")
+                .collect::<Vec<_>>();
+            self.glob_walk(PathBuf::from("/"), &segments, &mut paths);
+        }
+        paths
+            .iter()
+            .map(|path| JsValue::from(path.to_string_lossy().as_ref()))
+            .collect()
+    }
+
+    /// Find `path` and everything below it matching the `options`: the `type`
+    /// ('file', 'dir' or 'symlink'), the `minSize` in bytes and the time it
+    /// was `modifiedAfter`, in nanoseconds since the Unix epoch
+    pub fn find(&self, path: &str, options: JsValue) -> Result<js_sys::Array, JsValue> {
+        let (kind, min_size, modified_after) = if options.is_undefined() {
+            (None, None, None)
+        } else {
+            let kind = Reflect::get(&options, &"type".into())?;
+            let kind = match kind.as_string().as_deref() {
+                None if kind.is_undefined() => None,
+                Some(kind @ ("file" | "dir" | "symlink")) => Some(kind.to_string()),
+                _ => {
+                    return Err(js_sys::Error::new(
+                        "The `type` must be one of 'file', 'dir' or 'symlink'",
+                    )
+                    .into())
+                }
+            };
+            let min_size = get_u64(&options, "minSize")?;
+            let modified_after = get_u64(&options, "modifiedAfter")?;
+            (kind, min_size, modified_after)
+        };
+        self.symlink_metadata(Path::new(path))
+            .map_err(|e| js_sys::Error::new(&format!("Error when finding: {}`", e)))?;
+
+        let found = js_sys::Array::new();
+        let mut paths = vec![path::normalize(&self.cwd, Path::new(path))];
+        while let Some(path) = paths.pop() {
+            let metadata = match self.symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                let mut children = self.children(&path);
+                // Popped in order
+                children.reverse();
+                paths.extend(children);
+            }
+            let matches_kind = match kind.as_deref() {
+                Some("file") => metadata.is_file(),
+                Some("dir") => metadata.is_dir(),
+                Some("symlink") => metadata.ft.symlink,
+                _ => true,
+            };
+            let matches = matches_kind
+                && min_size.map_or(true, |min_size| metadata.len >= min_size)
+                && modified_after.map_or(true, |time| metadata.modified > time);
+            if matches {
+                found.push(&path.to_string_lossy().as_ref().into());
+            }
+        }
+        Ok(found)
+    }
+
+    /// The absolute path of `path` with all the symlinks resolved
+    pub fn realpath(&self, path: &str) -> Result<String, JsValue> {
+        let resolved = self
+            .resolve_symlinks(Path::new(path), true)
+            .and_then(|resolved| {
+                self.inner.metadata(&resolved)?;
+                Ok(resolved)
+            })
+            .map_err(|e| js_sys::Error::new(&format!("Error when resolving the path: {}`", e)))?;
+        Ok(resolved.to_string_lossy().into_owned())
+    }
+
+    /// Whether `path` exists and the user may access it with all of the
+    /// `mode` bits (4 to read, 2 to write, 1 to execute)
+    #[wasm_bindgen(js_name = access)]
+    pub fn js_access(&self, path: &str, mode: u32) -> bool {
+        self.node_permissions(Path::new(path), true)
+            .map(|permissions| permissions.allows(&self.user, mode & 0o7))
+            .unwrap_or(false)
+    }
+
+    /// Create a symlink at `path` pointing to `target`
+    #[wasm_bindgen(js_name = symlink)]
+    pub fn js_symlink(&self, target: &str, path: &str) -> Result<(), JsValue> {
+        self.symlink(Path::new(target), Path::new(path))
+            .map_err(|e| {
+                js_sys::Error::new(&format!("Error when creating the symlink: {}`", e)).into()
+            })
+    }
+
+    /// Get the target of the symlink at `path`
+    #[wasm_bindgen(js_name = readlink)]
+    pub fn js_readlink(&self, path: &str) -> Result<String, JsValue> {
+        match self.readlink(Path::new(path)) {
+            Ok(target) => Ok(target.to_string_lossy().into_owned()),
+            Err(FsError::InvalidInput) => {
+                Err(js_sys::Error::new(&format!("`{}` is not a symlink", path)).into())
+            }
+            Err(e) => {
+                Err(js_sys::Error::new(&format!("Error when reading the symlink: {}`", e)).into())
+            }
+        }
+    }
+
+    /// Create a hard link at `path` to the file at `existing`
+    #[wasm_bindgen(js_name = link)]
+    pub fn js_link(&self, existing: &str, path: &str) -> Result<(), JsValue> {
+        self.link(Path::new(existing), Path::new(path))
+            .map_err(|e| {
+                js_sys::Error::new(&format!("Error when creating the link: {}`", e)).into()
+            })
+    }
+
+    /// Call `callback` with an event for every change made to `path` or its
+    /// children, or to everything below it if `recursive` is set
+    #[wasm_bindgen(js_name = watch)]
+    pub fn js_watch(
+        &self,
+        path: &str,
+        options: JsValue,
+        callback: js_sys::Function,
+    ) -> Result<FSWatcher, JsValue> {
+        let recursive = if options.is_undefined() {
+            false
+        } else {
+            Reflect::get(&options, &"recursive".into())?
+                .as_bool()
+                .unwrap_or(false)
+        };
+        let path = self
+            .resolve(Path::new(path), true)
+            .map_err(|e| js_sys::Error::new(&format!("Error when watching: {}`", e)))?;
+        Ok(self.watchers.watch(path, recursive, callback))
+    }
+
+    /// The number of files and bytes stored, with the limits that were set
+    pub fn usage(&self) -> Result<js_sys::Object, JsValue> {
+        let usage = self.nodes.lock().unwrap().usage;
+        let usage_obj = js_sys::Object::new();
+        Reflect::set(&usage_obj, &"files".into(), &(usage.files as f64).into())?;
+        Reflect::set(&usage_obj, &"bytes".into(), &(usage.bytes as f64).into())?;
+        if let Some(max_files) = self.limits.max_files {
+            Reflect::set(&usage_obj, &"maxFiles".into(), &(max_files as f64).into())?;
+        }
+        if let Some(max_bytes) = self.limits.max_bytes {
+            Reflect::set(&usage_obj, &"maxBytes".into(), &(max_bytes as f64).into())?;
+        }
+        Ok(usage_obj)
+    }
+
+    /// Create an independent copy of the filesystem, with the same limits. The
+    /// file contents are shared between the copies until one of them changes
+    /// a file.
+    pub fn fork(&self) -> Result<MemFS, JsValue> {
+        let fork = MemFS {
+            inner: Arc::new(MemoryFilesystem::default()),
+            nodes: Arc::new(Mutex::new(Nodes::default())),
+            user: self.user,
+            limits: self.limits,
+            cwd: self.cwd.clone(),
+            watchers: Watchers::default(),
+            sandbox: None,
+        };
+        self.snapshot(true)
+            .and_then(|snapshot| fork.restore(snapshot))
+            .map_err(|e| {
+                js_sys::Error::new(&format!("Error when forking the filesystem: {}`", e))
+            })?;
+        Ok(fork)
+    }
+
+    /// Save the whole filesystem in a binary snapshot
+    pub fn serialize(&self) -> Result<Vec<u8>, JsValue> {
+        let snapshot = self.snapshot(false).map_err(|e| {
+            js_sys::Error::new(&format!("Error when serializing the filesystem: {}`", e))
+        })?;
+        Ok(snapshot.encode())
+    }
+
+    /// Create a filesystem from a snapshot made with `serialize`, which must
+    /// fit in the limits of the `options`
+    pub fn deserialize(bytes: &[u8], options: Option<MemFSOptions>) -> Result<MemFS, JsValue> {
+        let fs = MemFS::new(options)?;
+        Snapshot::decode(bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|snapshot| {
+                fs.restore(snapshot).map_err(|e| match e {
+                    FsError::WriteZero => {
+                        "the snapshot doesn't fit in the `maxFiles` and `maxBytes` limits"
+                            .to_string()
+                    }
+                    e => e.to_string(),
+                })
+            })
+            .map_err(|e| {
+                js_sys::Error::new(&format!("Error when deserializing the filesystem: {}`", e))
+            })?;
+        Ok(fs)
+    }
+
+    /// Save a snapshot of the filesystem in IndexedDB under `name`
+    #[wasm_bindgen(js_name = saveToIndexedDB)]
+    pub fn save_to_indexed_db(&self, name: &str) -> Result<js_sys::Promise, JsValue> {
+        let promise = idb::save(name, &self.serialize()?)?;
+        idb::then(&promise, |_| Ok(JsValue::UNDEFINED))
+    }
+
+    /// Create a filesystem from the snapshot saved in IndexedDB under `name`
+    #[wasm_bindgen(js_name = loadFromIndexedDB)]
+    pub fn load_from_indexed_db(
+        name: &str,
+        options: Option<MemFSOptions>,
+    ) -> Result<js_sys::Promise, JsValue> {
+        let name = name.to_string();
+        idb::then(&idb::load(&name)?, move |data| {
+            if data.is_undefined() {
+                return Err(js_sys::Error::new(&format!(
+                    "There is no filesystem saved as `{}` in IndexedDB",
+                    name
+                ))
+                .into());
+            }
+            let bytes = js_sys::Uint8Array::new(&data).to_vec();
+            MemFS::deserialize(&bytes, options).map(Into::into)
+        })
     }
 
     #[wasm_bindgen(js_name = open)]
@@ -157,36 +741,877 @@ impl MemFS {
         );
         let file = open_options
             .open(path)
-            .map_err(|e| js_sys::Error::new(&format!("Error when opening the file: {}`", e)))?;
+            .map_err(|e| js_sys::Error::new(&format!("Error when opening the file: {}", e)))?;
         Ok(JSVirtualFile { handle: file })
     }
 }
 
+impl MemFS {
+    /// The copy of the filesystem that wasmer-wasi resolves the guest paths with
+    pub(crate) fn for_guest(&self, sandbox: Arc<Sandbox>) -> MemFS {
+        MemFS {
+            sandbox: Some(sandbox),
+            ..self.clone()
+        }
+    }
+
+    /// The copy of the filesystem given back to JS
+    pub(crate) fn for_host(&self) -> MemFS {
+        MemFS {
+            sandbox: None,
+            ..self.clone()
+        }
+    }
+
+    /// Resolves all the symlinks in `path`
+    pub(crate) fn canonicalize(&self, path: &Path) -> Result<PathBuf, FsError> {
+        self.resolve_symlinks(path, true)
+    }
+
+    /// Resolves the symlinks in `path` but its last component
+    pub(crate) fn resolve_parent(&self, path: &Path) -> Result<PathBuf, FsError> {
+        self.resolve_symlinks(path, false)
+    }
+
+    /// Resolves the symlinks and hard links in `path`, giving the path where
+    /// the file or directory is stored in the underlying filesystem. The last
+    /// component of `path` is only resolved when `follow` is set.
+    fn resolve(&self, path: &Path, follow: bool) -> Result<PathBuf, FsError> {
+        let resolved = self.resolve_symlinks(path, follow)?;
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.hard_links.get(&resolved) {
+            // Only files can be hard linked, so only the last component can
+            // be a hard link
+            Some(target) if follow => Ok(target.clone()),
+            _ => Ok(resolved),
+        }
+    }
+
+    /// Resolves the symlinks in `path`, which is relative to the `cwd`. The
+    /// last component is only resolved when `follow` is set, or when `path`
+    /// ends with a separator, which requires it to be a directory. For the
+    /// guest, the resolved path must be in the preopen of the path, and
+    /// absolute symlink targets are relative to that preopen.
+    fn resolve_symlinks(&self, path: &Path, follow: bool) -> Result<PathBuf, FsError> {
+        let names_dir = path::names_dir(path);
+        let follow = follow || names_dir;
+        let nodes = self.nodes.lock().unwrap();
+        let mut resolved = if path.has_root() {
+            PathBuf::from("/")
+        } else {
+            self.cwd.clone()
+        };
+        let mut components = path.components().collect::<VecDeque<_>>();
+        let mut followed = 0;
+        while let Some(component) = components.pop_front() {
+            match component {
+                Component::RootDir | Component::Prefix(_) => resolved = PathBuf::from("/"),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    if !follow && components.is_empty() {
+                        break;
+                    }
+                    if let Some(target) = nodes.symlinks.get(&resolved) {
+                        followed += 1;
+                        if followed > MAX_SYMLINKS {
+                            return Err(FsError::InvalidInput);
+                        }
+                        // The guest sees its preopen as `/`, so absolute
+                        // targets are resolved from there
+                        let root = match &self.sandbox {
+                            Some(sandbox) if target.has_root() => sandbox.root_of(&resolved),
+                            _ => None,
+                        };
+                        resolved.pop();
+                        let mut target = target.components().collect::<Vec<_>>();
+                        if let Some(root) = root {
+                            target.retain(|component| {
+                                !matches!(component, Component::RootDir | Component::Prefix(_))
+                            });
+                            resolved = root;
+                        }
+                        for component in target.into_iter().rev() {
+                            components.push_front(component);
+                        }
+                    } else if !components.is_empty() && nodes.hard_links.contains_key(&resolved) {
+                        // A file in the middle of the path
+                        return Err(FsError::BaseNotDirectory);
+                    }
+                }
+            }
+        }
+        if names_dir {
+            if let Ok(metadata) = self.inner.metadata(&resolved) {
+                if !metadata.is_dir() {
+                    return Err(FsError::BaseNotDirectory);
+                }
+            }
+        }
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.check(&resolved)?;
+        }
+        Ok(resolved)
+    }
+
+    /// The permissions of the node stored at the resolved `path`
+    fn permissions(&self, nodes: &Nodes, path: &Path) -> Result<Permissions, FsError> {
+        if let Some(permissions) = nodes.permissions.get(path) {
+            return Ok(*permissions);
+        }
+        let mode = if nodes.symlinks.contains_key(path) {
+            0o777
+        } else if self.inner.metadata(path)?.is_dir() {
+            0o755
+        } else {
+            0o644
+        };
+        Ok(Permissions {
+            mode,
+            uid: self.user.uid,
+            gid: self.user.gid,
+        })
+    }
+
+    fn node_permissions(&self, path: &Path, follow: bool) -> Result<Permissions, FsError> {
+        let path = self.resolve(path, follow)?;
+        self.permissions(&self.nodes.lock().unwrap(), &path)
+    }
+
+    fn set_permissions(
+        &self,
+        path: &Path,
+        update: impl FnOnce(&mut Permissions),
+    ) -> Result<(), FsError> {
+        let path = self.resolve(path, true)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut permissions = self.permissions(&nodes, &path)?;
+        // Only the owner can change the permissions
+        if self.user.enforce_permissions && self.user.uid != 0 && self.user.uid != permissions.uid {
+            return Err(FsError::PermissionDenied);
+        }
+        update(&mut permissions);
+        nodes.permissions.insert(path.clone(), permissions);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Modify(path));
+        Ok(())
+    }
+
+    pub(crate) fn set_times(
+        &self,
+        path: &Path,
+        accessed: Option<u64>,
+        modified: Option<u64>,
+    ) -> Result<(), FsError> {
+        let path = self.resolve(path, true)?;
+        self.check_write(&path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let times = nodes.times.entry(path.clone()).or_default();
+        times.accessed = accessed.or(times.accessed);
+        times.modified = modified.or(times.modified);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Modify(path));
+        Ok(())
+    }
+
+    /// Checks that the user may write to the node stored at the resolved
+    /// `path`, when permissions are enforced
+    fn check_write(&self, path: &Path) -> Result<(), FsError> {
+        if !self.user.enforce_permissions {
+            return Ok(());
+        }
+        let permissions = self.permissions(&self.nodes.lock().unwrap(), path)?;
+        if permissions.allows(&self.user, Permissions::WRITE) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// Checks that the user may add or remove the resolved `path` in its
+    /// directory, when permissions are enforced
+    fn check_parent_write(&self, path: &Path) -> Result<(), FsError> {
+        self.check_write(path.parent().unwrap_or_else(|| Path::new("/")))
+    }
+
+    pub(crate) fn symlink(&self, target: &Path, path: &Path) -> Result<(), FsError> {
+        let path = self.resolve(path, false)?;
+        self.check_parent_write(&path)?;
+        self.nodes.lock().unwrap().check_usage(&self.limits, 1, 0)?;
+        let mut file = self
+            .inner
+            .new_open_options()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(target.to_string_lossy().as_bytes())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.symlinks.insert(path.clone(), target.to_path_buf());
+        nodes.update_usage(1, 0);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Create(path));
+        Ok(())
+    }
+
+    /// Whether a write went past the limits since the last call
+    pub(crate) fn take_full(&self) -> bool {
+        std::mem::take(&mut self.nodes.lock().unwrap().full)
+    }
+
+    /// The target of the symlink at `path`, or `InvalidInput` if it isn't one
+    pub(crate) fn readlink(&self, path: &Path) -> Result<PathBuf, FsError> {
+        let path = self.resolve(path, false)?;
+        self.nodes
+            .lock()
+            .unwrap()
+            .symlinks
+            .get(&path)
+            .cloned()
+            .ok_or(FsError::InvalidInput)
+    }
+
+    /// The paths of the entries of the directory at `path`, sorted by name, or
+    /// none if it can't be read
+    fn children(&self, path: &Path) -> Vec<PathBuf> {
+        let mut children = match self.read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        children.sort();
+        children
+    }
+
+    /// Adds the paths under `dir` matching the pattern `segments` to `paths`
+    fn glob_walk(&self, dir: PathBuf, segments: &[&str], paths: &mut BTreeSet<PathBuf>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => {
+                paths.insert(dir);
+                return;
+            }
+        };
+        if *segment == "**" {
+            // Matching no directories
+            self.glob_walk(dir.clone(), rest, paths);
+            for child in self.children(&dir) {
+                let hidden = child
+                    .file_name()
+                    .map_or(false, |name| name.to_string_lossy().starts_with('.'));
+                if hidden {
+                    continue;
+                }
+                // Symlinks aren't followed, so they can't make loops
+                match self.symlink_metadata(&child) {
+                    Ok(metadata) if metadata.is_dir() => self.glob_walk(child, segments, paths),
+                    // A trailing `**` matches the files too
+                    Ok(_) if rest.is_empty() => {
+                        paths.insert(child);
+                    }
+                    _ => {}
+                }
+            }
+        } else if !glob::has_wildcards(segment) {
+            let child = dir.join(segment);
+            if self.symlink_metadata(&child).is_ok() {
+                self.glob_walk(child, rest, paths);
+            }
+        } else {
+            for child in self.children(&dir) {
+                let name = child.file_name().unwrap_or_default().to_string_lossy();
+                if glob::matches(segment, &name) {
+                    self.glob_walk(child, rest, paths);
+                }
+            }
+        }
+    }
+
+    /// Takes a snapshot of every node, parents before their children. With
+    /// `share`, the contents of the files are kept to be shared with the next
+    /// snapshots, until the files change.
+    fn snapshot(&self, share: bool) -> Result<Snapshot, FsError> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut snapshot = Snapshot::default();
+        let mut paths = vec![PathBuf::from("/")];
+        while let Some(path) = paths.pop() {
+            let metadata = nodes.apply(&path, self.inner.metadata(&path)?);
+            let kind = if let Some(target) = nodes.symlinks.get(&path) {
+                NodeKind::Symlink(target.to_string_lossy().into_owned())
+            } else if let Some(target) = nodes.hard_links.get(&path) {
+                NodeKind::HardLink(target.to_string_lossy().into_owned())
+            } else if metadata.is_dir() {
+                for entry in self.inner.read_dir(&path)? {
+                    paths.push(path.join(entry?.path.file_name().unwrap_or_default()));
+                }
+                NodeKind::Dir
+            } else if let Some(shared) = nodes.shared.get(&path) {
+                NodeKind::File(shared.contents.clone())
+            } else {
+                let mut contents = Vec::new();
+                self.inner
+                    .new_open_options()
+                    .read(true)
+                    .open(&path)?
+                    .read_to_end(&mut contents)?;
+                let contents = Arc::new(contents);
+                if share {
+                    let shared = Shared {
+                        contents: contents.clone(),
+                        materialized: true,
+                    };
+                    nodes.shared.insert(path.clone(), shared);
+                }
+                NodeKind::File(contents)
+            };
+            snapshot.nodes.push(SnapshotNode {
+                path: path.to_string_lossy().into_owned(),
+                kind,
+                permissions: nodes
+                    .permissions
+                    .get(&path)
+                    .map(|permissions| (permissions.mode, permissions.uid, permissions.gid)),
+                accessed: metadata.accessed,
+                modified: metadata.modified,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    /// Adds the nodes of `snapshot`, without checking the permissions. The
+    /// file contents are shared with the snapshot rather than copied. Fails
+    /// with `WriteZero` when the nodes don't fit in the limits.
+    fn restore(&self, snapshot: Snapshot) -> Result<(), FsError> {
+        let mut nodes = self.nodes.lock().unwrap();
+        for node in snapshot.nodes {
+            let path = PathBuf::from(node.path);
+            // The root directory always exists, so it isn't counted
+            let files = if path == Path::new("/") { 0 } else { 1 };
+            let bytes = match &node.kind {
+                NodeKind::File(contents) => contents.len() as i64,
+                _ => 0,
+            };
+            nodes.check_usage(&self.limits, files, bytes)?;
+            nodes.update_usage(files, bytes);
+            let contents = match &node.kind {
+                NodeKind::Dir => None,
+                NodeKind::File(_) | NodeKind::HardLink(_) => Some(&[][..]),
+                NodeKind::Symlink(target) => Some(target.as_bytes()),
+            };
+            match contents {
+                // The root directory always exists
+                None if path == Path::new("/") => {}
+                None => self.inner.create_dir(&path)?,
+                Some(contents) => self
+                    .inner
+                    .new_open_options()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)?
+                    .write_all(contents)?,
+            }
+            match node.kind {
+                NodeKind::File(contents) => {
+                    let shared = Shared {
+                        contents,
+                        materialized: false,
+                    };
+                    nodes.shared.insert(path.clone(), shared);
+                }
+                NodeKind::Symlink(target) => {
+                    nodes.symlinks.insert(path.clone(), PathBuf::from(target));
+                }
+                NodeKind::HardLink(target) => {
+                    nodes.hard_links.insert(path.clone(), PathBuf::from(target));
+                }
+                NodeKind::Dir => {}
+            }
+            if let Some((mode, uid, gid)) = node.permissions {
+                nodes
+                    .permissions
+                    .insert(path.clone(), Permissions { mode, uid, gid });
+            }
+            nodes.times.insert(
+                path,
+                Times {
+                    accessed: Some(node.accessed),
+                    modified: Some(node.modified),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// The number of bytes stored for the node at the resolved `path`, which is
+    /// 0 for directories and links
+    fn stored_len(&self, path: &Path) -> Result<u64, FsError> {
+        let nodes = self.nodes.lock().unwrap();
+        if nodes.symlinks.contains_key(path) || nodes.hard_links.contains_key(path) {
+            return Ok(0);
+        }
+        let metadata = nodes.apply(path, self.inner.metadata(path)?);
+        Ok(if metadata.is_file() { metadata.len } else { 0 })
+    }
+
+    /// Writes the shared contents of the file at the resolved `path` to the
+    /// underlying filesystem, so it can be changed
+    fn materialize(&self, path: &Path) -> Result<(), FsError> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let shared = match nodes.shared.get_mut(path) {
+            Some(shared) if !shared.materialized => shared,
+            _ => return Ok(()),
+        };
+        self.inner
+            .new_open_options()
+            .write(true)
+            .open(path)?
+            .write_all(&shared.contents)?;
+        shared.materialized = true;
+        Ok(())
+    }
+
+    fn link(&self, existing: &Path, path: &Path) -> Result<(), FsError> {
+        let existing = self.resolve(existing, true)?;
+        if !self.inner.metadata(&existing)?.is_file() {
+            return Err(FsError::NotAFile);
+        }
+        let path = self.resolve(path, false)?;
+        self.check_parent_write(&path)?;
+        self.nodes.lock().unwrap().check_usage(&self.limits, 1, 0)?;
+        self.inner
+            .new_open_options()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.hard_links.insert(path.clone(), existing);
+        nodes.update_usage(1, 0);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Create(path));
+        Ok(())
+    }
+}
+
 impl FileSystem for MemFS {
     fn read_dir(&self, path: &Path) -> Result<ReadDir, FsError> {
-        self.inner.read_dir(path)
+        let entries = self
+            .inner
+            .read_dir(&self.resolve(path, true)?)?
+            .map(|entry| {
+                let mut entry = entry?;
+                entry.path = path::normalize(&self.cwd, path)
+                    .join(entry.path.file_name().unwrap_or_default());
+                if let Ok(metadata) = self.metadata(&entry.path) {
+                    entry.metadata = Ok(metadata);
+                }
+                Ok(entry)
+            })
+            .collect::<Result<Vec<DirEntry>, FsError>>()?;
+        Ok(ReadDir::new(entries))
     }
     fn create_dir(&self, path: &Path) -> Result<(), FsError> {
-        self.inner.create_dir(path)
+        let path = self.resolve(path, false)?;
+        self.check_parent_write(&path)?;
+        self.nodes.lock().unwrap().check_usage(&self.limits, 1, 0)?;
+        self.inner.create_dir(&path)?;
+        self.nodes.lock().unwrap().update_usage(1, 0);
+        self.watchers.notify(FsEvent::Create(path));
+        Ok(())
     }
     fn remove_dir(&self, path: &Path) -> Result<(), FsError> {
-        self.inner.remove_dir(path)
+        let path = self.resolve(path, false)?;
+        self.check_parent_write(&path)?;
+        self.inner.remove_dir(&path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.remove(&path);
+        nodes.update_usage(-1, 0);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Remove(path));
+        Ok(())
     }
     fn rename(&self, from: &Path, to: &Path) -> Result<(), FsError> {
-        self.inner.rename(from, to)
+        let from = self.resolve(from, false)?;
+        let to = self.resolve(to, false)?;
+        self.check_parent_write(&from)?;
+        self.check_parent_write(&to)?;
+        // Links are stored as files, so renaming a directory onto one must
+        // fail like for a file
+        let replaced = match self.inner.metadata(&to) {
+            Ok(metadata) if metadata.is_file() && from != to => {
+                if self.inner.metadata(&from)?.is_dir() {
+                    return Err(FsError::BaseNotDirectory);
+                }
+                Some(self.stored_len(&to)?)
+            }
+            _ => None,
+        };
+        if replaced.is_some() {
+            self.inner.remove_file(&to)?;
+        }
+        self.inner.rename(&from, &to)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(len) = replaced {
+            nodes.remove(&to);
+            nodes.update_usage(-1, -(len as i64));
+        }
+        nodes.rename(&from, &to);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Rename { from, to });
+        Ok(())
     }
     fn metadata(&self, path: &Path) -> Result<Metadata, FsError> {
-        self.inner.metadata(path)
+        let path = self.resolve(path, true)?;
+        let metadata = self.inner.metadata(&path)?;
+        Ok(self.nodes.lock().unwrap().apply(&path, metadata))
     }
     fn symlink_metadata(&self, path: &Path) -> Result<Metadata, FsError> {
-        self.inner.symlink_metadata(path)
+        let resolved = self.resolve(path, false)?;
+        let nodes = self.nodes.lock().unwrap();
+        if nodes.symlinks.contains_key(&resolved) && self.sandbox.is_some() {
+            // wasmer-wasi would read a symlink from the host filesystem, so
+            // the guest sees its target instead, or a file if it is dangling
+            drop(nodes);
+            return match self.metadata(path) {
+                Err(FsError::PermissionDenied) => Err(FsError::PermissionDenied),
+                metadata => metadata.or_else(|_| self.inner.metadata(&resolved)),
+            };
+        }
+        let path = resolved;
+        if nodes.symlinks.contains_key(&path) {
+            let metadata = self.inner.metadata(&path)?;
+            Ok(Metadata {
+                ft: FileType {
+                    symlink: true,
+                    ..Default::default()
+                },
+                ..nodes.apply(&path, metadata)
+            })
+        } else if let Some(target) = nodes.hard_links.get(&path) {
+            Ok(nodes.apply(target, self.inner.metadata(target)?))
+        } else {
+            Ok(nodes.apply(&path, self.inner.metadata(&path)?))
+        }
     }
     fn remove_file(&self, path: &Path) -> Result<(), FsError> {
-        self.inner.remove_file(path)
+        let path = self.resolve(path, false)?;
+        self.check_parent_write(&path)?;
+        let len = self.stored_len(&path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.hard_links.contains_key(&path) && !nodes.symlinks.contains_key(&path) {
+            // The file is stored here, so it is moved to one of its hard
+            // links if there are any left
+            let hard_link = nodes
+                .hard_links
+                .iter()
+                .find(|(_, target)| **target == path)
+                .map(|(hard_link, _)| hard_link.clone());
+            if let Some(hard_link) = hard_link {
+                self.inner.remove_file(&hard_link)?;
+                self.inner.rename(&path, &hard_link)?;
+                nodes.hard_links.remove(&hard_link);
+                nodes.rename(&path, &hard_link);
+                nodes.update_usage(-1, 0);
+                drop(nodes);
+                self.watchers.notify(FsEvent::Remove(path));
+                return Ok(());
+            }
+        }
+        self.inner.remove_file(&path)?;
+        nodes.remove(&path);
+        nodes.update_usage(-1, -(len as i64));
+        drop(nodes);
+        self.watchers.notify(FsEvent::Remove(path));
+        Ok(())
     }
 
     fn new_open_options(&self) -> OpenOptions {
-        self.inner.new_open_options()
+        OpenOptions::new(Box::new(self.clone()))
+    }
+}
+
+impl FileOpener for MemFS {
+    fn open(
+        &mut self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync>, FsError> {
+        let path = self.resolve(path, true)?;
+        let existed = self.inner.metadata(&path).is_ok();
+        let truncated = if existed && conf.truncate() {
+            self.stored_len(&path)?
+        } else {
+            0
+        };
+        if conf.write() || conf.append() || conf.truncate() {
+            if existed {
+                self.check_write(&path)?;
+                self.materialize(&path)?;
+            } else {
+                self.check_parent_write(&path)?;
+            }
+        }
+        if !existed && (conf.create() || conf.create_new()) {
+            self.nodes.lock().unwrap().check_usage(&self.limits, 1, 0)?;
+        }
+        let file = self
+            .inner
+            .new_open_options()
+            .read(conf.read())
+            .write(conf.write())
+            .append(conf.append())
+            .truncate(conf.truncate())
+            .create(conf.create())
+            .create_new(conf.create_new())
+            .open(&path)?;
+        let shared = self.nodes.lock().unwrap().shared.get(&path).cloned();
+        let file: Box<dyn VirtualFile + Send + Sync> = match shared {
+            Some(shared) if !shared.materialized => Box::new(SharedFile {
+                inner: file,
+                contents: shared.contents,
+                position: 0,
+            }),
+            _ => file,
+        };
+        let file = MemFile {
+            inner: file,
+            id: self.nodes.lock().unwrap().open_file(path.clone()),
+            append: conf.append(),
+            nodes: self.nodes.clone(),
+            limits: self.limits,
+            watchers: self.watchers.clone(),
+        };
+        if !existed {
+            self.nodes.lock().unwrap().update_usage(1, 0);
+            self.watchers.notify(FsEvent::Create(path));
+        } else if conf.truncate() {
+            self.nodes
+                .lock()
+                .unwrap()
+                .update_usage(0, -(truncated as i64));
+            file.modified();
+        }
+        Ok(Box::new(file))
+    }
+}
+
+/// A file opened from a `MemFS`, which keeps the `MemFS` bookkeeping up to
+/// date as the file is used
+#[derive(Debug)]
+struct MemFile {
+    inner: Box<dyn VirtualFile + Send + Sync>,
+    /// The id of the file in the open files of the `nodes`, which have its
+    /// resolved path
+    id: u64,
+    /// Whether the writes go to the end of the file
+    append: bool,
+    nodes: Arc<Mutex<Nodes>>,
+    limits: Limits,
+    watchers: Watchers,
+}
+
+impl MemFile {
+    /// Checks that the file can grow from `old_size` to `new_size`
+    fn check_size(&self, old_size: u64, new_size: u64) -> Result<(), FsError> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .check_usage(&self.limits, 0, new_size as i64 - old_size as i64)
+    }
+
+    /// Called whenever the contents of the file change
+    fn modified(&self) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let path = match nodes.open_files.get(&self.id) {
+            Some(path) => path.clone(),
+            // The file was removed
+            None => return,
+        };
+        // The modification time that was set explicitly is outdated now
+        if let Some(times) = nodes.times.get_mut(&path) {
+            times.modified = None;
+        }
+        // The contents aren't shared anymore
+        nodes.shared.remove(&path);
+        drop(nodes);
+        self.watchers.notify(FsEvent::Modify(path));
+    }
+
+    /// The times of the file that were set explicitly
+    fn times(&self) -> Option<Times> {
+        let nodes = self.nodes.lock().unwrap();
+        let path = nodes.open_files.get(&self.id)?;
+        nodes.times.get(path).copied()
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        if let Ok(mut nodes) = self.nodes.lock() {
+            nodes.open_files.remove(&self.id);
+        }
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let old_size = self.inner.size();
+        let end = if self.append {
+            old_size + buf.len() as u64
+        } else {
+            self.inner.seek(SeekFrom::Current(0))? + buf.len() as u64
+        };
+        if self.check_size(old_size, end.max(old_size)).is_err() {
+            self.nodes.lock().unwrap().full = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "the filesystem is full",
+            ));
+        }
+        let written = self.inner.write(buf)?;
+        let new_size = self.inner.size();
+        self.nodes
+            .lock()
+            .unwrap()
+            .update_usage(0, new_size as i64 - old_size as i64);
+        self.modified();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl VirtualFile for MemFile {
+    fn last_accessed(&self) -> u64 {
+        self.times()
+            .and_then(|times| times.accessed)
+            .unwrap_or_else(|| self.inner.last_accessed())
+    }
+    fn last_modified(&self) -> u64 {
+        self.times()
+            .and_then(|times| times.modified)
+            .unwrap_or_else(|| self.inner.last_modified())
+    }
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+    fn set_len(&mut self, new_size: u64) -> Result<(), FsError> {
+        let old_size = self.inner.size();
+        self.check_size(old_size, new_size)?;
+        self.inner.set_len(new_size)?;
+        self.nodes
+            .lock()
+            .unwrap()
+            .update_usage(0, new_size as i64 - old_size as i64);
+        self.modified();
+        Ok(())
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        self.inner.unlink()
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        self.inner.bytes_available()
+    }
+}
+
+/// A file opened for reading whose contents are shared with other
+/// filesystems, so they are only stored once
+#[derive(Debug)]
+struct SharedFile {
+    /// The empty file standing for it in the underlying filesystem
+    inner: Box<dyn VirtualFile + Send + Sync>,
+    contents: Arc<Vec<u8>>,
+    position: u64,
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = (self.position as usize).min(self.contents.len());
+        let len = buf.len().min(self.contents.len() - start);
+        buf[..len].copy_from_slice(&self.contents[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "the file is not opened for writing",
+        ))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = |base: u64, offset: i64| {
+            if offset >= 0 {
+                base.checked_add(offset as u64)
+            } else {
+                base.checked_sub(offset.unsigned_abs())
+            }
+        };
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => offset(self.contents.len() as u64, delta),
+            SeekFrom::Current(delta) => offset(self.position, delta),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl VirtualFile for SharedFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+    fn size(&self) -> u64 {
+        self.contents.len() as u64
+    }
+    fn set_len(&mut self, _new_size: u64) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        self.inner.unlink()
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        Ok(self.contents.len().saturating_sub(self.position as usize))
     }
 }
 
@@ -225,47 +1650,149 @@ impl JSVirtualFile {
     }
 
     // Read APIs
-    pub fn read(&mut self) -> Result<Vec<u8>, JsValue> {
+
+    /// Reads `length` bytes from the current position, or everything up to
+    /// the end of the file when no `length` is given. Fewer bytes are returned
+    /// when the end of the file is reached.
+    pub fn read(&mut self, length: Option<usize>) -> Result<Vec<u8>, JsValue> {
         let mut buf: Vec<u8> = vec![];
-        self.handle
-            .read_to_end(&mut buf)
-            .map_err(|e| js_sys::Error::new(&format!("Error when reading: {}`", e)))?;
+        let result = match length {
+            Some(length) => (&mut self.handle).take(length as u64).read_to_end(&mut buf),
+            None => self.handle.read_to_end(&mut buf),
+        };
+        result.map_err(|e| js_sys::Error::new(&format!("Error when reading: {}`", e)))?;
         Ok(buf)
     }
 
     #[wasm_bindgen(js_name = readString)]
     pub fn read_string(&mut self) -> Result<String, JsValue> {
-        String::from_utf8(self.read()?).map_err(|e| {
+        String::from_utf8(self.read(None)?).map_err(|e| {
             js_sys::Error::new(&format!("Could not convert the bytes to a String: {}`", e)).into()
         })
     }
 
+    /// Reads up to `length` bytes at `offset`, without moving the current
+    /// position
+    #[wasm_bindgen(js_name = readAt)]
+    pub fn read_at(&mut self, offset: u64, length: usize) -> Result<Vec<u8>, JsValue> {
+        self.at(offset, |file| file.read(Some(length)))
+    }
+
     // Write APIs
-    pub fn write(&mut self, buf: &mut [u8]) -> Result<usize, JsValue> {
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, JsValue> {
         self.handle
             .write(buf)
             .map_err(|e| js_sys::Error::new(&format!("Error when writing: {}`", e)).into())
     }
 
     #[wasm_bindgen(js_name = writeString)]
-    pub fn write_string(&mut self, mut buf: String) -> Result<usize, JsValue> {
+    pub fn write_string(&mut self, buf: String) -> Result<usize, JsValue> {
         self.handle
-            .write(unsafe { buf.as_bytes_mut() })
+            .write(buf.as_bytes())
             .map_err(|e| js_sys::Error::new(&format!("Error when writing string: {}`", e)).into())
     }
 
+    /// Writes all of `buf` at `offset`, without moving the current position
+    #[wasm_bindgen(js_name = writeAt)]
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize, JsValue> {
+        self.at(offset, |file| {
+            file.handle
+                .write_all(buf)
+                .map_err(|e| js_sys::Error::new(&format!("Error when writing: {}`", e)))?;
+            Ok(buf.len())
+        })
+    }
+
     pub fn flush(&mut self) -> Result<(), JsValue> {
         self.handle
             .flush()
             .map_err(|e| js_sys::Error::new(&format!("Error when flushing: {}`", e)).into())
     }
 
+    // Stream APIs
+
+    /// Turns the file into a `ReadableStream` of its contents, from the
+    /// current position up to the end of the file.
+    /// Note: the file can't be used anymore afterwards
+    pub fn readable(mut self) -> Result<ReadableStream, JsValue> {
+        streams::readable_stream(move || {
+            let data = self.read(Some(streams::CHUNK_SIZE))?;
+            Ok(if data.is_empty() {
+                Chunk::Done
+            } else {
+                Chunk::Data(data)
+            })
+        })
+    }
+
+    /// Turns the file into a `WritableStream` that writes at the current
+    /// position, and flushes the file when closed.
+    /// Note: the file can't be used anymore afterwards
+    pub fn writable(self) -> Result<WritableStream, JsValue> {
+        let file = Rc::new(RefCell::new(self));
+        let closed_file = file.clone();
+        streams::writable_stream(
+            move |data| {
+                file.borrow_mut()
+                    .handle
+                    .write_all(data)
+                    .map_err(|e| js_sys::Error::new(&format!("Error when writing: {}`", e)).into())
+            },
+            move || closed_file.borrow_mut().flush(),
+        )
+    }
+
     // Seek APIs
-    pub fn seek(&mut self, position: u32) -> Result<u32, JsValue> {
-        let ret = self
-            .handle
-            .seek(std::io::SeekFrom::Start(position as _))
-            .map_err(|e| js_sys::Error::new(&format!("Error when seeking: {}`", e)))?;
-        Ok(ret as _)
+
+    /// Moves the current position by `offset` (a number or a bigint) relative
+    /// to `whence`, which is `'start'` (default), `'current'` or `'end'`.
+    /// Returns the new position.
+    pub fn seek(&mut self, offset: JsValue, whence: Option<String>) -> Result<u64, JsValue> {
+        let offset = js_sys::BigInt::new(&offset)
+            .ok()
+            .and_then(|offset| i64::try_from(offset).ok())
+            .ok_or_else(|| js_sys::Error::new("The seek offset must be an integer"))?;
+        let position = match whence.as_deref() {
+            None | Some("start") => SeekFrom::Start(u64::try_from(offset).map_err(|_| {
+                js_sys::Error::new("The seek offset from the start can't be negative")
+            })?),
+            Some("current") => SeekFrom::Current(offset),
+            Some("end") => SeekFrom::End(offset),
+            Some(other) => {
+                return Err(js_sys::Error::new(&format!(
+                    "Unknown seek origin `{}`, expected 'start', 'current' or 'end'",
+                    other
+                ))
+                .into())
+            }
+        };
+        self.handle
+            .seek(position)
+            .map_err(|e| js_sys::Error::new(&format!("Error when seeking: {}`", e)).into())
+    }
+}
+
+impl JSVirtualFile {
+    pub(crate) fn new(handle: Box<dyn VirtualFile>) -> Self {
+        JSVirtualFile { handle }
+    }
+
+    /// Runs `f` with the file positioned at `offset`, and restores the current
+    /// position afterwards
+    fn at<T>(
+        &mut self,
+        offset: u64,
+        f: impl FnOnce(&mut Self) -> Result<T, JsValue>,
+    ) -> Result<T, JsValue> {
+        let seek = |file: &mut Self, position| {
+            file.handle
+                .seek(position)
+                .map_err(|e| js_sys::Error::new(&format!("Error when seeking: {}`", e)))
+        };
+        let current = seek(self, SeekFrom::Current(0))?;
+        seek(self, SeekFrom::Start(offset))?;
+        let result = f(self);
+        seek(self, SeekFrom::Start(current))?;
+        result
     }
 }
@@ -0,0 +1,97 @@
+//! Glob patterns, as used by `MemFS.glob`.
+
+/// Expands the `{a,b}` alternatives of `pattern`, which can be nested
+pub(crate) fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let prefix = &pattern[..start];
+                    let suffix = &pattern[i + 1..];
+                    return split_alternatives(&pattern[start + 1..i])
+                        .into_iter()
+                        .flat_map(|alternative| {
+                            expand_braces(&format!("{}{}{}", prefix, alternative, suffix))
+                        })
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+    }
+    vec![pattern.to_string()]
+}
+
+/// Splits the inside of braces at the commas that aren't nested in braces
+fn split_alternatives(alternatives: &str) -> Vec<&str> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut split = Vec::new();
+    for (i, c) in alternatives.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(&alternatives[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&alternatives[start..]);
+    split
+}
+
+/// Whether a path segment of a pattern has `*` or `?` wildcards
+pub(crate) fn has_wildcards(segment: &str) -> bool {
+    segment.contains(['*', '?'])
+}
+
+/// Whether the file `name` matches a pattern segment, where `*` matches any
+/// characters and `?` a single one. Like in shells, names starting with a `.`
+/// are only matched by segments starting with a `.`.
+pub(crate) fn matches(segment: &str, name: &str) -> bool {
+    if name.starts_with('.') && !segment.starts_with('.') {
+        return false;
+    }
+    let segment = segment.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut s, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match
+    let mut backtrack = None;
+    while n < name.len() {
+        match segment.get(s) {
+            Some('*') => {
+                backtrack = Some((s, n));
+                s += 1;
+            }
+            Some('?') => {
+                s += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                s += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` match one more character
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    s = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    segment[s..].iter().all(|c| *c == '*')
+}
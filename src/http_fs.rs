@@ -0,0 +1,588 @@
+use js_sys::{Function, Reflect, Uint8Array};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_downcast::DowncastJS;
+
+use crate::fs::JSVirtualFile;
+use crate::js_handle::JsHandle;
+use crate::path;
+use crate::streams;
+use wasmer_vfs::{
+    DirEntry, FileOpener, FileSystem, FileType, FsError, Metadata, OpenOptions, OpenOptionsConfig,
+    ReadDir, VirtualFile,
+};
+
+/// The size of the blocks fetched and cached by default
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// How many bytes of blocks are cached by default
+const DEFAULT_MAX_CACHED_BYTES: u64 = 64 * 1024 * 1024;
+
+#[wasm_bindgen(typescript_custom_section)]
+const HTTP_FS_TYPE_DEFINITIONS: &str = r#"
+/** The files served by an HttpFS. */
+export type HttpFSManifest = {
+    readonly files: Array<{
+        /** The absolute path of the file in the filesystem. */
+        readonly path: string;
+        /** The size of the file in bytes. */
+        readonly size: number;
+        /** Where the contents of the file are fetched from. */
+        readonly url: string;
+    }>;
+};
+
+/** Options used when creating a new HttpFS. */
+export type HttpFSOptions = {
+    /**
+     * Fetches the bytes `start..end` of `url`. It must be synchronous, since
+     * the guest waits for the data. By default a synchronous `XMLHttpRequest`
+     * with a `Range` header is used.
+     */
+    readonly fetch?: (url: string, start: number, end: number) => Uint8Array;
+    /** The size of the blocks that are fetched and cached (64 KiB by default). */
+    readonly blockSize?: number;
+    /**
+     * How many bytes of blocks are cached (64 MiB by default). The blocks
+     * used least recently are dropped first, and fetched again when needed.
+     */
+    readonly maxCachedBytes?: number;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "HttpFSManifest | string")]
+    pub type HttpFSManifest;
+
+    #[wasm_bindgen(typescript_type = "HttpFSOptions")]
+    pub type HttpFSOptions;
+}
+
+/// A read-only filesystem whose files are fetched over HTTP when they are
+/// first read
+#[wasm_bindgen]
+#[derive(Debug, Clone, DowncastJS)]
+pub struct HttpFS {
+    inner: Arc<HttpFSInner>,
+}
+
+#[derive(Debug)]
+struct HttpFSInner {
+    /// The fetch function, or `None` for the default one
+    fetch: JsHandle<Option<Function>>,
+    files: HashMap<PathBuf, HttpFile>,
+    dirs: BTreeSet<PathBuf>,
+    block_size: u64,
+    blocks: Mutex<BlockCache>,
+}
+
+/// A file of the manifest
+#[derive(Debug, Clone)]
+struct HttpFile {
+    /// The index of the contents in the cache, shared by the files with the
+    /// same URL and size
+    index: usize,
+    url: String,
+    size: u64,
+}
+
+/// The blocks fetched so far, by file index and block index, up to
+/// `max_bytes`
+#[derive(Debug)]
+struct BlockCache {
+    blocks: HashMap<(usize, u64), CachedBlock>,
+    bytes: u64,
+    max_bytes: u64,
+    /// Counts the uses of the blocks, to tell which was used least recently
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+impl BlockCache {
+    fn new(max_bytes: u64) -> Self {
+        BlockCache {
+            blocks: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: (usize, u64)) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let block = self.blocks.get_mut(&key)?;
+        block.last_used = self.clock;
+        Some(block.data.clone())
+    }
+
+    /// Adds a block, dropping the blocks used least recently to make room
+    fn insert(&mut self, key: (usize, u64), data: Arc<Vec<u8>>) {
+        self.clock += 1;
+        let len = data.len() as u64;
+        if let Some(previous) = self.blocks.insert(
+            key,
+            CachedBlock {
+                data,
+                last_used: self.clock,
+            },
+        ) {
+            self.bytes -= previous.data.len() as u64;
+        }
+        self.bytes += len;
+        while self.bytes > self.max_bytes {
+            let oldest = self
+                .blocks
+                .iter()
+                .filter(|(other, _)| **other != key)
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(key, _)| *key);
+            let oldest = match oldest {
+                Some(oldest) => oldest,
+                // A block larger than the cache is kept until the next one
+                None => break,
+            };
+            if let Some(block) = self.blocks.remove(&oldest) {
+                self.bytes -= block.data.len() as u64;
+            }
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl HttpFS {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        manifest: HttpFSManifest,
+        options: Option<HttpFSOptions>,
+    ) -> Result<HttpFS, JsValue> {
+        let manifest: JsValue = manifest.into();
+        let manifest = match manifest.as_string() {
+            Some(json) => js_sys::JSON::parse(&json)?,
+            None => manifest,
+        };
+        let entries: js_sys::Array = Reflect::get(&manifest, &"files".into())?
+            .dyn_into()
+            .map_err(|_| js_sys::Error::new("The manifest must have a `files` array"))?;
+        let mut files = HashMap::new();
+        let mut indices = HashMap::new();
+        let mut dirs = BTreeSet::from([PathBuf::from("/")]);
+        for entry in entries.iter() {
+            let path = Reflect::get(&entry, &"path".into())?.as_string();
+            let url = Reflect::get(&entry, &"url".into())?.as_string();
+            let size = Reflect::get(&entry, &"size".into())?.as_f64();
+            let (path, url, size) = match (path, url, size) {
+                (Some(path), Some(url), Some(size)) if size >= 0.0 => (path, url, size as u64),
+                _ => {
+                    return Err(js_sys::Error::new(
+                        "Every file in the manifest needs a `path`, a `url` and a `size`",
+                    )
+                    .into())
+                }
+            };
+            let path = path::normalize(Path::new("/"), Path::new(&path));
+            dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            let next_index = indices.len();
+            let index = *indices.entry((url.clone(), size)).or_insert(next_index);
+            files.insert(path, HttpFile { index, url, size });
+        }
+        if let Some(path) = files.keys().find(|path| dirs.contains(*path)) {
+            return Err(js_sys::Error::new(&format!(
+                "`{}` is both a file and a directory in the manifest",
+                path.display()
+            ))
+            .into());
+        }
+
+        let options: JsValue = options.map(Into::into).unwrap_or(JsValue::UNDEFINED);
+        let get = |key: &str| -> Result<JsValue, JsValue> {
+            if options.is_undefined() {
+                Ok(JsValue::UNDEFINED)
+            } else {
+                Reflect::get(&options, &key.into())
+            }
+        };
+        let block_size = match get("blockSize")?.as_f64() {
+            Some(block_size) if block_size >= 1.0 => block_size as u64,
+            Some(_) => return Err(js_sys::Error::new("The `blockSize` must be positive").into()),
+            None => DEFAULT_BLOCK_SIZE,
+        };
+        let max_cached_bytes = match get("maxCachedBytes")?.as_f64() {
+            Some(max) if max >= 0.0 => max as u64,
+            Some(_) => {
+                return Err(js_sys::Error::new("The `maxCachedBytes` can't be negative").into())
+            }
+            None => DEFAULT_MAX_CACHED_BYTES,
+        };
+        let fetch = get("fetch")?;
+        let fetch = if fetch.is_undefined() {
+            None
+        } else {
+            Some(
+                fetch
+                    .dyn_into()
+                    .map_err(|_| js_sys::Error::new("The `fetch` option must be a function"))?,
+            )
+        };
+
+        Ok(HttpFS {
+            inner: Arc::new(HttpFSInner {
+                fetch: JsHandle::new(fetch),
+                files,
+                dirs,
+                block_size,
+                blocks: Mutex::new(BlockCache::new(max_cached_bytes)),
+            }),
+        })
+    }
+
+    pub fn from_js(jso: JsValue) -> Result<HttpFS, JsValue> {
+        HttpFS::downcast_js(jso)
+    }
+
+    /// Open the file at `path` for reading
+    #[wasm_bindgen(js_name = open)]
+    pub fn js_open(&self, path: &str) -> Result<JSVirtualFile, JsValue> {
+        let file = self
+            .new_open_options()
+            .read(true)
+            .open(path)
+            .map_err(|e| js_sys::Error::new(&format!("Error when opening the file: {}", e)))?;
+        Ok(JSVirtualFile::new(file))
+    }
+
+    /// The number of bytes of the blocks in the cache
+    #[wasm_bindgen(getter, js_name = cachedBytes)]
+    pub fn cached_bytes(&self) -> f64 {
+        self.inner.blocks.lock().unwrap().bytes as f64
+    }
+}
+
+/// The default fetch function, with a synchronous `XMLHttpRequest`. The
+/// response is read as a binary string, since array buffers can't be
+/// requested synchronously outside of workers.
+fn fetch_with_xhr(url: &str, start: u64, end: u64) -> Result<Vec<u8>, JsValue> {
+    let constructor: Function = Reflect::get(&js_sys::global(), &"XMLHttpRequest".into())?
+        .dyn_into()
+        .map_err(|_| {
+            js_sys::Error::new(
+                "`XMLHttpRequest` is not available in this environment, a `fetch` option is needed",
+            )
+        })?;
+    let xhr = Reflect::construct(&constructor, &js_sys::Array::new())?;
+    streams::call_method(&xhr, "open", &["GET".into(), url.into(), false.into()])?;
+    let range = format!("bytes={}-{}", start, end - 1);
+    streams::call_method(&xhr, "setRequestHeader", &["Range".into(), range.into()])?;
+    let mime_type = "text/plain; charset=x-user-defined";
+    streams::call_method(&xhr, "overrideMimeType", &[mime_type.into()])?;
+    streams::call_method(&xhr, "send", &[])?;
+    let status = Reflect::get(&xhr, &"status".into())?
+        .as_f64()
+        .unwrap_or(0.0) as u32;
+    if status != 200 && status != 206 {
+        return Err(js_sys::Error::new(&format!(
+            "Fetching {} failed with the status {}",
+            url, status
+        ))
+        .into());
+    }
+    let text = Reflect::get(&xhr, &"responseText".into())?
+        .as_string()
+        .unwrap_or_default();
+    // Servers without range support send the whole file
+    let offset = if status == 200 { start as usize } else { 0 };
+    // Each byte is a UTF-16 code unit, whose low byte is the data
+    Ok(text
+        .encode_utf16()
+        .skip(offset)
+        .take((end - start) as usize)
+        .map(|unit| unit as u8)
+        .collect())
+}
+
+impl HttpFS {
+    fn file(&self, path: &Path) -> Result<&HttpFile, FsError> {
+        let path = path::normalize(Path::new("/"), path);
+        match self.inner.files.get(&path) {
+            Some(file) => Ok(file),
+            None if self.inner.dirs.contains(&path) => Err(FsError::NotAFile),
+            None => Err(FsError::EntityNotFound),
+        }
+    }
+
+    /// Reads the bytes of `file` starting at `offset` into `buf`, fetching the
+    /// blocks that aren't cached
+    fn read_at(&self, file: &HttpFile, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let end = file.size.min(offset + buf.len() as u64);
+        if offset >= end {
+            return Ok(0);
+        }
+        let block_size = self.inner.block_size;
+        let first = offset / block_size;
+        let blocks = self.blocks(file, first, (end - 1) / block_size)?;
+
+        let mut read = 0;
+        for (index, block) in (first..).zip(blocks) {
+            let block_start = index * block_size;
+            let to = ((end - block_start) as usize).min(block.len());
+            let from = ((offset + read as u64 - block_start) as usize).min(to);
+            buf[read..read + to - from].copy_from_slice(&block[from..to]);
+            read += to - from;
+        }
+        Ok(read)
+    }
+
+    /// The blocks of `file` from `first` to `last`, fetching the missing ones
+    /// with one request per run of consecutive missing blocks
+    fn blocks(&self, file: &HttpFile, first: u64, last: u64) -> io::Result<Vec<Arc<Vec<u8>>>> {
+        let block_size = self.inner.block_size;
+        let mut blocks = {
+            let mut cache = self.inner.blocks.lock().unwrap();
+            (first..=last)
+                .map(|index| cache.get((file.index, index)))
+                .collect::<Vec<_>>()
+        };
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for index in (first..=last).filter(|index| blocks[(index - first) as usize].is_none()) {
+            match runs.last_mut() {
+                Some((_, run_last)) if *run_last + 1 == index => *run_last = index,
+                _ => runs.push((index, index)),
+            }
+        }
+        for (run_first, run_last) in runs {
+            let start = run_first * block_size;
+            let end = file.size.min((run_last + 1) * block_size);
+            let data = self.fetch(&file.url, start, end)?;
+            let mut cache = self.inner.blocks.lock().unwrap();
+            for (index, chunk) in (run_first..).zip(data.chunks(block_size as usize)) {
+                let block = Arc::new(chunk.to_vec());
+                cache.insert((file.index, index), block.clone());
+                blocks[(index - first) as usize] = Some(block);
+            }
+        }
+        // Every block is either cached or fetched by now
+        Ok(blocks.into_iter().flatten().collect())
+    }
+
+    fn fetch(&self, url: &str, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let fetch = self
+            .inner
+            .fetch
+            .get()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "the filesystem is gone"))?;
+        let data = match fetch {
+            Some(fetch) => fetch
+                .call3(
+                    &JsValue::UNDEFINED,
+                    &url.into(),
+                    &(start as f64).into(),
+                    &(end as f64).into(),
+                )
+                .map(|data| Uint8Array::new(&data).to_vec()),
+            None => fetch_with_xhr(url, start, end),
+        }
+        .map_err(|e| {
+            let message = e
+                .dyn_ref::<js_sys::Error>()
+                .map(|e| String::from(e.message()))
+                .unwrap_or_else(|| format!("{:?}", e));
+            io::Error::new(io::ErrorKind::Other, message)
+        })?;
+        if data.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "fetching {} returned {} bytes instead of {}",
+                    url,
+                    data.len(),
+                    end - start
+                ),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+fn file_metadata(size: u64) -> Metadata {
+    Metadata {
+        ft: FileType {
+            file: true,
+            ..Default::default()
+        },
+        len: size,
+        ..Default::default()
+    }
+}
+
+fn dir_metadata() -> Metadata {
+    Metadata {
+        ft: FileType {
+            dir: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+impl FileSystem for HttpFS {
+    fn read_dir(&self, path: &Path) -> Result<ReadDir, FsError> {
+        let path = path::normalize(Path::new("/"), path);
+        if !self.inner.dirs.contains(&path) {
+            if self.inner.files.contains_key(&path) {
+                return Err(FsError::BaseNotDirectory);
+            }
+            return Err(FsError::EntityNotFound);
+        }
+        let dirs = self
+            .inner
+            .dirs
+            .iter()
+            .filter(|dir| dir.parent() == Some(path.as_path()))
+            .map(|dir| DirEntry {
+                path: dir.clone(),
+                metadata: Ok(dir_metadata()),
+            });
+        let files = self
+            .inner
+            .files
+            .iter()
+            .filter(|(file, _)| file.parent() == Some(path.as_path()))
+            .map(|(file, http_file)| DirEntry {
+                path: file.clone(),
+                metadata: Ok(file_metadata(http_file.size)),
+            });
+        let mut entries = dirs.chain(files).collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(ReadDir::new(entries))
+    }
+    fn create_dir(&self, _path: &Path) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn remove_dir(&self, _path: &Path) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn rename(&self, _from: &Path, _to: &Path) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        match self.file(path) {
+            Ok(file) => Ok(file_metadata(file.size)),
+            Err(FsError::NotAFile) => Ok(dir_metadata()),
+            Err(e) => Err(e),
+        }
+    }
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        self.metadata(path)
+    }
+    fn remove_file(&self, _path: &Path) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(Box::new(self.clone()))
+    }
+}
+
+impl FileOpener for HttpFS {
+    fn open(
+        &mut self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync>, FsError> {
+        if conf.write() || conf.append() || conf.truncate() || conf.create_new() {
+            return Err(FsError::PermissionDenied);
+        }
+        let file = self.file(path)?.clone();
+        Ok(Box::new(HttpFileHandle {
+            fs: self.clone(),
+            file,
+            position: 0,
+        }))
+    }
+}
+
+/// An open file of an `HttpFS`
+#[derive(Debug)]
+struct HttpFileHandle {
+    fs: HttpFS,
+    file: HttpFile,
+    position: u64,
+}
+
+impl Read for HttpFileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fs.read_at(&self.file, self.position, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for HttpFileHandle {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the HTTP filesystem is read-only",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for HttpFileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = |base: u64, offset: i64| {
+            if offset >= 0 {
+                base.checked_add(offset as u64)
+            } else {
+                base.checked_sub(offset.unsigned_abs())
+            }
+        };
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => offset(self.file.size, delta),
+            SeekFrom::Current(delta) => offset(self.position, delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl VirtualFile for HttpFileHandle {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+    fn last_modified(&self) -> u64 {
+        0
+    }
+    fn created_time(&self) -> u64 {
+        0
+    }
+    fn size(&self) -> u64 {
+        self.file.size
+    }
+    fn set_len(&mut self, _new_size: u64) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        Ok(self.file.size.saturating_sub(self.position) as usize)
+    }
+}
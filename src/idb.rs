@@ -0,0 +1,95 @@
+use js_sys::{Promise, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::streams::call_method;
+
+/// The IndexedDB database where the snapshots are stored
+const DATABASE_NAME: &str = "wasmer-wasi-js";
+const DATABASE_VERSION: u32 = 1;
+const STORE_NAME: &str = "memfs";
+
+/// Stores `data` under `name`, resolving once it is written
+pub(crate) fn save(name: &str, data: &[u8]) -> Result<Promise, JsValue> {
+    let name = JsValue::from(name);
+    let data = JsValue::from(Uint8Array::from(data));
+    with_store("readwrite", move |store| {
+        call_method(store, "put", &[data, name])
+    })
+}
+
+/// Resolves with the data stored under `name`, or `undefined` if there is none
+pub(crate) fn load(name: &str) -> Result<Promise, JsValue> {
+    let name = JsValue::from(name);
+    with_store("readonly", move |store| call_method(store, "get", &[name]))
+}
+
+/// Calls `f` on the value `promise` resolves with, giving the promise of its
+/// result
+pub(crate) fn then(
+    promise: &Promise,
+    f: impl FnOnce(JsValue) -> Result<JsValue, JsValue> + 'static,
+) -> Result<Promise, JsValue> {
+    let f = Closure::once_into_js(move |value: JsValue| -> JsValue {
+        f(value).unwrap_or_else(|e| wasm_bindgen::throw_val(e))
+    });
+    Ok(call_method(promise, "then", &[f])?.unchecked_into())
+}
+
+/// Runs the request made by `f` on the snapshot store, resolving with its
+/// result
+fn with_store(
+    mode: &'static str,
+    f: impl FnOnce(&JsValue) -> Result<JsValue, JsValue> + 'static,
+) -> Result<Promise, JsValue> {
+    then(&open_database()?, move |db| {
+        let transaction = call_method(&db, "transaction", &[STORE_NAME.into(), mode.into()])?;
+        let store = call_method(&transaction, "objectStore", &[STORE_NAME.into()])?;
+        let request = f(&store)?;
+        // The connection is only closed once the transaction is done
+        call_method(&db, "close", &[])?;
+        Ok(request_promise(&request)?.into())
+    })
+}
+
+fn open_database() -> Result<Promise, JsValue> {
+    let factory = Reflect::get(&js_sys::global(), &"indexedDB".into())?;
+    if factory.is_undefined() {
+        return Err(js_sys::Error::new("`indexedDB` is not available in this environment").into());
+    }
+    let request = call_method(
+        &factory,
+        "open",
+        &[DATABASE_NAME.into(), DATABASE_VERSION.into()],
+    )?;
+    let upgraded = request.clone();
+    let on_upgrade = Closure::once_into_js(move || {
+        let result = Reflect::get(&upgraded, &"result".into())
+            .and_then(|db| call_method(&db, "createObjectStore", &[STORE_NAME.into()]));
+        if let Err(e) = result {
+            wasm_bindgen::throw_val(e)
+        }
+    });
+    Reflect::set(&request, &"onupgradeneeded".into(), &on_upgrade)?;
+    request_promise(&request)
+}
+
+/// A promise settled by the `success` or `error` event of an IndexedDB request
+fn request_promise(request: &JsValue) -> Result<Promise, JsValue> {
+    let mut result = Ok(true);
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        let on_success = Closure::once_into_js(move || {
+            let value = Reflect::get(&succeeded, &"result".into()).unwrap_or(JsValue::UNDEFINED);
+            let _ = resolve.call1(&JsValue::UNDEFINED, &value);
+        });
+        let failed = request.clone();
+        let on_error = Closure::once_into_js(move || {
+            let error = Reflect::get(&failed, &"error".into()).unwrap_or(JsValue::UNDEFINED);
+            let _ = reject.call1(&JsValue::UNDEFINED, &error);
+        });
+        result = Reflect::set(request, &"onsuccess".into(), &on_success)
+            .and_then(|_| Reflect::set(request, &"onerror".into(), &on_error));
+    });
+    result.map(|_| promise)
+}
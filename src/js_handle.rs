@@ -0,0 +1,79 @@
+//! Handles on the JS values of the parts of the crate that wasmer requires to
+//! be `Send + Sync`, like filesystems, networks and buses.
+//!
+//! JS values can't be shared between threads, so those parts keep a
+//! `JsHandle` instead, and the value lives in a thread local for as long as
+//! the handle does.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The ids given to the values
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    /// The values of the live handles, by id
+    static VALUES: RefCell<HashMap<u32, Box<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// A value of type `T` kept in `VALUES` for as long as its handle lives
+pub(crate) struct JsHandle<T: 'static> {
+    id: u32,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> JsHandle<T> {
+    pub(crate) fn new(value: T) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        VALUES.with(|values| values.borrow_mut().insert(id, Box::new(value)));
+        JsHandle {
+            id,
+            value: PhantomData,
+        }
+    }
+
+    /// Calls `f` with the value, or returns `None` when it is gone because the
+    /// handle is used on another thread. `f` must not use other handles, so it
+    /// shouldn't call JS functions either.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        VALUES.with(|values| {
+            let mut values = values.borrow_mut();
+            let value = values.get_mut(&self.id)?.downcast_mut::<T>()?;
+            Some(f(value))
+        })
+    }
+}
+
+impl<T: Clone + 'static> JsHandle<T> {
+    /// A copy of the value, to call JS with it
+    pub(crate) fn get(&self) -> Option<T> {
+        self.with(|value| value.clone())
+    }
+}
+
+impl<T: Default + 'static> Default for JsHandle<T> {
+    fn default() -> Self {
+        JsHandle::new(T::default())
+    }
+}
+
+impl<T: 'static> fmt::Debug for JsHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsHandle").field("id", &self.id).finish()
+    }
+}
+
+impl<T: 'static> Drop for JsHandle<T> {
+    fn drop(&mut self) {
+        // The thread local may already be gone when the thread exits, and the
+        // value is dropped once it is out of the map
+        let _ = VALUES.try_with(|values| {
+            let value = values.borrow_mut().remove(&self.id);
+            drop(value)
+        });
+    }
+}
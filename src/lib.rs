@@ -1,5 +1,29 @@
 mod fs;
+mod glob;
+mod http_fs;
+mod idb;
+mod js_handle;
+mod loopback;
+mod net;
+mod path;
+mod process;
+mod runtime;
+mod sandbox;
+mod shell;
+mod snapshot;
+mod stdio;
+mod streams;
+mod suspend;
+mod syscalls;
+mod tty;
 mod wasi;
+mod watch;
 
-pub use crate::fs::{JSVirtualFile, MemFS};
-pub use crate::wasi::{WasiConfig, WASI};
+pub use crate::fs::{JSVirtualFile, MemFS, MemFSOptions};
+pub use crate::http_fs::{HttpFS, HttpFSManifest, HttpFSOptions};
+pub use crate::loopback::Network;
+pub use crate::shell::{Shell, ShellOptions};
+pub use crate::stdio::StdioCursor;
+pub use crate::streams::{ReadableStream, WritableStream};
+pub use crate::wasi::{pipeline, WasiConfig, WASI};
+pub use crate::watch::FSWatcher;
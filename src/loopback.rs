@@ -0,0 +1,443 @@
+//! An in-memory network that WASI instances can share, so that their guests
+//! can connect to each other without any real networking.
+
+use bytes::Bytes;
+use js_sys::Function;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_downcast::DowncastJS;
+use wasmer_vnet::{
+    NetworkError, SocketReceive, SocketStatus, TimeType, VirtualConnectedSocket, VirtualSocket,
+    VirtualTcpListener, VirtualTcpSocket,
+};
+
+use crate::js_handle::JsHandle;
+use crate::net::TcpNetwork;
+
+/// The ports given to the sockets that don't choose one
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// A virtual network that several `WASI` instances can share, with the
+/// `network` option. Their guests can listen and connect to each other with
+/// WASIX sockets, through in-memory pipes.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default, DowncastJS)]
+pub struct Network {
+    inner: Arc<Mutex<Namespace>>,
+}
+
+#[derive(Debug, Default)]
+struct Namespace {
+    /// The connections waiting to be accepted, by listening address
+    listeners: HashMap<SocketAddr, Backlog>,
+    /// The local ports of the connected sockets
+    connected_ports: HashSet<u16>,
+    /// The ephemeral port to try first next time
+    next_ephemeral_port: u16,
+    /// The resolve functions of the guests waiting for a connection
+    accepting: JsHandle<Vec<Function>>,
+}
+
+type Backlog = Arc<Mutex<VecDeque<(LoopbackSocket, SocketAddr)>>>;
+
+impl Namespace {
+    /// The next ephemeral port that no socket uses, going back to the start
+    /// of the range after its end
+    fn ephemeral_port(&mut self) -> Result<u16, NetworkError> {
+        let start = self.next_ephemeral_port.max(*EPHEMERAL_PORTS.start());
+        let port = (start..=*EPHEMERAL_PORTS.end())
+            .chain(*EPHEMERAL_PORTS.start()..start)
+            .find(|port| {
+                !self.connected_ports.contains(port)
+                    && !self.listeners.keys().any(|addr| addr.port() == *port)
+            })
+            .ok_or(NetworkError::AddressNotAvailable)?;
+        self.next_ephemeral_port = port.checked_add(1).unwrap_or(*EPHEMERAL_PORTS.start());
+        Ok(port)
+    }
+
+    /// Whether a listener or a connected socket already uses the port of
+    /// `addr`, which a socket can't bind to then
+    fn in_use(&self, addr: SocketAddr) -> bool {
+        self.listener(addr).is_some() || self.connected_ports.contains(&addr.port())
+    }
+
+    /// The listener that `addr` reaches
+    fn listener(&self, addr: SocketAddr) -> Option<(&SocketAddr, &Backlog)> {
+        self.listeners.iter().find(|(listening, _)| {
+            listening.port() == addr.port()
+                && (listening.ip().is_unspecified()
+                    || addr.ip().is_unspecified()
+                    || listening.ip() == addr.ip())
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl Network {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    pub fn from_js(jso: JsValue) -> Result<Network, JsValue> {
+        Network::downcast_js(jso)
+    }
+
+    /// The addresses that guests listen on, as sorted `ip:port` strings
+    #[wasm_bindgen(getter)]
+    pub fn listening(&self) -> js_sys::Array {
+        let mut addresses = self
+            .inner
+            .lock()
+            .unwrap()
+            .listeners
+            .keys()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses.into_iter().map(JsValue::from).collect()
+    }
+}
+
+impl Network {
+    /// A promise resolved once a guest connects to one of the listeners
+    pub(crate) fn connected(&self) -> js_sys::Promise {
+        js_sys::Promise::new(&mut |resolve, _reject| {
+            let namespace = self.inner.lock().unwrap();
+            namespace.accepting.with(|waiters| waiters.push(resolve));
+        })
+    }
+}
+
+impl TcpNetwork for Network {
+    fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>, NetworkError> {
+        let mut namespace = self.inner.lock().unwrap();
+        let addr = if addr.port() == 0 {
+            SocketAddr::new(addr.ip(), namespace.ephemeral_port()?)
+        } else if namespace.in_use(addr) {
+            return Err(NetworkError::AddressInUse);
+        } else {
+            addr
+        };
+        let backlog = Backlog::default();
+        namespace.listeners.insert(addr, backlog.clone());
+        Ok(Box::new(LoopbackListener {
+            network: self.clone(),
+            addr,
+            backlog,
+            timeout: None,
+            ttl: 64,
+        }))
+    }
+
+    fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>, NetworkError> {
+        let mut namespace = self.inner.lock().unwrap();
+        let (listening, backlog) = namespace
+            .listener(peer)
+            .map(|(listening, backlog)| (*listening, backlog.clone()))
+            .ok_or(NetworkError::ConnectionRefused)?;
+        let ip = if addr.ip().is_unspecified() {
+            peer.ip()
+        } else {
+            addr.ip()
+        };
+        let port = match addr.port() {
+            0 => namespace.ephemeral_port()?,
+            _ if namespace.in_use(addr) => return Err(NetworkError::AddressInUse),
+            port => port,
+        };
+        namespace.connected_ports.insert(port);
+        let addr = SocketAddr::new(ip, port);
+        let peer = SocketAddr::new(
+            if listening.ip().is_unspecified() {
+                peer.ip()
+            } else {
+                listening.ip()
+            },
+            listening.port(),
+        );
+
+        let (to_server, to_client) = (SharedPipe::default(), SharedPipe::default());
+        let server = LoopbackSocket::new(peer, addr, to_server.clone(), to_client.clone());
+        let mut client = LoopbackSocket::new(addr, peer, to_client, to_server);
+        client.network = Some(self.clone());
+        backlog.lock().unwrap().push_back((server, addr));
+        let waiters = namespace.accepting.with(std::mem::take).unwrap_or_default();
+        drop(namespace);
+        for resolve in waiters {
+            let _ = resolve.call0(&JsValue::UNDEFINED);
+        }
+        Ok(Box::new(client))
+    }
+
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, NetworkError> {
+        match host {
+            "localhost" => Ok(vec![IpAddr::from([127, 0, 0, 1])]),
+            _ => Err(NetworkError::AddressNotAvailable),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LoopbackListener {
+    network: Network,
+    addr: SocketAddr,
+    backlog: Backlog,
+    timeout: Option<Duration>,
+    ttl: u8,
+}
+
+impl Drop for LoopbackListener {
+    fn drop(&mut self) {
+        // No other listener can take the address while this one lives
+        self.network
+            .inner
+            .lock()
+            .unwrap()
+            .listeners
+            .remove(&self.addr);
+    }
+}
+
+impl VirtualTcpListener for LoopbackListener {
+    fn accept(&self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr), NetworkError> {
+        // The guest waits for a connection in `sock_accept` when it can
+        let (socket, addr) = self
+            .backlog
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or(NetworkError::WouldBlock)?;
+        Ok((Box::new(socket), addr))
+    }
+
+    fn accept_timeout(
+        &self,
+        _timeout: Duration,
+    ) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr), NetworkError> {
+        self.accept()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), NetworkError> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Result<Option<Duration>, NetworkError> {
+        Ok(self.timeout)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.addr)
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<(), NetworkError> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u8, NetworkError> {
+        Ok(self.ttl)
+    }
+}
+
+/// One direction of a connection
+#[derive(Debug, Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    /// Whether the writer or the reader shut it down
+    closed: bool,
+}
+
+type SharedPipe = Arc<Mutex<Pipe>>;
+
+/// One end of a connection
+#[derive(Debug)]
+struct LoopbackSocket {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    incoming: SharedPipe,
+    outgoing: SharedPipe,
+    /// The network to give the port of the socket back to, for the sockets
+    /// that connected rather than were accepted
+    network: Option<Network>,
+    ttl: u32,
+    nodelay: bool,
+    linger: Option<Duration>,
+}
+
+impl LoopbackSocket {
+    fn new(addr: SocketAddr, peer: SocketAddr, incoming: SharedPipe, outgoing: SharedPipe) -> Self {
+        LoopbackSocket {
+            addr,
+            peer,
+            incoming,
+            outgoing,
+            network: None,
+            ttl: 64,
+            nodelay: false,
+            linger: None,
+        }
+    }
+
+    fn receive(&self, consume: bool) -> Result<SocketReceive, NetworkError> {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.data.is_empty() {
+            return if incoming.closed {
+                // The end of the stream
+                Ok(SocketReceive {
+                    data: Bytes::new(),
+                    truncated: false,
+                })
+            } else {
+                Err(NetworkError::WouldBlock)
+            };
+        }
+        let data = if consume {
+            incoming.data.drain(..).collect::<Vec<_>>()
+        } else {
+            incoming.data.iter().copied().collect()
+        };
+        Ok(SocketReceive {
+            data: Bytes::from(data),
+            truncated: false,
+        })
+    }
+}
+
+impl Drop for LoopbackSocket {
+    fn drop(&mut self) {
+        let _ = VirtualTcpSocket::shutdown(self, Shutdown::Both);
+        if let Some(network) = &self.network {
+            let mut namespace = network.inner.lock().unwrap();
+            namespace.connected_ports.remove(&self.addr.port());
+        }
+    }
+}
+
+impl VirtualSocket for LoopbackSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<(), NetworkError> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32, NetworkError> {
+        Ok(self.ttl)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.addr)
+    }
+
+    fn status(&self) -> Result<SocketStatus, NetworkError> {
+        let closed = self.incoming.lock().unwrap().closed && self.outgoing.lock().unwrap().closed;
+        Ok(if closed {
+            SocketStatus::Closed
+        } else {
+            SocketStatus::Opened
+        })
+    }
+}
+
+impl VirtualConnectedSocket for LoopbackSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<(), NetworkError> {
+        self.linger = linger;
+        Ok(())
+    }
+
+    fn linger(&self) -> Result<Option<Duration>, NetworkError> {
+        Ok(self.linger)
+    }
+
+    fn send(&mut self, data: Bytes) -> Result<usize, NetworkError> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if outgoing.closed {
+            return Err(NetworkError::BrokenPipe);
+        }
+        outgoing.data.extend(&data[..]);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<SocketReceive, NetworkError> {
+        self.receive(true)
+    }
+
+    fn peek(&mut self) -> Result<SocketReceive, NetworkError> {
+        self.receive(false)
+    }
+}
+
+impl VirtualTcpSocket for LoopbackSocket {
+    fn set_opt_time(
+        &mut self,
+        _ty: TimeType,
+        _timeout: Option<Duration>,
+    ) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn opt_time(&self, _ty: TimeType) -> Result<Option<Duration>, NetworkError> {
+        Ok(None)
+    }
+
+    fn set_recv_buf_size(&mut self, _size: usize) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn recv_buf_size(&self) -> Result<usize, NetworkError> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn set_send_buf_size(&mut self, _size: usize) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn send_buf_size(&self) -> Result<usize, NetworkError> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> Result<(), NetworkError> {
+        self.nodelay = nodelay;
+        Ok(())
+    }
+
+    fn nodelay(&self) -> Result<bool, NetworkError> {
+        Ok(self.nodelay)
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.peer)
+    }
+
+    fn flush(&mut self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<(), NetworkError> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            let mut incoming = self.incoming.lock().unwrap();
+            incoming.closed = true;
+            incoming.data.clear();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.outgoing.lock().unwrap().closed = true;
+        }
+        Ok(())
+    }
+}
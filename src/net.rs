@@ -0,0 +1,457 @@
+//! Networking for the guests through a transport provided by JS, like a
+//! WebSocket relay or Node's `net` module.
+
+use bytes::Bytes;
+use std::fmt;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasmer_vnet::{
+    IpCidr, IpRoute, NetworkError, SocketHttpRequest, SocketReceive, SocketStatus, StreamSecurity,
+    TimeType, UnsupportedVirtualNetworking, VirtualConnectedSocket, VirtualIcmpSocket,
+    VirtualNetworking, VirtualRawSocket, VirtualSocket, VirtualTcpListener, VirtualTcpSocket,
+    VirtualUdpSocket, VirtualWebSocket,
+};
+
+use crate::js_handle::JsHandle;
+use crate::streams::call_method;
+
+#[wasm_bindgen(typescript_custom_section)]
+const NETWORK_TYPE_DEFINITIONS: &str = r#"
+/**
+ * Opens the TCP connections of the guest. WASI calls are synchronous, so all
+ * of the methods must be too.
+ *
+ * Only outgoing TCP connections go through the transport: the guest can't
+ * listen or use UDP, and peeking at the data received fails as unsupported.
+ */
+export type NetworkTransport = {
+    /** Connects to `port` of the IP `address`. */
+    connect(address: string, port: number): NetworkConnection;
+    /** The IP addresses of `host`, which fails to resolve when missing. */
+    resolve?(host: string): string[];
+};
+
+/** A TCP connection opened by a `NetworkTransport`. */
+export type NetworkConnection = {
+    send(data: Uint8Array): void;
+    /**
+     * The data received since the last call, an empty array when there is
+     * none yet, which the guest gets as `EAGAIN`, or `null` once the peer
+     * closed the connection, after which `recv` isn't called again.
+     */
+    recv(): Uint8Array | null;
+    close(): void;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "NetworkTransport")]
+    pub type NetworkTransport;
+}
+
+/// Calls `method` on the transport or connection of `handle`
+fn call(handle: &JsHandle<JsValue>, method: &str, args: &[JsValue]) -> Result<JsValue, JsValue> {
+    let object = handle
+        .get()
+        .ok_or_else(|| js_sys::Error::new("The transport is gone"))?;
+    call_method(&object, method, args)
+}
+
+/// The part of the networking that the networks of this crate implement
+pub(crate) trait TcpNetwork: fmt::Debug + Send + Sync + 'static {
+    fn listen_tcp(
+        &self,
+        _addr: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>, NetworkError> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>, NetworkError>;
+
+    /// The IP addresses of `host`, when it isn't an IP address itself
+    fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>, NetworkError> {
+        Err(NetworkError::AddressNotAvailable)
+    }
+}
+
+/// The networking of a guest, where everything but what `N` implements is
+/// unsupported
+#[derive(Debug)]
+pub(crate) struct Networking<N> {
+    network: N,
+    unsupported: UnsupportedVirtualNetworking,
+}
+
+impl<N: TcpNetwork> Networking<N> {
+    pub(crate) fn new(network: N) -> Self {
+        Networking {
+            network,
+            unsupported: UnsupportedVirtualNetworking::default(),
+        }
+    }
+}
+
+impl<N: TcpNetwork> VirtualNetworking for Networking<N> {
+    fn ws_connect(&self, url: &str) -> Result<Box<dyn VirtualWebSocket + Sync>, NetworkError> {
+        self.unsupported.ws_connect(url)
+    }
+
+    fn http_request(
+        &self,
+        url: &str,
+        method: &str,
+        headers: &str,
+        gzip: bool,
+    ) -> Result<SocketHttpRequest, NetworkError> {
+        self.unsupported.http_request(url, method, headers, gzip)
+    }
+
+    fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<(), NetworkError> {
+        self.unsupported.bridge(network, access_token, security)
+    }
+
+    fn unbridge(&self) -> Result<(), NetworkError> {
+        self.unsupported.unbridge()
+    }
+
+    fn dhcp_acquire(&self) -> Result<Vec<IpAddr>, NetworkError> {
+        self.unsupported.dhcp_acquire()
+    }
+
+    fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<(), NetworkError> {
+        self.unsupported.ip_add(ip, prefix)
+    }
+
+    fn ip_remove(&self, ip: IpAddr) -> Result<(), NetworkError> {
+        self.unsupported.ip_remove(ip)
+    }
+
+    fn ip_clear(&self) -> Result<(), NetworkError> {
+        self.unsupported.ip_clear()
+    }
+
+    fn ip_list(&self) -> Result<Vec<IpCidr>, NetworkError> {
+        self.unsupported.ip_list()
+    }
+
+    fn mac(&self) -> Result<[u8; 6], NetworkError> {
+        self.unsupported.mac()
+    }
+
+    fn gateway_set(&self, ip: IpAddr) -> Result<(), NetworkError> {
+        self.unsupported.gateway_set(ip)
+    }
+
+    fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<(), NetworkError> {
+        self.unsupported
+            .route_add(cidr, via_router, preferred_until, expires_at)
+    }
+
+    fn route_remove(&self, cidr: IpAddr) -> Result<(), NetworkError> {
+        self.unsupported.route_remove(cidr)
+    }
+
+    fn route_clear(&self) -> Result<(), NetworkError> {
+        self.unsupported.route_clear()
+    }
+
+    fn route_list(&self) -> Result<Vec<IpRoute>, NetworkError> {
+        self.unsupported.route_list()
+    }
+
+    fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>, NetworkError> {
+        self.unsupported.bind_raw()
+    }
+
+    fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        _only_v6: bool,
+        _reuse_port: bool,
+        _reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>, NetworkError> {
+        self.network.listen_tcp(addr)
+    }
+
+    fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>, NetworkError> {
+        self.unsupported.bind_udp(addr, reuse_port, reuse_addr)
+    }
+
+    fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>, NetworkError> {
+        self.unsupported.bind_icmp(addr)
+    }
+
+    fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+        _timeout: Option<Duration>,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>, NetworkError> {
+        self.network.connect_tcp(addr, peer)
+    }
+
+    fn resolve(
+        &self,
+        host: &str,
+        _port: Option<u16>,
+        _dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>, NetworkError> {
+        match host.parse::<IpAddr>() {
+            Ok(addr) => Ok(vec![addr]),
+            Err(_) => self.network.resolve(host),
+        }
+    }
+}
+
+/// A network that opens the TCP connections of the guest with a
+/// `NetworkTransport`. Listening, UDP and raw sockets aren't supported.
+#[derive(Debug)]
+pub(crate) struct JsNetwork {
+    transport: JsHandle<JsValue>,
+}
+
+impl JsNetwork {
+    pub(crate) fn new(transport: NetworkTransport) -> Result<Self, JsValue> {
+        let connect = js_sys::Reflect::get(&transport, &"connect".into())?;
+        if !connect.is_function() {
+            return Err(js_sys::Error::new("The `network` must have a `connect` method").into());
+        }
+        Ok(JsNetwork {
+            transport: JsHandle::new(transport.into()),
+        })
+    }
+}
+
+impl TcpNetwork for JsNetwork {
+    fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>, NetworkError> {
+        let connection = call(
+            &self.transport,
+            "connect",
+            &[peer.ip().to_string().into(), peer.port().into()],
+        )
+        .map_err(|_| NetworkError::ConnectionRefused)?;
+        if !connection.is_object() {
+            return Err(NetworkError::ConnectionRefused);
+        }
+        Ok(Box::new(JsTcpSocket {
+            connection: JsHandle::new(connection),
+            addr,
+            peer,
+            closed: false,
+            ended: false,
+            ttl: 64,
+            nodelay: false,
+            linger: None,
+        }))
+    }
+
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, NetworkError> {
+        let addresses = call(&self.transport, "resolve", &[host.into()])
+            .map_err(|_| NetworkError::AddressNotAvailable)?;
+        if !js_sys::Array::is_array(&addresses) {
+            return Err(NetworkError::AddressNotAvailable);
+        }
+        js_sys::Array::from(&addresses)
+            .iter()
+            .map(|address| {
+                address
+                    .as_string()
+                    .and_then(|address| address.parse().ok())
+                    .ok_or(NetworkError::InvalidData)
+            })
+            .collect()
+    }
+}
+
+/// A TCP connection opened by a `NetworkTransport`
+#[derive(Debug)]
+struct JsTcpSocket {
+    connection: JsHandle<JsValue>,
+    addr: SocketAddr,
+    peer: SocketAddr,
+    closed: bool,
+    /// Whether the peer closed the connection, after which there is nothing
+    /// more to receive
+    ended: bool,
+    ttl: u32,
+    nodelay: bool,
+    linger: Option<Duration>,
+}
+
+impl JsTcpSocket {
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            // There is nobody to report the error to
+            let _ = call(&self.connection, "close", &[]);
+        }
+    }
+}
+
+impl Drop for JsTcpSocket {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl VirtualSocket for JsTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<(), NetworkError> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32, NetworkError> {
+        Ok(self.ttl)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.addr)
+    }
+
+    fn status(&self) -> Result<SocketStatus, NetworkError> {
+        Ok(if self.closed {
+            SocketStatus::Closed
+        } else {
+            SocketStatus::Opened
+        })
+    }
+}
+
+impl VirtualConnectedSocket for JsTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<(), NetworkError> {
+        self.linger = linger;
+        Ok(())
+    }
+
+    fn linger(&self) -> Result<Option<Duration>, NetworkError> {
+        Ok(self.linger)
+    }
+
+    fn send(&mut self, data: Bytes) -> Result<usize, NetworkError> {
+        if self.closed {
+            return Err(NetworkError::NotConnected);
+        }
+        call(
+            &self.connection,
+            "send",
+            &[js_sys::Uint8Array::from(&data[..]).into()],
+        )
+        .map_err(|_| NetworkError::BrokenPipe)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<SocketReceive, NetworkError> {
+        if self.closed {
+            return Err(NetworkError::NotConnected);
+        }
+        let data = if self.ended {
+            JsValue::NULL
+        } else {
+            call(&self.connection, "recv", &[]).map_err(|_| NetworkError::ConnectionReset)?
+        };
+        // The end of the stream
+        if data.is_null() || data.is_undefined() {
+            self.ended = true;
+            return Ok(SocketReceive {
+                data: Bytes::new(),
+                truncated: false,
+            });
+        }
+        let data = js_sys::Uint8Array::new(&data).to_vec();
+        if data.is_empty() {
+            return Err(NetworkError::WouldBlock);
+        }
+        Ok(SocketReceive {
+            data: Bytes::from(data),
+            truncated: false,
+        })
+    }
+
+    fn peek(&mut self) -> Result<SocketReceive, NetworkError> {
+        // Peeking would need a buffer for the data received in the meantime
+        Err(NetworkError::Unsupported)
+    }
+}
+
+impl VirtualTcpSocket for JsTcpSocket {
+    fn set_opt_time(
+        &mut self,
+        _ty: TimeType,
+        _timeout: Option<Duration>,
+    ) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn opt_time(&self, _ty: TimeType) -> Result<Option<Duration>, NetworkError> {
+        Ok(None)
+    }
+
+    fn set_recv_buf_size(&mut self, _size: usize) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn recv_buf_size(&self) -> Result<usize, NetworkError> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn set_send_buf_size(&mut self, _size: usize) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn send_buf_size(&self) -> Result<usize, NetworkError> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> Result<(), NetworkError> {
+        self.nodelay = nodelay;
+        Ok(())
+    }
+
+    fn nodelay(&self) -> Result<bool, NetworkError> {
+        Ok(self.nodelay)
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.peer)
+    }
+
+    fn flush(&mut self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self, _how: Shutdown) -> Result<(), NetworkError> {
+        // The transports can only close both directions at once
+        self.close();
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+//! Path handling shared by the filesystems, for the paths given by JS and by
+//! the guest alike.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Makes `path` absolute, starting from `cwd` when it is relative, and removes
+/// the `.` and `..` components, the duplicate separators and the trailing
+/// slash. This is only lexical, symlinks are resolved by the filesystems.
+pub(crate) fn normalize(cwd: &Path, path: &Path) -> PathBuf {
+    let mut normalized = if path.has_root() {
+        PathBuf::from("/")
+    } else {
+        cwd.to_path_buf()
+    };
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                // `..` stays at the root
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+/// Whether `path` can only name a directory, because it ends with a
+/// separator or with `.` or `..`
+pub(crate) fn names_dir(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.ends_with('/')
+        || path.ends_with("/.")
+        || path.ends_with("/..")
+        || path == "."
+        || path == ".."
+}
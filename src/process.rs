@@ -0,0 +1,300 @@
+//! The processes that guests spawn with WASIX `proc_spawn` and `proc_exec`,
+//! from the commands registered on their `WASI` instance.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasmer_vbus::{
+    BusDataFormat, BusSpawnedProcess, SpawnOptions, SpawnOptionsConfig, StdioMode, VirtualBus,
+    VirtualBusError, VirtualBusInvocation, VirtualBusInvokable, VirtualBusListener,
+    VirtualBusProcess, VirtualBusScope, VirtualBusSpawner,
+};
+use wasmer_wasi::{Pipe, WasiTtyState};
+
+use crate::js_handle::JsHandle;
+use crate::path;
+use crate::stdio::{OutputPipe, SharedStdio};
+use crate::wasi::WASI;
+
+struct Registry {
+    modules: HashMap<String, js_sys::WebAssembly::Module>,
+    /// The filesystem that the processes share
+    fs: JsValue,
+}
+
+/// The commands that an instance, and the processes it spawns, can run
+#[derive(Debug, Clone)]
+pub(crate) struct Commands {
+    registry: Arc<JsHandle<Registry>>,
+}
+
+impl Commands {
+    /// An empty registry whose processes use the `fs` filesystem
+    pub(crate) fn new(fs: JsValue) -> Self {
+        let registry = Registry {
+            modules: HashMap::new(),
+            fs,
+        };
+        Commands {
+            registry: Arc::new(JsHandle::new(registry)),
+        }
+    }
+
+    pub(crate) fn register(&self, name: String, module: js_sys::WebAssembly::Module) {
+        self.registry
+            .with(|registry| registry.modules.insert(name, module));
+    }
+
+    /// The module of the command `name`, which can also be a path ending with
+    /// the name, like `/bin/name`, and the filesystem to run it with
+    pub(crate) fn get(&self, name: &str) -> Option<(js_sys::WebAssembly::Module, JsValue)> {
+        self.registry
+            .with(|registry| {
+                let module = registry.modules.get(name).or_else(|| {
+                    let file_name = Path::new(name).file_name()?.to_str()?;
+                    registry.modules.get(file_name)
+                })?;
+                Some((module.clone(), registry.fs.clone()))
+            })
+            .flatten()
+    }
+}
+
+/// The bus of a guest, which spawns the registered commands
+#[derive(Debug, Clone)]
+pub(crate) struct Bus {
+    commands: Commands,
+    env: Vec<(String, String)>,
+    /// The working directory of the guest, where processes start by default
+    cwd: String,
+    /// The stdio of the guest, that processes inherit
+    stdio: Inherited,
+    exits: Arc<Mutex<Exits>>,
+}
+
+/// The stdio handles and the terminal that a process inherits from the guest
+/// that spawned it, for the streams it doesn't pipe
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Inherited {
+    pub(crate) stdin: Option<SharedStdio>,
+    pub(crate) stdout: Option<SharedStdio>,
+    pub(crate) stderr: Option<SharedStdio>,
+    pub(crate) tty: Option<Arc<Mutex<WasiTtyState>>>,
+}
+
+/// The exit codes of the processes that a guest spawned, until it polls them
+#[derive(Debug, Default)]
+struct Exits {
+    /// The exit code of the process spawned last, until wasmer-wasi gives it
+    /// a handle
+    last: Option<u32>,
+    /// The handles of the processes that exited, with their exit codes
+    unpolled: VecDeque<(u32, u32)>,
+}
+
+impl Bus {
+    pub(crate) fn new(
+        commands: Commands,
+        env: Vec<(String, String)>,
+        cwd: String,
+        stdio: Inherited,
+    ) -> Self {
+        Bus {
+            commands,
+            env,
+            cwd,
+            stdio,
+            exits: Arc::default(),
+        }
+    }
+
+    /// Records that the process spawned last got the handle `bid`, so that
+    /// its exit code gets polled with it
+    pub(crate) fn spawned(&self, bid: u32) {
+        let mut exits = self.exits.lock().unwrap();
+        if let Some(exit_code) = exits.last.take() {
+            exits.unpolled.push_back((bid, exit_code));
+        }
+    }
+
+    /// Takes at most `max` of the handles of the processes that exited, with
+    /// their exit codes
+    pub(crate) fn poll_exits(&self, max: usize) -> Vec<(u32, u32)> {
+        let mut exits = self.exits.lock().unwrap();
+        let count = max.min(exits.unpolled.len());
+        exits.unpolled.drain(..count).collect()
+    }
+
+    /// Runs the command `name` with `args` and the stdio of the guest, for
+    /// `proc_exec`, returning its exit code
+    pub(crate) fn exec(&self, name: &str, args: Vec<String>) -> Result<u32, VirtualBusError> {
+        let mut options = self.new_spawn();
+        options.args(args);
+        options.stdin_mode(StdioMode::Inherit);
+        options.stdout_mode(StdioMode::Inherit);
+        options.stderr_mode(StdioMode::Inherit);
+        let process = options.spawn(name)?;
+        // The process doesn't get a handle
+        self.exits.lock().unwrap().last = None;
+        Ok(process.inst.exit_code().unwrap_or(0))
+    }
+
+    /// The config of a `WASI` instance running `name` with the `config` of the
+    /// spawn
+    fn wasi_config(
+        &self,
+        name: &str,
+        fs: JsValue,
+        config: &SpawnOptionsConfig,
+    ) -> Result<js_sys::Object, JsValue> {
+        let args = std::iter::once(name)
+            .chain(config.args().iter().map(String::as_str))
+            .map(JsValue::from)
+            .collect::<js_sys::Array>();
+        // Relative working directories start from the one of the guest
+        let cwd = path::normalize(Path::new(&self.cwd), Path::new(config.working_dir()));
+        let cwd = cwd.to_string_lossy().into_owned();
+        let env = js_sys::Object::new();
+        for (key, value) in &self.env {
+            js_sys::Reflect::set(&env, &key.into(), &value.into())?;
+        }
+        js_sys::Reflect::set(&env, &"PWD".into(), &cwd.as_str().into())?;
+        let wasi_config = js_sys::Object::new();
+        js_sys::Reflect::set(&wasi_config, &"args".into(), &args)?;
+        js_sys::Reflect::set(&wasi_config, &"env".into(), &env)?;
+        js_sys::Reflect::set(&wasi_config, &"fs".into(), &fs)?;
+        js_sys::Reflect::set(&wasi_config, &"cwd".into(), &cwd.into())?;
+        Ok(wasi_config)
+    }
+
+    /// The stdio of the guest that a process inherits, with the modes of
+    /// the `config` of its spawn
+    fn inherited(&self, config: &SpawnOptionsConfig) -> Inherited {
+        let inherit = |mode: StdioMode, handle: &Option<SharedStdio>| match mode {
+            StdioMode::Piped | StdioMode::Null => None,
+            _ => handle.clone(),
+        };
+        let stdin = inherit(config.stdin_mode(), &self.stdio.stdin);
+        let stdout = inherit(config.stdout_mode(), &self.stdio.stdout);
+        let stderr = inherit(config.stderr_mode(), &self.stdio.stderr);
+        // A process inheriting all the stdio shares the terminal, so that its
+        // modes apply to the guest too. Otherwise only the streams it
+        // inherits are still the terminal.
+        let tty = self.stdio.tty.as_ref().map(|tty| {
+            if stdin.is_some() && stdout.is_some() && stderr.is_some() {
+                return tty.clone();
+            }
+            let mut state = tty.lock().unwrap().clone();
+            state.stdin_tty &= stdin.is_some();
+            state.stdout_tty &= stdout.is_some();
+            state.stderr_tty &= stderr.is_some();
+            Arc::new(Mutex::new(state))
+        });
+        Inherited {
+            stdin,
+            stdout,
+            stderr,
+            tty,
+        }
+    }
+
+    /// Runs the command `name` until it exits
+    fn run(&self, name: &str, config: &SpawnOptionsConfig) -> Result<Exited, VirtualBusError> {
+        let (module, fs) = self.commands.get(name).ok_or(VirtualBusError::NotFound)?;
+        let wasi_config = self
+            .wasi_config(name, fs, config)
+            .map_err(|_| VirtualBusError::InternalError)?;
+        // The inherited stdio is read and written as the process runs, so
+        // what it doesn't read stays for the guest
+        let mut wasi = WASI::with_commands(
+            wasi_config.unchecked_into(),
+            Some(self.commands.clone()),
+            self.inherited(config),
+        )
+        .map_err(|_| VirtualBusError::BadRequest)?;
+        wasi.instantiate(module.into(), None)
+            .map_err(|_| VirtualBusError::InvalidABI)?;
+        let exit_code = wasi.start(None).map_err(|_| VirtualBusError::Aborted)?;
+        self.exits.lock().unwrap().last = Some(exit_code);
+
+        let piped = |mode: StdioMode| matches!(mode, StdioMode::Piped);
+        Ok(Exited {
+            exit_code,
+            stdin: piped(config.stdin_mode()).then(|| wasi.stdin_pipe()),
+            stdout: piped(config.stdout_mode()).then(|| wasi.stdout_pipe()),
+            stderr: piped(config.stderr_mode()).then(|| wasi.stderr_pipe()),
+        })
+    }
+}
+
+impl VirtualBus for Bus {
+    fn new_spawn(&self) -> SpawnOptions {
+        SpawnOptions::new(Box::new(self.clone()))
+    }
+
+    fn listen(&self) -> Result<Box<dyn VirtualBusListener + Sync>, VirtualBusError> {
+        Err(VirtualBusError::Unsupported)
+    }
+}
+
+impl VirtualBusSpawner for Bus {
+    fn spawn(
+        &mut self,
+        name: &str,
+        config: &SpawnOptionsConfig,
+    ) -> Result<BusSpawnedProcess, VirtualBusError> {
+        // Nothing else can run while the guest waits, so the process runs
+        // until it exits before the guest gets it. Its piped stdin is only
+        // written once it read the end of it.
+        let mut process = self.run(name, config)?;
+        let stdin = process.stdin.take();
+        let stdout = process.stdout.take();
+        let stderr = process.stderr.take();
+        Ok(BusSpawnedProcess {
+            inst: Box::new(process),
+            stdin: stdin.map(|stdin| Box::new(stdin) as _),
+            stdout: stdout.map(|stdout| Box::new(stdout) as _),
+            stderr: stderr.map(|stderr| Box::new(stderr) as _),
+        })
+    }
+}
+
+/// A process that already exited, with its piped stdio
+#[derive(Debug)]
+struct Exited {
+    exit_code: u32,
+    stdin: Option<Pipe>,
+    stdout: Option<OutputPipe>,
+    stderr: Option<OutputPipe>,
+}
+
+impl VirtualBusProcess for Exited {
+    fn exit_code(&self) -> Option<u32> {
+        Some(self.exit_code)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+impl VirtualBusScope for Exited {
+    fn poll_finished(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+}
+
+impl VirtualBusInvokable for Exited {
+    fn invoke(
+        &self,
+        _topic: String,
+        _format: BusDataFormat,
+        _buf: &[u8],
+    ) -> Result<Box<dyn VirtualBusInvocation + Sync>, VirtualBusError> {
+        Err(VirtualBusError::Unsupported)
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::{Arc, Mutex};
+use wasmer_wasi::{
+    PluggableRuntimeImplementation, VirtualBus, VirtualNetworking, WasiRuntimeImplementation,
+    WasiThreadId, WasiTtyState,
+};
+
+/// The runtime that WASI instances use for the services that don't come from
+/// the filesystem
+#[derive(Debug, Default)]
+pub(crate) struct Runtime {
+    inner: PluggableRuntimeImplementation,
+    tty: Option<Arc<Mutex<WasiTtyState>>>,
+    networking: Option<Box<dyn VirtualNetworking>>,
+    bus: Option<Box<dyn VirtualBus>>,
+}
+
+impl Runtime {
+    pub(crate) fn new(
+        tty: Option<Arc<Mutex<WasiTtyState>>>,
+        networking: Option<Box<dyn VirtualNetworking>>,
+        bus: Box<dyn VirtualBus>,
+    ) -> Self {
+        Runtime {
+            inner: PluggableRuntimeImplementation::default(),
+            tty,
+            networking,
+            bus: Some(bus),
+        }
+    }
+}
+
+impl WasiRuntimeImplementation for Runtime {
+    fn bus(&self) -> &(dyn VirtualBus) {
+        match &self.bus {
+            Some(bus) => bus.as_ref(),
+            None => self.inner.bus(),
+        }
+    }
+
+    fn networking(&self) -> &(dyn VirtualNetworking) {
+        match &self.networking {
+            Some(networking) => networking.as_ref(),
+            None => self.inner.networking(),
+        }
+    }
+
+    fn thread_generate_id(&self) -> WasiThreadId {
+        self.inner.thread_generate_id()
+    }
+
+    fn tty_get(&self) -> WasiTtyState {
+        match &self.tty {
+            Some(tty) => tty.lock().unwrap().clone(),
+            None => self.inner.tty_get(),
+        }
+    }
+
+    fn tty_set(&self, tty_state: WasiTtyState) {
+        match &self.tty {
+            Some(tty) => *tty.lock().unwrap() = tty_state,
+            None => self.inner.tty_set(tty_state),
+        }
+    }
+}
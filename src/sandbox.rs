@@ -0,0 +1,347 @@
+//! Confines the paths given by the guest to its preopens, so that each
+//! preopen acts as a chroot onto its sub-tree of the filesystem.
+//!
+//! The paths that leave their directory are rejected as they are given, and
+//! the `MemFS` rejects those that only leave the preopen of their fd once
+//! their symlinks are resolved. Either way the guest gets `ENOTCAPABLE`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use js_sys::{Array, Function, Number, Object, Proxy, Reflect, Uint8Array, WebAssembly};
+use wasm_bindgen::prelude::*;
+use wasmer::Module;
+use wasmer_vfs::FsError;
+use wasmer_wasi::WasiState;
+
+use crate::fs::MemFS;
+use crate::syscalls;
+
+/// The errno returned for the paths that leave their directory
+const ENOTCAPABLE: u32 = 76;
+
+/// The namespaces of the WASI versions whose pointers are 32-bit
+pub(crate) const NAMESPACES: &[&str] = &["wasi_unstable", "wasi_snapshot_preview1", "wasix_32v1"];
+
+/// The namespaces of the WASI versions whose pointers are 64-bit, which take
+/// the same arguments
+pub(crate) const NAMESPACES_64: &[&str] = &["wasix_64v1"];
+
+/// The functions that take paths, with the positions of the fd, pointer and
+/// length arguments of each path
+const PATH_FUNCTIONS: &[(&str, &[(u32, u32, u32)])] = &[
+    ("path_create_directory", &[(0, 1, 2)]),
+    ("path_filestat_get", &[(0, 2, 3)]),
+    ("path_filestat_set_times", &[(0, 2, 3)]),
+    ("path_link", &[(0, 2, 3), (4, 5, 6)]),
+    ("path_open", &[(0, 2, 3)]),
+    ("path_readlink", &[(0, 1, 2)]),
+    ("path_remove_directory", &[(0, 1, 2)]),
+    ("path_rename", &[(0, 1, 2), (3, 4, 5)]),
+    ("path_symlink", &[(2, 3, 4)]),
+    ("path_unlink_file", &[(0, 1, 2)]),
+];
+
+/// The position of the pointer where `path_open` writes the opened fd
+const PATH_OPEN_FD: u32 = 8;
+
+/// The memory of the guest, set once it is instantiated
+pub(crate) type GuestMemory = Rc<RefCell<Option<WebAssembly::Memory>>>;
+
+/// The directories a guest is confined to, shared by its imports and the
+/// `MemFS` that resolves its paths
+#[derive(Debug)]
+pub(crate) struct Sandbox {
+    /// The resolved paths of the preopens
+    roots: Vec<PathBuf>,
+    /// The root of the preopen that each directory the guest opened is in,
+    /// by fd
+    fds: Mutex<HashMap<u32, PathBuf>>,
+    /// The roots of the fds of the call in progress. Outside of the calls
+    /// taking paths, like when wasmer-wasi opens the preopens, any preopen
+    /// will do.
+    current: Mutex<Vec<PathBuf>>,
+    /// Whether the filesystem rejected a path since the last call
+    escaped: AtomicBool,
+}
+
+impl Sandbox {
+    pub(crate) fn new(roots: Vec<PathBuf>) -> Self {
+        Sandbox {
+            roots,
+            fds: Mutex::new(HashMap::new()),
+            current: Mutex::new(vec![]),
+            escaped: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that `fd` is in the preopen at `root`
+    pub(crate) fn track(&self, fd: u32, root: PathBuf) {
+        self.fds.lock().unwrap().insert(fd, root);
+    }
+
+    /// The root of the preopen that `fd` is in. The fds that weren't opened
+    /// through a preopen, like those renumbered, get the deepest preopen
+    /// holding their directory.
+    fn root(&self, state: &WasiState, fd: u32) -> Option<PathBuf> {
+        if let Some(root) = self.fds.lock().unwrap().get(&fd) {
+            return Some(root.clone());
+        }
+        let dir = syscalls::fd_path(state, fd).ok()?;
+        self.roots
+            .iter()
+            .filter(|root| dir.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .cloned()
+    }
+
+    /// Confines the paths resolved until `leave` to `roots`
+    fn enter(&self, roots: Vec<PathBuf>) {
+        *self.current.lock().unwrap() = roots;
+    }
+
+    fn leave(&self) {
+        self.current.lock().unwrap().clear();
+    }
+
+    /// Checks that the resolved `path` is in the preopen of the call in
+    /// progress
+    pub(crate) fn check(&self, path: &Path) -> Result<(), FsError> {
+        let current = self.current.lock().unwrap();
+        let roots = if current.is_empty() {
+            &self.roots
+        } else {
+            &*current
+        };
+        if roots.iter().any(|root| path.starts_with(root)) {
+            Ok(())
+        } else {
+            self.escaped.store(true, Ordering::Relaxed);
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// The root that the absolute target of the symlink at the resolved
+    /// `link` is relative to, as the guest sees its preopen as `/`
+    pub(crate) fn root_of(&self, link: &Path) -> Option<PathBuf> {
+        let current = self.current.lock().unwrap();
+        let roots = if current.is_empty() {
+            &self.roots
+        } else {
+            &*current
+        };
+        roots
+            .iter()
+            .filter(|root| link.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .cloned()
+    }
+
+    /// Whether the filesystem rejected a path since the last call
+    fn take_escaped(&self) -> bool {
+        self.escaped.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Wraps the functions of the WASI `imports` that `module` imports and that
+/// take paths, so that they fail with `ENOTCAPABLE` instead of following a
+/// path out of the preopen of its fd. The wrapped functions are returned as a
+/// new imports object.
+pub(crate) fn confine(
+    imports: &Object,
+    module: &Module,
+    state: Arc<WasiState>,
+    memory: &GuestMemory,
+    sandbox: &Arc<Sandbox>,
+) -> Result<Object, JsValue> {
+    let confined = Object::new();
+    for import in module.imports() {
+        if !NAMESPACES.contains(&import.module()) && !NAMESPACES_64.contains(&import.module()) {
+            continue;
+        }
+        let paths = match PATH_FUNCTIONS
+            .iter()
+            .find(|(name, _)| *name == import.name())
+        {
+            Some((_, paths)) => *paths,
+            None => continue,
+        };
+        let namespace = Reflect::get(imports, &import.module().into())?;
+        if !namespace.is_object() {
+            continue;
+        }
+        let original = Reflect::get(&namespace, &import.name().into())?;
+        if !original.is_function() {
+            continue;
+        }
+
+        let is_symlink = import.name() == "path_symlink";
+        let is_open = import.name() == "path_open";
+        let (state, memory) = (state.clone(), memory.clone());
+        let sandbox = sandbox.clone();
+        // The roots of the fds of the call, or `None` if one of its paths
+        // leaves its preopen
+        let check = {
+            let (sandbox, memory) = (sandbox.clone(), memory.clone());
+            move |args: &Array| -> Option<Vec<PathBuf>> {
+                let memory = memory.borrow();
+                let memory = match memory.as_ref() {
+                    Some(memory) => memory,
+                    None => return Some(vec![]),
+                };
+                let fs = state.fs.fs_backing.downcast_ref::<MemFS>();
+                let read = |ptr, len| read_path(memory, args, ptr, len);
+                let mut roots = vec![];
+                for &(fd, ptr, len) in paths {
+                    let fd = match pointer(&args.get(fd)) {
+                        Some(fd) => fd,
+                        None => continue,
+                    };
+                    // wasmer-wasi fails with `EBADF` on the fds that aren't
+                    // directories
+                    let (root, dir) =
+                        match (sandbox.root(&state, fd), syscalls::fd_path(&state, fd)) {
+                            (Some(root), Ok(dir)) => (root, dir),
+                            _ => continue,
+                        };
+                    if let Some(path) = read(ptr, len) {
+                        depth(Path::new(&path), 0)?;
+                        // Each path is resolved in the preopen of its own fd
+                        // here, as the call is confined to those of all its fds
+                        if let Some(fs) = fs {
+                            sandbox.enter(vec![root.clone()]);
+                            let resolved = fs.resolve_parent(&dir.join(&path));
+                            sandbox.leave();
+                            if let Err(FsError::PermissionDenied) = resolved {
+                                return None;
+                            }
+                        }
+                    }
+                    roots.push(root);
+                }
+                // The target of a symlink is relative to the directory of the
+                // link, and must not leave the directory either once followed.
+                // Absolute targets are relative to the preopen.
+                if is_symlink {
+                    if let (Some(target), Some(link)) = (read(0, 1), read(3, 4)) {
+                        let target = Path::new(&target);
+                        match target.strip_prefix("/") {
+                            Ok(target) => depth(target, 0)?,
+                            Err(_) => {
+                                let parent =
+                                    depth(Path::new(&link), 0).unwrap_or(0).saturating_sub(1);
+                                depth(target, parent)?
+                            }
+                        };
+                    }
+                }
+                Some(roots)
+            }
+        };
+        // The arguments are forwarded as they are, whatever their number
+        let apply = Closure::wrap(Box::new(
+            move |target: Function, this: JsValue, args: Array| -> Result<JsValue, JsValue> {
+                let roots = match check(&args) {
+                    Some(roots) => roots,
+                    None => return Ok(ENOTCAPABLE.into()),
+                };
+                sandbox.take_escaped();
+                sandbox.enter(roots.clone());
+                let errno = target.apply(&this, &args);
+                sandbox.leave();
+                let errno = errno?;
+                if sandbox.take_escaped() {
+                    return Ok(ENOTCAPABLE.into());
+                }
+                // The directories opened stay in the preopen they were
+                // opened from
+                if is_open && errno.as_f64() == Some(0.0) {
+                    let memory = memory.borrow();
+                    let opened = memory.as_ref().and_then(|memory| {
+                        let ptr = pointer(&args.get(PATH_OPEN_FD))?;
+                        read_u32(memory, ptr)
+                    });
+                    if let (Some(opened), Some(root)) = (opened, roots.into_iter().next()) {
+                        sandbox.track(opened, root);
+                    }
+                }
+                Ok(errno)
+            },
+        )
+            as Box<dyn FnMut(Function, JsValue, Array) -> Result<JsValue, JsValue>>)
+        .into_js_value();
+        let handler = Object::new();
+        Reflect::set(&handler, &"apply".into(), &apply)?;
+        let wrapped = Proxy::new(&original, &handler);
+
+        let confined_namespace = match Reflect::get(&confined, &import.module().into())? {
+            namespace if namespace.is_object() => namespace,
+            _ => {
+                let namespace: JsValue = Object::new().into();
+                Reflect::set(&confined, &import.module().into(), &namespace)?;
+                namespace
+            }
+        };
+        Reflect::set(&confined_namespace, &import.name().into(), &wrapped)?;
+    }
+    Ok(confined)
+}
+
+/// Reads the path whose pointer and length are the arguments at `ptr` and
+/// `len`, or `None` if it isn't in the memory
+fn read_path(memory: &WebAssembly::Memory, args: &Array, ptr: u32, len: u32) -> Option<String> {
+    let ptr = pointer(&args.get(ptr))?;
+    let len = pointer(&args.get(len))?;
+    let bytes = Uint8Array::new(&memory.buffer());
+    let end = ptr.checked_add(len)?;
+    if end > bytes.length() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes.subarray(ptr, end).to_vec()).into_owned())
+}
+
+/// Reads the `u32` at `ptr`, or `None` if it isn't in the memory
+fn read_u32(memory: &WebAssembly::Memory, ptr: u32) -> Option<u32> {
+    let bytes = Uint8Array::new(&memory.buffer());
+    let end = ptr.checked_add(4)?;
+    if end > bytes.length() {
+        return None;
+    }
+    let mut value = [0; 4];
+    bytes.subarray(ptr, end).copy_to(&mut value);
+    Some(u32::from_le_bytes(value))
+}
+
+/// The pointer or length `arg`, which is a signed number for 32-bit WASI and
+/// a bigint for 64-bit WASI
+pub(crate) fn pointer(arg: &JsValue) -> Option<u32> {
+    let value = Number::new(arg).value_of();
+    if value.is_nan() {
+        None
+    } else if value < 0.0 {
+        // `i32` arguments are given as signed numbers
+        Some(value as i32 as u32)
+    } else if value <= u32::MAX as f64 {
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+/// How deep `path` goes below the directory it is relative to, starting
+/// `depth` levels below it, or `None` if it leaves the directory on the way
+fn depth(path: &Path, mut depth: usize) -> Option<usize> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(depth)
+}
@@ -0,0 +1,671 @@
+//! A minimal shell, running command lines made of the commands registered on
+//! it against a shared `MemFS`.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasmer_vfs::FileSystem;
+
+use crate::fs::MemFS;
+use crate::idb;
+use crate::path;
+use crate::process::{Commands, Inherited};
+use crate::stdio::{Encoding, OutputPipe};
+use crate::suspend;
+use crate::wasi::WASI;
+
+/// The exit code of the commands whose redirections or environment fail
+const FAILED: u32 = 1;
+
+/// The exit code of the commands that can't be instantiated or started
+const CANNOT_EXECUTE: u32 = 126;
+
+/// The exit code of the commands that aren't registered
+const COMMAND_NOT_FOUND: u32 = 127;
+
+/// The exit code of the commands that trap, like a process aborted by
+/// `SIGABRT`
+const TRAPPED: u32 = 128 + 6;
+
+#[wasm_bindgen(typescript_custom_section)]
+const SHELL_OPTIONS_TYPE_DEFINITION: &str = r#"
+/** Options used when creating a `Shell`. */
+export type ShellOptions = {
+    /** The filesystem that the commands share, an empty `MemFS` by default. */
+    readonly fs?: MemFS;
+    /** The environment variables of the commands. */
+    readonly env?: Record<string, string>;
+    /** The absolute path of the working directory, `/` by default. */
+    readonly cwd?: string;
+};
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ShellOptions")]
+    pub type ShellOptions;
+}
+
+/// A piece of a command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(Word),
+    Redirect(Redirect),
+    /// `|`
+    Pipe,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `;`
+    Then,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Word {
+    text: String,
+    /// The position of the `=` when the word is an assignment, like `NAME=value`
+    assignment: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Redirect {
+    /// `< file`
+    Stdin,
+    /// `> file` or `>> file`
+    Stdout { append: bool },
+    /// `2> file` or `2>> file`
+    Stderr { append: bool },
+    /// `2>&1`
+    StderrToStdout,
+}
+
+/// How a pipeline follows the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connector {
+    /// Always run, after `;` or at the start of the line
+    Then,
+    /// Run if the previous pipeline succeeded, after `&&`
+    And,
+    /// Run if the previous pipeline failed, after `||`
+    Or,
+}
+
+/// A simple command, like `NAME=value cmd arg > file`
+#[derive(Debug, Default)]
+struct Command {
+    env: Vec<(String, String)>,
+    args: Vec<String>,
+    stdin: Option<String>,
+    /// The file and whether to append to it
+    stdout: Option<(String, bool)>,
+    stderr: Option<(String, bool)>,
+    /// Whether the stderr goes wherever the stdout goes, with `2>&1`
+    merge_stderr: bool,
+}
+
+type Pipeline = Vec<Command>;
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits `line` into tokens, removing the quotes and the escapes
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    // The word being read, if any, and whether part of it was quoted
+    let mut word: Option<Word> = None;
+    let mut quoted = false;
+    let end_word = |word: &mut Option<Word>, quoted: &mut bool, tokens: &mut Vec<Token>| {
+        if let Some(word) = word.take() {
+            tokens.push(Token::Word(word));
+        }
+        *quoted = false;
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => end_word(&mut word, &mut quoted, &mut tokens),
+            '|' | '&' | ';' | '<' | '>' => {
+                // `2>` redirects the stderr, unless the `2` is part of a word
+                let is_stderr =
+                    c == '>' && !quoted && word.as_ref().map_or(false, |word| word.text == "2");
+                if is_stderr {
+                    word = None;
+                } else {
+                    end_word(&mut word, &mut quoted, &mut tokens);
+                }
+                let token = match c {
+                    '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+                    '|' => Token::Pipe,
+                    '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+                    '&' => return Err("running in the background with `&` is not supported".into()),
+                    ';' => Token::Then,
+                    '<' => Token::Redirect(Redirect::Stdin),
+                    _ if is_stderr && chars.next_if_eq(&'&').is_some() => {
+                        if chars.next_if_eq(&'1').is_none() {
+                            return Err("only `2>&1` can duplicate a descriptor".into());
+                        }
+                        Token::Redirect(Redirect::StderrToStdout)
+                    }
+                    _ => {
+                        let append = chars.next_if_eq(&'>').is_some();
+                        Token::Redirect(if is_stderr {
+                            Redirect::Stderr { append }
+                        } else {
+                            Redirect::Stdout { append }
+                        })
+                    }
+                };
+                tokens.push(token);
+                quoted = false;
+            }
+            _ => {
+                let current = word.get_or_insert_with(|| Word {
+                    text: String::new(),
+                    assignment: None,
+                });
+                match c {
+                    '\'' => {
+                        quoted = true;
+                        loop {
+                            match chars.next() {
+                                Some('\'') => break,
+                                Some(c) => current.text.push(c),
+                                None => return Err("unterminated `'`".into()),
+                            }
+                        }
+                    }
+                    '"' => {
+                        quoted = true;
+                        loop {
+                            match chars.next() {
+                                Some('"') => break,
+                                Some('\\') => match chars.next() {
+                                    Some(c @ ('"' | '\\' | '$' | '`')) => current.text.push(c),
+                                    Some(c) => {
+                                        current.text.push('\\');
+                                        current.text.push(c);
+                                    }
+                                    None => return Err("unterminated `\"`".into()),
+                                },
+                                Some(c) => current.text.push(c),
+                                None => return Err("unterminated `\"`".into()),
+                            }
+                        }
+                    }
+                    '\\' => {
+                        quoted = true;
+                        match chars.next() {
+                            Some(c) => current.text.push(c),
+                            None => return Err("nothing to escape after `\\`".into()),
+                        }
+                    }
+                    '=' if !quoted && current.assignment.is_none() && is_name(&current.text) => {
+                        current.assignment = Some(current.text.len());
+                        current.text.push(c);
+                    }
+                    _ => current.text.push(c),
+                }
+            }
+        }
+    }
+    end_word(&mut word, &mut quoted, &mut tokens);
+    Ok(tokens)
+}
+
+/// Groups the `tokens` into the pipelines of a command line
+fn parse(tokens: Vec<Token>) -> Result<Vec<(Connector, Pipeline)>, String> {
+    let mut list = vec![];
+    let mut connector = Connector::Then;
+    let mut pipeline = vec![];
+    let mut command = Command::default();
+    let mut tokens = tokens.into_iter();
+
+    let unexpected = |token: &Token| {
+        let token = match token {
+            Token::Word(word) => word.text.as_str(),
+            Token::Redirect(_) => "redirection",
+            Token::Pipe => "|",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Then => ";",
+        };
+        format!("unexpected `{}`", token)
+    };
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => match word.assignment {
+                Some(equal) if command.args.is_empty() => {
+                    let (name, value) = word.text.split_at(equal);
+                    command.env.push((name.to_string(), value[1..].to_string()));
+                }
+                _ => command.args.push(word.text),
+            },
+            Token::Redirect(Redirect::StderrToStdout) => {
+                command.stderr = None;
+                command.merge_stderr = true;
+            }
+            Token::Redirect(redirect) => {
+                let file = match tokens.next() {
+                    Some(Token::Word(word)) => word.text,
+                    Some(token) => return Err(unexpected(&token)),
+                    None => return Err("missing the file to redirect to".into()),
+                };
+                match redirect {
+                    Redirect::Stdin => command.stdin = Some(file),
+                    // The stderr would keep going to the previous stdout
+                    Redirect::Stdout { .. } if command.merge_stderr => {
+                        return Err("the stdout must be redirected before `2>&1`".into())
+                    }
+                    Redirect::Stdout { append } => command.stdout = Some((file, append)),
+                    Redirect::Stderr { append } => {
+                        command.stderr = Some((file, append));
+                        command.merge_stderr = false;
+                    }
+                    Redirect::StderrToStdout => unreachable!(),
+                }
+            }
+            Token::Pipe | Token::And | Token::Or | Token::Then => {
+                if command.args.is_empty() && command.env.is_empty() {
+                    return Err(unexpected(&token));
+                }
+                pipeline.push(std::mem::take(&mut command));
+                let next = match token {
+                    Token::Pipe => continue,
+                    Token::And => Connector::And,
+                    Token::Or => Connector::Or,
+                    _ => Connector::Then,
+                };
+                list.push((connector, std::mem::take(&mut pipeline)));
+                connector = next;
+            }
+        }
+    }
+    if command.args.is_empty() && command.env.is_empty() {
+        // A line can end with `;`, but not with a pipe or a condition
+        if !pipeline.is_empty() || connector != Connector::Then {
+            return Err("unexpected end of line".into());
+        }
+    } else {
+        pipeline.push(command);
+        list.push((connector, pipeline));
+    }
+    Ok(list)
+}
+
+/// A minimal shell running the commands registered on it, with pipes,
+/// redirections, environment assignments, `&&`, `||`, `;` and quotes
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct Shell {
+    fs: MemFS,
+    commands: Commands,
+    /// The variables of the shell, which the pipelines still running share
+    env: Rc<RefCell<Vec<(String, String)>>>,
+    cwd: String,
+    stdout: OutputPipe,
+    stderr: OutputPipe,
+}
+
+#[wasm_bindgen]
+impl Shell {
+    #[wasm_bindgen(constructor)]
+    pub fn new(options: Option<ShellOptions>) -> Result<Shell, JsValue> {
+        let options: JsValue = options.map(Into::into).unwrap_or(JsValue::UNDEFINED);
+        let get = |key: &str| -> Result<JsValue, JsValue> {
+            if options.is_undefined() {
+                Ok(JsValue::UNDEFINED)
+            } else {
+                js_sys::Reflect::get(&options, &key.into())
+            }
+        };
+        let fs = match get("fs")? {
+            fs if fs.is_undefined() => MemFS::new(None)?,
+            fs => MemFS::from_js(fs)
+                .map_err(|_| js_sys::Error::new("The `fs` of a shell must be a `MemFS`"))?,
+        };
+        let env = match get("env")? {
+            env if env.is_undefined() => vec![],
+            env => {
+                let env: js_sys::Object = env.dyn_into()?;
+                js_sys::Object::entries(&env)
+                    .iter()
+                    .map(|entry| {
+                        let entry: js_sys::Array = entry.unchecked_into();
+                        match (entry.get(0).as_string(), entry.get(1).as_string()) {
+                            (Some(key), Some(value)) => Ok((key, value)),
+                            _ => Err(js_sys::Error::new(
+                                "All environment keys and values must be strings",
+                            )
+                            .into()),
+                        }
+                    })
+                    .collect::<Result<Vec<(String, String)>, JsValue>>()?
+            }
+        };
+        let cwd = match get("cwd")? {
+            cwd if cwd.is_undefined() => "/".to_string(),
+            cwd => match cwd.as_string() {
+                Some(cwd) if cwd.starts_with('/') => {
+                    path::normalize(Path::new("/"), Path::new(&cwd))
+                        .to_string_lossy()
+                        .into_owned()
+                }
+                _ => return Err(js_sys::Error::new("The `cwd` must be an absolute path").into()),
+            },
+        };
+        Ok(Shell {
+            commands: Commands::new(JsValue::from(fs.clone())),
+            fs,
+            env: Rc::new(RefCell::new(env)),
+            cwd,
+            stdout: OutputPipe::default(),
+            stderr: OutputPipe::default(),
+        })
+    }
+
+    /// The filesystem that the commands share
+    #[wasm_bindgen(getter)]
+    pub fn fs(&self) -> MemFS {
+        self.fs.clone()
+    }
+
+    /// Make `module` available as the command `name`, to the command lines
+    /// and to the processes that commands spawn
+    #[wasm_bindgen(js_name = registerCommand)]
+    pub fn register_command(&mut self, name: String, module: js_sys::WebAssembly::Module) {
+        self.commands.register(name, module);
+    }
+
+    /// Run the command `line`, and return a promise of the exit code of the
+    /// last pipeline that ran. The output that isn't redirected is collected
+    /// by the shell, and so are its errors, like `sh: cmd: command not found`.
+    /// A command that fails to start exits with 1, 126 or 127, and one that
+    /// traps with 134, and the line goes on.
+    /// Note: the commands of a pipeline stream to each other with JSPI, see
+    /// `pipeline`. A syntax error throws before any command runs.
+    pub fn run(&mut self, line: &str) -> Result<js_sys::Promise, JsValue> {
+        let list = tokenize(line)
+            .and_then(parse)
+            .map_err(|e| js_sys::Error::new(&format!("Syntax error: {}", e)))?;
+        self.clone().run_list(list.into_iter(), 0)
+    }
+
+    /// Get the stdout data of the commands as a string, decoded with
+    /// `encoding` (`'utf-8'` by default, `'utf-8-lossy'` or `'latin1'`)
+    /// Note: this method flushes the stdout
+    #[wasm_bindgen(js_name = getStdoutString)]
+    pub fn get_stdout_string(&mut self, encoding: Option<String>) -> Result<String, JsValue> {
+        let encoding = Encoding::from_js(encoding)?;
+        self.stdout.read_string(encoding).map_err(|e| {
+            js_sys::Error::new(&format!(
+                "Could not convert the stdout bytes to a String: {}`",
+                e
+            ))
+            .into()
+        })
+    }
+
+    /// Get the stderr data of the commands and of the shell as a string,
+    /// decoded with `encoding` (`'utf-8'` by default, `'utf-8-lossy'` or
+    /// `'latin1'`)
+    /// Note: this method flushes the stderr
+    #[wasm_bindgen(js_name = getStderrString)]
+    pub fn get_stderr_string(&mut self, encoding: Option<String>) -> Result<String, JsValue> {
+        let encoding = Encoding::from_js(encoding)?;
+        self.stderr.read_string(encoding).map_err(|e| {
+            js_sys::Error::new(&format!(
+                "Could not convert the stderr bytes to a String: {}`",
+                e
+            ))
+            .into()
+        })
+    }
+}
+
+/// How a command of a pipeline started
+enum Stage {
+    Started(WASI),
+    /// Assignments alone do nothing in a pipeline
+    Nothing,
+    /// The command couldn't start, with its exit code
+    Failed(u32),
+}
+
+impl Shell {
+    /// Runs the pipelines of `list` that their connectors let run, once the
+    /// previous one exited with `status`, and returns a promise of the exit
+    /// code of the last that ran
+    fn run_list(
+        self,
+        mut list: std::vec::IntoIter<(Connector, Pipeline)>,
+        status: u32,
+    ) -> Result<js_sys::Promise, JsValue> {
+        while let Some((connector, pipeline)) = list.next() {
+            let run = match connector {
+                Connector::Then => true,
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                let pipeline = self.run_pipeline(pipeline)?;
+                return idb::then(&pipeline, move |status| {
+                    let status = status.as_f64().map_or(0, |status| status as u32);
+                    Ok(self.run_list(list, status)?.into())
+                });
+            }
+        }
+        Ok(js_sys::Promise::resolve(&status.into()))
+    }
+
+    /// Starts the commands of `pipeline` together, each reading what the
+    /// previous one writes, and returns a promise of the exit code of the
+    /// last
+    fn run_pipeline(&self, pipeline: Pipeline) -> Result<js_sys::Promise, JsValue> {
+        // Assignments alone set the variables of the shell
+        if let [command] = &pipeline[..] {
+            if command.args.is_empty() {
+                let mut env = self.env.borrow_mut();
+                for (key, value) in &command.env {
+                    env.retain(|(existing, _)| existing != key);
+                    env.push((key.clone(), value.clone()));
+                }
+                return Ok(js_sys::Promise::resolve(&0.into()));
+            }
+        }
+
+        let mut stages = vec![];
+        for command in &pipeline {
+            stages.push(self.spawn(command)?);
+        }
+        for i in 1..stages.len() {
+            // Like other shells, the redirections win over the pipe
+            if pipeline[i - 1].stdout.is_some() || pipeline[i].stdin.is_some() {
+                continue;
+            }
+            let (previous, next) = stages.split_at_mut(i);
+            if let (Stage::Started(previous), Stage::Started(next)) =
+                (&mut previous[i - 1], &mut next[0])
+            {
+                previous.pipe_to(next)?;
+            }
+        }
+
+        let last = stages.len() - 1;
+        let codes = js_sys::Array::new();
+        for (i, (stage, command)) in stages.into_iter().zip(pipeline).enumerate() {
+            let mut wasi = match stage {
+                Stage::Started(wasi) => wasi,
+                Stage::Nothing => {
+                    codes.push(&js_sys::Promise::resolve(&0.into()));
+                    continue;
+                }
+                Stage::Failed(code) => {
+                    codes.push(&js_sys::Promise::resolve(&code.into()));
+                    continue;
+                }
+            };
+            let name = command.args[0].clone();
+            let exited = match wasi.start_async(None) {
+                Ok(exited) => exited,
+                Err(error) => {
+                    self.report(&name, &error)?;
+                    codes.push(&js_sys::Promise::resolve(&CANNOT_EXECUTE.into()));
+                    continue;
+                }
+            };
+            let shell = self.clone();
+            // The instance lives until its guest exits
+            let code = suspend::settle(&exited, move |code| {
+                if i == last {
+                    shell.write_stdout(&wasi.stdout_pipe().read_up_to(usize::MAX))?;
+                }
+                shell.write_stderr(&wasi.stderr_pipe().read_up_to(usize::MAX))?;
+                match code {
+                    Ok(code) => Ok(code),
+                    // Like a crashed process, the rest of the line goes on
+                    Err(error) => {
+                        shell.report(&name, &error)?;
+                        Ok(TRAPPED.into())
+                    }
+                }
+            })?;
+            codes.push(&code);
+        }
+        idb::then(&js_sys::Promise::all(&codes), |codes| {
+            Ok(js_sys::Array::from(&codes).pop())
+        })
+    }
+
+    /// An instance ready to run `command`, or how it failed to start, in
+    /// which case the error is written to the stderr of the shell
+    fn spawn(&self, command: &Command) -> Result<Stage, JsValue> {
+        let name = match command.args.first() {
+            Some(name) => name,
+            None => return Ok(Stage::Nothing),
+        };
+        let module = match self.commands.get(name) {
+            Some((module, _)) => module,
+            None => {
+                self.report(name, &"command not found".into())?;
+                return Ok(Stage::Failed(COMMAND_NOT_FOUND));
+            }
+        };
+        let mut wasi = match self.wasi(command) {
+            Ok(wasi) => wasi,
+            Err(error) => {
+                self.report(name, &error)?;
+                return Ok(Stage::Failed(FAILED));
+            }
+        };
+        if let Err(error) = wasi.instantiate(module.into(), None) {
+            self.report(name, &error)?;
+            return Ok(Stage::Failed(CANNOT_EXECUTE));
+        }
+        Ok(Stage::Started(wasi))
+    }
+
+    /// An instance with the arguments, environment and redirections of
+    /// `command`
+    fn wasi(&self, command: &Command) -> Result<WASI, JsValue> {
+        let args = command
+            .args
+            .iter()
+            .map(JsValue::from)
+            .collect::<js_sys::Array>();
+        let env = js_sys::Object::new();
+        for (key, value) in self.env.borrow().iter().chain(&command.env) {
+            js_sys::Reflect::set(&env, &key.into(), &value.into())?;
+        }
+        let file = |path: &str, append: bool| -> Result<js_sys::Object, JsValue> {
+            let path = path::normalize(Path::new(&self.cwd), Path::new(path));
+            let file = js_sys::Object::new();
+            js_sys::Reflect::set(
+                &file,
+                &"file".into(),
+                &JsValue::from(path.to_string_lossy().into_owned()),
+            )?;
+            js_sys::Reflect::set(&file, &"append".into(), &append.into())?;
+            Ok(file)
+        };
+
+        let config = js_sys::Object::new();
+        js_sys::Reflect::set(&config, &"args".into(), &args)?;
+        js_sys::Reflect::set(&config, &"env".into(), &env)?;
+        js_sys::Reflect::set(&config, &"fs".into(), &JsValue::from(self.fs.clone()))?;
+        js_sys::Reflect::set(&config, &"cwd".into(), &self.cwd.as_str().into())?;
+        if let Some(path) = &command.stdin {
+            js_sys::Reflect::set(&config, &"stdin".into(), &file(path, false)?)?;
+        }
+        match (&command.stdout, command.merge_stderr) {
+            // Both streams write at the end of the file, as they would
+            // through the same descriptor
+            (Some((path, append)), true) => {
+                if !*append {
+                    self.truncate(path)?;
+                }
+                js_sys::Reflect::set(&config, &"stdout".into(), &file(path, true)?)?;
+                js_sys::Reflect::set(&config, &"stderr".into(), &file(path, true)?)?;
+            }
+            (Some((path, append)), false) => {
+                js_sys::Reflect::set(&config, &"stdout".into(), &file(path, *append)?)?;
+            }
+            (None, true) => {
+                js_sys::Reflect::set(&config, &"mergeStderr".into(), &true.into())?;
+            }
+            (None, false) => {}
+        }
+        if let Some((path, append)) = &command.stderr {
+            js_sys::Reflect::set(&config, &"stderr".into(), &file(path, *append)?)?;
+        }
+
+        WASI::with_commands(
+            config.unchecked_into(),
+            Some(self.commands.clone()),
+            Inherited::default(),
+        )
+    }
+
+    /// Creates the file at `path`, or empties it
+    fn truncate(&self, path: &str) -> Result<(), JsValue> {
+        let path = path::normalize(Path::new(&self.cwd), Path::new(path));
+        let mut open_options = self.fs.new_open_options();
+        open_options.write(true).create(true).truncate(true);
+        open_options.open(&path).map_err(|e| {
+            js_sys::Error::new(&format!("Couldn't open `{}`: {}`", path.display(), e))
+        })?;
+        Ok(())
+    }
+
+    fn write_stdout(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.stdout
+            .clone()
+            .write_all(data)
+            .map_err(|e| js_sys::Error::new(&format!("Error writing stdout: {}`", e)).into())
+    }
+
+    fn write_stderr(&self, data: &[u8]) -> Result<(), JsValue> {
+        self.stderr
+            .clone()
+            .write_all(data)
+            .map_err(|e| js_sys::Error::new(&format!("Error writing stderr: {}`", e)).into())
+    }
+
+    /// Writes `error`, which the command `name` failed with, to the stderr
+    fn report(&self, name: &str, error: &JsValue) -> Result<(), JsValue> {
+        let error = error
+            .dyn_ref::<js_sys::Error>()
+            .map(|error| String::from(error.message()))
+            .or_else(|| error.as_string())
+            .unwrap_or_else(|| "failed".to_string());
+        self.write_stderr(format!("sh: {}: {}\n", name, error).as_bytes())
+    }
+}
@@ -0,0 +1,158 @@
+//! The binary format of `MemFS` snapshots.
+//!
+//! A snapshot starts with the `WMFS` magic and a version byte, followed by the
+//! number of nodes and the nodes themselves, parents before their children.
+//! All the integers are little endian, and strings and byte arrays are
+//! prefixed with their length as a `u32`.
+
+use std::sync::Arc;
+
+/// The first bytes of every snapshot
+const MAGIC: &[u8; 4] = b"WMFS";
+
+/// The version of the format written by `Snapshot::encode`
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    pub(crate) nodes: Vec<SnapshotNode>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotNode {
+    pub(crate) path: String,
+    pub(crate) kind: NodeKind,
+    /// The `(mode, uid, gid)` of the node, if they were changed from the
+    /// defaults
+    pub(crate) permissions: Option<(u32, u32, u32)>,
+    pub(crate) accessed: u64,
+    pub(crate) modified: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum NodeKind {
+    Dir,
+    File(Arc<Vec<u8>>),
+    Symlink(String),
+    /// A hard link to the file stored at the given path
+    HardLink(String),
+}
+
+impl Snapshot {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_u32(&mut out, self.nodes.len() as u32);
+        for node in &self.nodes {
+            let (tag, payload): (u8, &[u8]) = match &node.kind {
+                NodeKind::Dir => (0, &[]),
+                NodeKind::File(contents) => (1, contents.as_slice()),
+                NodeKind::Symlink(target) => (2, target.as_bytes()),
+                NodeKind::HardLink(target) => (3, target.as_bytes()),
+            };
+            out.push(tag);
+            write_bytes(&mut out, node.path.as_bytes());
+            write_bytes(&mut out, payload);
+            match node.permissions {
+                Some((mode, uid, gid)) => {
+                    out.push(1);
+                    write_u32(&mut out, mode);
+                    write_u32(&mut out, uid);
+                    write_u32(&mut out, gid);
+                }
+                None => out.push(0),
+            }
+            out.extend_from_slice(&node.accessed.to_le_bytes());
+            out.extend_from_slice(&node.modified.to_le_bytes());
+        }
+        out
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("the data is not a MemFS snapshot".to_string());
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+        let count = reader.u32()?;
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            let tag = reader.take(1)?[0];
+            let path = reader.string()?;
+            let payload = reader.bytes()?;
+            let kind = match tag {
+                0 => NodeKind::Dir,
+                1 => NodeKind::File(Arc::new(payload.to_vec())),
+                2 => NodeKind::Symlink(utf8(payload)?),
+                3 => NodeKind::HardLink(utf8(payload)?),
+                _ => return Err(format!("unknown node type {}", tag)),
+            };
+            let permissions = match reader.take(1)?[0] {
+                0 => None,
+                _ => Some((reader.u32()?, reader.u32()?, reader.u32()?)),
+            };
+            nodes.push(SnapshotNode {
+                path,
+                kind,
+                permissions,
+                accessed: reader.u64()?,
+                modified: reader.u64()?,
+            });
+        }
+        if !reader.bytes.is_empty() {
+            return Err("unexpected data after the last node".to_string());
+        }
+        Ok(Snapshot { nodes })
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("the snapshot is truncated".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        // Safe to unwrap, `take` returns exactly 4 bytes
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        // Safe to unwrap, `take` returns exactly 8 bytes
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let bytes = self.bytes()?;
+        utf8(bytes)
+    }
+}
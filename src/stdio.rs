@@ -0,0 +1,505 @@
+use crate::streams::Chunk;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use wasmer_vfs::{FsError, VirtualFile};
+use wasmer_wasi::Pipe;
+
+/// The output streams of a WASI instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// The encodings that stdio data can be decoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Utf8,
+    Utf8Lossy,
+    Latin1,
+}
+
+impl Encoding {
+    pub(crate) fn from_js(encoding: Option<String>) -> Result<Self, JsValue> {
+        match encoding.as_deref() {
+            None | Some("utf-8") => Ok(Encoding::Utf8),
+            Some("utf-8-lossy") => Ok(Encoding::Utf8Lossy),
+            Some("latin1") => Ok(Encoding::Latin1),
+            Some(other) => Err(js_sys::Error::new(&format!(
+                "Unknown encoding `{}`, expected 'utf-8', 'utf-8-lossy' or 'latin1'",
+                other
+            ))
+            .into()),
+        }
+    }
+
+    /// Decodes `bytes`, leaving out an incomplete UTF-8 sequence at the end
+    /// so it can be decoded once the rest of it arrives.
+    /// Returns the string and the number of bytes that were decoded.
+    fn decode(&self, bytes: &[u8]) -> Result<(String, usize), String> {
+        match self {
+            Encoding::Latin1 => Ok((bytes.iter().map(|&b| b as char).collect(), bytes.len())),
+            Encoding::Utf8 => match std::str::from_utf8(bytes) {
+                Ok(s) => Ok((s.to_string(), bytes.len())),
+                Err(e) if e.error_len().is_none() => {
+                    let len = e.valid_up_to();
+                    // Safe to unwrap, the bytes up to `valid_up_to` are valid UTF-8
+                    Ok((std::str::from_utf8(&bytes[..len]).unwrap().to_string(), len))
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            Encoding::Utf8Lossy => {
+                let mut len = 0;
+                while let Err(e) = std::str::from_utf8(&bytes[len..]) {
+                    match e.error_len() {
+                        Some(invalid) => len += e.valid_up_to() + invalid,
+                        None => {
+                            let len = len + e.valid_up_to();
+                            return Ok((String::from_utf8_lossy(&bytes[..len]).into_owned(), len));
+                        }
+                    }
+                }
+                Ok((String::from_utf8_lossy(bytes).into_owned(), bytes.len()))
+            }
+        }
+    }
+}
+
+/// A pipe for the output of a WASI instance, which can be inspected without
+/// consuming it.
+///
+/// Every byte written to the pipe has an offset, counting from the first
+/// byte ever written. The consuming reads take the bytes in order, but the
+/// bytes are kept until the cursors following the output have read them too,
+/// so several readers can follow the output without taking it from the
+/// others.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OutputPipe {
+    inner: Arc<Mutex<OutputPipeInner>>,
+}
+
+#[derive(Debug, Default)]
+struct OutputPipeInner {
+    buffer: VecDeque<u8>,
+    /// The offset of the first byte in `buffer`
+    offset: u64,
+    /// The offset of the first byte that wasn't consumed
+    consumed: u64,
+    /// The offsets of the live cursors, by id
+    cursors: HashMap<u32, u64>,
+    next_cursor: u32,
+    /// Whether the WASI instance finished writing to the pipe
+    closed: bool,
+    /// Where the data goes instead of the consuming reads, when the output is
+    /// piped to another instance
+    forward: Option<Pipe>,
+}
+
+impl OutputPipeInner {
+    /// The offset right after the last byte written
+    fn end(&self) -> u64 {
+        self.offset + self.buffer.len() as u64
+    }
+
+    /// The bytes from `offset` to the end
+    fn bytes_from(&self, offset: u64) -> Vec<u8> {
+        self.buffer
+            .iter()
+            .skip((offset - self.offset) as usize)
+            .copied()
+            .collect()
+    }
+
+    /// Consumes `len` bytes, returning them
+    fn consume(&mut self, len: usize) -> Vec<u8> {
+        let start = (self.consumed - self.offset) as usize;
+        let data = self.buffer.range(start..start + len).copied().collect();
+        self.consumed += len as u64;
+        self.trim();
+        data
+    }
+
+    /// Drops the bytes that were consumed and that every cursor read
+    fn trim(&mut self) {
+        let keep = self.cursors.values().copied().fold(self.consumed, u64::min);
+        self.buffer.drain(..(keep - self.offset) as usize);
+        self.offset = keep;
+    }
+}
+
+impl OutputPipe {
+    /// Returns the bytes that weren't consumed yet without consuming them
+    pub(crate) fn peek(&self) -> Vec<u8> {
+        let inner = self.inner.lock().unwrap();
+        inner.bytes_from(inner.consumed)
+    }
+
+    /// Consumes up to `max_bytes` of the buffered bytes
+    pub(crate) fn read_up_to(&self, max_bytes: usize) -> Vec<u8> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = max_bytes.min((inner.end() - inner.consumed) as usize);
+        inner.consume(len)
+    }
+
+    /// Returns the bytes starting at `offset` without consuming them, as
+    /// long as they are kept. The bytes are gone once they are consumed and
+    /// every cursor read them.
+    pub(crate) fn read_from(&self, offset: u64) -> Result<Vec<u8>, String> {
+        let inner = self.inner.lock().unwrap();
+        let end = inner.end();
+        if offset < inner.offset || offset > end {
+            return Err(format!(
+                "The offset {} is outside of the buffered output ({}..{}), \
+                 the bytes before {} were consumed",
+                offset, inner.offset, end, inner.offset
+            ));
+        }
+        Ok(inner.bytes_from(offset))
+    }
+
+    /// Consumes the buffered bytes decoded as a string. A trailing incomplete
+    /// UTF-8 sequence is kept in the pipe, and nothing is consumed on errors.
+    pub(crate) fn read_string(&self, encoding: Encoding) -> Result<String, String> {
+        let mut inner = self.inner.lock().unwrap();
+        let start = (inner.consumed - inner.offset) as usize;
+        let (string, len) = encoding.decode(&inner.buffer.make_contiguous()[start..])?;
+        inner.consume(len);
+        Ok(string)
+    }
+
+    /// Marks the pipe as finished, so readers know no more data is coming
+    pub(crate) fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+    }
+
+    /// Lets the WASI instance write to the pipe again, when it starts again
+    pub(crate) fn reopen(&self) {
+        self.inner.lock().unwrap().closed = false;
+    }
+
+    /// Consumes the buffered bytes for a readable stream
+    pub(crate) fn read_chunk(&self) -> Chunk {
+        let data = self.read_up_to(usize::MAX);
+        if !data.is_empty() {
+            Chunk::Data(data)
+        } else if self.inner.lock().unwrap().closed {
+            Chunk::Done
+        } else {
+            Chunk::Pending
+        }
+    }
+
+    /// Sends the bytes that weren't consumed and everything written from now
+    /// on to `pipe`
+    pub(crate) fn forward_to(&self, mut pipe: Pipe) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = (inner.end() - inner.consumed) as usize;
+        pipe.write_all(&inner.consume(len))?;
+        inner.forward = Some(pipe);
+        Ok(())
+    }
+
+    /// The offset right after the last byte written to the pipe
+    pub(crate) fn end_offset(&self) -> u64 {
+        self.inner.lock().unwrap().end()
+    }
+
+    /// A cursor following the output from the first byte that wasn't
+    /// consumed
+    pub(crate) fn cursor(&self) -> StdioCursor {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_cursor;
+        inner.next_cursor += 1;
+        let offset = inner.consumed;
+        inner.cursors.insert(id, offset);
+        StdioCursor {
+            pipe: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Follows the output of a WASI instance, without consuming it from the
+/// other readers. The output is kept until every cursor read it, so cursors
+/// that are no longer used must be freed.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct StdioCursor {
+    pipe: OutputPipe,
+    id: u32,
+}
+
+#[wasm_bindgen]
+impl StdioCursor {
+    /// Get the bytes written since the last read, and move past them
+    pub fn read(&mut self) -> Vec<u8> {
+        let mut inner = self.pipe.inner.lock().unwrap();
+        let offset = inner.cursors[&self.id];
+        let data = inner.bytes_from(offset);
+        let end = inner.end();
+        inner.cursors.insert(self.id, end);
+        inner.trim();
+        data
+    }
+
+    /// The offset of the next byte the cursor reads
+    #[wasm_bindgen(getter)]
+    pub fn offset(&self) -> u64 {
+        self.pipe.inner.lock().unwrap().cursors[&self.id]
+    }
+}
+
+impl Drop for StdioCursor {
+    fn drop(&mut self) {
+        let mut inner = self.pipe.inner.lock().unwrap();
+        inner.cursors.remove(&self.id);
+        inner.trim();
+    }
+}
+
+impl Read for OutputPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.read_up_to(buf.len());
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl Write for OutputPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut *self.inner.lock().unwrap();
+        let written = match &mut inner.forward {
+            Some(pipe) => pipe.write(buf)?,
+            None => buf.len(),
+        };
+        inner.buffer.extend(&buf[..written]);
+        if inner.forward.is_some() {
+            // The cursors still see the forwarded bytes
+            inner.consumed = inner.end();
+            inner.trim();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for OutputPipe {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "can not seek in a pipe",
+        ))
+    }
+}
+
+impl VirtualFile for OutputPipe {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+    fn last_modified(&self) -> u64 {
+        0
+    }
+    fn created_time(&self) -> u64 {
+        0
+    }
+    fn size(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.end() - inner.consumed
+    }
+    fn set_len(&mut self, new_size: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = (inner.consumed - inner.offset + new_size) as usize;
+        inner.buffer.resize(len, 0);
+        let end = inner.end();
+        for offset in inner.cursors.values_mut() {
+            *offset = (*offset).min(end);
+        }
+        Ok(())
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        Ok(self.size() as usize)
+    }
+}
+
+/// The stdout and stderr writes of a WASI instance, in the order they
+/// happened
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OutputLog {
+    entries: Arc<Mutex<Vec<(OutputStream, Vec<u8>)>>>,
+}
+
+impl OutputLog {
+    fn push(&self, stream: OutputStream, data: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.last_mut() {
+            // Consecutive writes to the same stream are merged together
+            Some((last_stream, last_data)) if *last_stream == stream => {
+                last_data.extend_from_slice(data)
+            }
+            _ => entries.push((stream, data.to_vec())),
+        }
+    }
+
+    /// Drains the log into an array of `{stream, data}` objects
+    pub(crate) fn take(&self) -> Result<js_sys::Array, JsValue> {
+        let entries = std::mem::take(&mut *self.entries.lock().unwrap());
+        entries
+            .into_iter()
+            .map(|(stream, data)| {
+                let entry = js_sys::Object::new();
+                js_sys::Reflect::set(&entry, &"stream".into(), &stream.as_str().into())?;
+                js_sys::Reflect::set(
+                    &entry,
+                    &"data".into(),
+                    &js_sys::Uint8Array::from(&data[..]).into(),
+                )?;
+                Ok(entry)
+            })
+            .collect()
+    }
+}
+
+/// An output handle that records everything written to it in an `OutputLog`
+#[derive(Debug)]
+pub(crate) struct LoggedOutput {
+    stream: OutputStream,
+    inner: Box<dyn VirtualFile + Send + Sync>,
+    log: OutputLog,
+}
+
+impl LoggedOutput {
+    pub(crate) fn new(
+        stream: OutputStream,
+        inner: Box<dyn VirtualFile + Send + Sync>,
+        log: OutputLog,
+    ) -> Self {
+        LoggedOutput { stream, inner, log }
+    }
+}
+
+impl Read for LoggedOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for LoggedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.log.push(self.stream, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for LoggedOutput {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl VirtualFile for LoggedOutput {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+    fn set_len(&mut self, new_size: u64) -> Result<(), FsError> {
+        self.inner.set_len(new_size)
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        self.inner.unlink()
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        self.inner.bytes_available()
+    }
+}
+
+/// A stdio handle that a guest shares with the processes inheriting it, so
+/// that they all read and write through the same file, log and pipe, in order
+#[derive(Debug, Clone)]
+pub(crate) struct SharedStdio {
+    inner: Arc<Mutex<Box<dyn VirtualFile + Send + Sync>>>,
+}
+
+impl SharedStdio {
+    pub(crate) fn new(inner: Box<dyn VirtualFile + Send + Sync>) -> Self {
+        SharedStdio {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+}
+
+impl Read for SharedStdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for SharedStdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+impl Seek for SharedStdio {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.lock().unwrap().seek(pos)
+    }
+}
+
+impl VirtualFile for SharedStdio {
+    fn last_accessed(&self) -> u64 {
+        self.inner.lock().unwrap().last_accessed()
+    }
+    fn last_modified(&self) -> u64 {
+        self.inner.lock().unwrap().last_modified()
+    }
+    fn created_time(&self) -> u64 {
+        self.inner.lock().unwrap().created_time()
+    }
+    fn size(&self) -> u64 {
+        self.inner.lock().unwrap().size()
+    }
+    fn set_len(&mut self, new_size: u64) -> Result<(), FsError> {
+        self.inner.lock().unwrap().set_len(new_size)
+    }
+    fn unlink(&mut self) -> Result<(), FsError> {
+        self.inner.lock().unwrap().unlink()
+    }
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        self.inner.lock().unwrap().bytes_available()
+    }
+}
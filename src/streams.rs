@@ -0,0 +1,106 @@
+use js_sys::{Function, Object, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// The size of the chunks read from files by readable streams
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a readable stream waits before checking for new data again
+const POLL_INTERVAL_MS: i32 = 10;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ReadableStream<Uint8Array>")]
+    pub type ReadableStream;
+
+    #[wasm_bindgen(typescript_type = "WritableStream<Uint8Array>")]
+    pub type WritableStream;
+}
+
+/// The result of pulling data for a readable stream
+pub(crate) enum Chunk {
+    Data(Vec<u8>),
+    /// There is no data yet, but there may be later
+    Pending,
+    /// There won't be any more data
+    Done,
+}
+
+/// Creates a `ReadableStream` that gets its chunks from `pull`
+pub(crate) fn readable_stream(
+    mut pull: impl FnMut() -> Result<Chunk, JsValue> + 'static,
+) -> Result<ReadableStream, JsValue> {
+    let source = Object::new();
+    let pull = Closure::wrap(Box::new(move |controller: JsValue| -> JsValue {
+        let result = match pull() {
+            Ok(Chunk::Data(data)) => call_method(
+                &controller,
+                "enqueue",
+                &[Uint8Array::from(&data[..]).into()],
+            ),
+            Ok(Chunk::Pending) => Ok(sleep(POLL_INTERVAL_MS).into()),
+            Ok(Chunk::Done) => call_method(&controller, "close", &[]),
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| wasm_bindgen::throw_val(e))
+    }) as Box<dyn FnMut(JsValue) -> JsValue>);
+    Reflect::set(&source, &"pull".into(), &pull.into_js_value())?;
+    Ok(construct("ReadableStream", &source)?.unchecked_into())
+}
+
+/// Creates a `WritableStream` that passes its chunks to `write`, and calls
+/// `close` once the stream is closed
+pub(crate) fn writable_stream(
+    mut write: impl FnMut(&[u8]) -> Result<(), JsValue> + 'static,
+    mut close: impl FnMut() -> Result<(), JsValue> + 'static,
+) -> Result<WritableStream, JsValue> {
+    let sink = Object::new();
+    let write = Closure::wrap(Box::new(move |chunk: JsValue| {
+        if let Err(e) = write(&Uint8Array::new(&chunk).to_vec()) {
+            wasm_bindgen::throw_val(e)
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+    let close = Closure::wrap(Box::new(move || {
+        if let Err(e) = close() {
+            wasm_bindgen::throw_val(e)
+        }
+    }) as Box<dyn FnMut()>);
+    Reflect::set(&sink, &"write".into(), &write.into_js_value())?;
+    Reflect::set(&sink, &"close".into(), &close.into_js_value())?;
+    Ok(construct("WritableStream", &sink)?.unchecked_into())
+}
+
+/// Calls `new name(arg)` on a global constructor, like `ReadableStream`
+fn construct(name: &str, arg: &JsValue) -> Result<JsValue, JsValue> {
+    let constructor: Function = Reflect::get(&js_sys::global(), &name.into())?
+        .dyn_into()
+        .map_err(|_| {
+            js_sys::Error::new(&format!(
+                "`{}` is not available in this environment, it must be made global first \
+                 (in Node.js before 18, from the `stream/web` module)",
+                name
+            ))
+        })?;
+    Reflect::construct(&constructor, &js_sys::Array::of1(arg))
+}
+
+pub(crate) fn call_method(
+    object: &JsValue,
+    name: &str,
+    args: &[JsValue],
+) -> Result<JsValue, JsValue> {
+    let method: Function = Reflect::get(object, &name.into())?.dyn_into()?;
+    method.apply(object, &args.iter().collect())
+}
+
+/// A promise resolved after `ms` milliseconds
+fn sleep(ms: i32) -> js_sys::Promise {
+    js_sys::Promise::new(&mut |resolve, reject| {
+        let result = Reflect::get(&js_sys::global(), &"setTimeout".into())
+            .and_then(|set_timeout| set_timeout.dyn_into::<Function>())
+            .and_then(|set_timeout| set_timeout.call2(&JsValue::UNDEFINED, &resolve, &ms.into()));
+        if let Err(e) = result {
+            let _ = reject.call1(&JsValue::UNDEFINED, &e);
+        }
+    })
+}
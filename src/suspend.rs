@@ -0,0 +1,368 @@
+//! Lets the guests started with `startAsync` wait for the other guests, by
+//! importing the WASI functions that can wait as `WebAssembly.Suspending`
+//! functions (JSPI). A guest waits:
+//!
+//! - in `fd_read`, while its stdin is empty and the stage piped into it is
+//!   still running,
+//! - in `fd_write`, while the stage its stdout is piped to has
+//!   `PIPE_CAPACITY` bytes left to read,
+//! - in `sock_accept` on a blocking listener of a `Network`, until a guest
+//!   connects.
+//!
+//! The other guests run meanwhile, so the stages of a pipeline stream their
+//! output to each other. A guest started with `start`, or on an engine
+//! without JSPI, never waits: it reads the end of an empty stdin instead, and
+//! a blocking `sock_accept` without connections fails with `EDEADLK`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use js_sys::{Array, Function, Object, Promise, Proxy, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasmer::Module;
+use wasmer_vfs::VirtualFile;
+use wasmer_wasi::{Pipe, WasiState};
+
+use crate::idb;
+use crate::loopback::Network;
+use crate::sandbox::{self, NAMESPACES, NAMESPACES_64};
+use crate::streams::call_method;
+
+/// The bytes a stage writes to the next before waiting for it to read them
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+const EAGAIN: u32 = 6;
+/// The errno returned for the blocking accepts that can't wait
+const EDEADLK: u32 = 16;
+/// The errno returned for the writes to a stage that exited
+const EPIPE: u32 = 64;
+
+/// The fd flag of the sockets that don't block
+const FDFLAGS_NONBLOCK: u16 = 1 << 2;
+
+const STDIN: u32 = 0;
+const STDOUT: u32 = 1;
+
+/// What the guest of a `WASI` instance can wait on
+#[derive(Debug, Default)]
+pub(crate) struct Waits {
+    /// The pipe into the stdin, from the previous stage of a pipeline
+    stdin: Option<Link>,
+    /// The pipe from the stdout, to the next stage
+    stdout: Option<Link>,
+    /// The network whose connections `sock_accept` waits for
+    network: Option<Network>,
+    /// Whether the guest runs with `startAsync`, so it can wait
+    suspendable: bool,
+}
+
+pub(crate) type SharedWaits = Rc<RefCell<Waits>>;
+
+impl Waits {
+    pub(crate) fn shared(network: Option<Network>) -> SharedWaits {
+        Rc::new(RefCell::new(Waits {
+            network,
+            ..Waits::default()
+        }))
+    }
+}
+
+/// A pipe from the stdout of a stage to the stdin of the next
+#[derive(Debug, Clone)]
+struct Link {
+    inner: Rc<RefCell<LinkState>>,
+}
+
+#[derive(Debug)]
+struct LinkState {
+    /// The stdin of the next stage, which the stdout is forwarded to
+    stdin: Pipe,
+    /// Whether the stages on either end are running
+    writing: bool,
+    reading: bool,
+    /// The resolve functions of the stages waiting on the pipe
+    waiters: Vec<Function>,
+}
+
+impl Link {
+    fn buffered(&self) -> usize {
+        self.inner.borrow().stdin.bytes_available().unwrap_or(0)
+    }
+
+    fn writing(&self) -> bool {
+        self.inner.borrow().writing
+    }
+
+    fn reading(&self) -> bool {
+        self.inner.borrow().reading
+    }
+
+    /// A promise resolved once either end reads, writes or exits
+    fn changed(&self) -> Promise {
+        Promise::new(&mut |resolve, _reject| self.inner.borrow_mut().waiters.push(resolve))
+    }
+
+    fn wake(&self) {
+        let waiters = std::mem::take(&mut self.inner.borrow_mut().waiters);
+        for resolve in waiters {
+            let _ = resolve.call0(&JsValue::UNDEFINED);
+        }
+    }
+}
+
+/// Records that the stdout of `writer` is piped to `stdin`, the stdin of
+/// `reader`
+pub(crate) fn link(writer: &SharedWaits, reader: &SharedWaits, stdin: Pipe) {
+    let link = Link {
+        inner: Rc::new(RefCell::new(LinkState {
+            stdin,
+            writing: true,
+            reading: true,
+            waiters: vec![],
+        })),
+    };
+    writer.borrow_mut().stdout = Some(link.clone());
+    reader.borrow_mut().stdin = Some(link);
+}
+
+/// Records that the guest started, with `startAsync` when `suspendable`
+pub(crate) fn started(waits: &SharedWaits, suspendable: bool) {
+    let mut waits = waits.borrow_mut();
+    waits.suspendable = suspendable;
+    if let Some(link) = &waits.stdin {
+        link.inner.borrow_mut().reading = true;
+    }
+    if let Some(link) = &waits.stdout {
+        link.inner.borrow_mut().writing = true;
+    }
+}
+
+/// Records that the guest exited, waking the stages waiting on its pipes
+pub(crate) fn exited(waits: &SharedWaits) {
+    let (stdin, stdout) = {
+        let mut waits = waits.borrow_mut();
+        waits.suspendable = false;
+        (waits.stdin.clone(), waits.stdout.clone())
+    };
+    if let Some(link) = stdin {
+        link.inner.borrow_mut().reading = false;
+        link.wake();
+    }
+    if let Some(link) = stdout {
+        link.inner.borrow_mut().writing = false;
+        link.wake();
+    }
+}
+
+/// `WebAssembly[name]`, if the engine has it
+fn webassembly(name: &str) -> Option<Function> {
+    let webassembly = Reflect::get(&js_sys::global(), &"WebAssembly".into()).ok()?;
+    Reflect::get(&webassembly, &name.into())
+        .ok()?
+        .dyn_into()
+        .ok()
+}
+
+/// `function` as a function returning a promise, which lets the imports of
+/// `suspend` wait, or `None` if the engine doesn't support JSPI
+pub(crate) fn promising(function: &JsValue) -> Option<Function> {
+    webassembly("promising")?
+        .call1(&JsValue::UNDEFINED, function)
+        .ok()?
+        .dyn_into()
+        .ok()
+}
+
+/// Wraps the functions of the WASI `imports` that `module` imports and that
+/// can wait, replacing them by `WebAssembly.Suspending` functions if the
+/// engine supports JSPI. Returns whether it does.
+pub(crate) fn suspend(
+    imports: &Object,
+    module: &Module,
+    state: Arc<WasiState>,
+    waits: &SharedWaits,
+) -> Result<bool, JsValue> {
+    let suspending = webassembly("Suspending");
+    for import in module.imports() {
+        if !NAMESPACES.contains(&import.module()) && !NAMESPACES_64.contains(&import.module()) {
+            continue;
+        }
+        let wait: Wait = match import.name() {
+            "fd_read" => read,
+            "fd_write" => write,
+            "sock_accept" => accept,
+            _ => continue,
+        };
+        let namespace = Reflect::get(imports, &import.module().into())?;
+        if !namespace.is_object() {
+            continue;
+        }
+        let original = Reflect::get(&namespace, &import.name().into())?;
+        if !original.is_function() {
+            continue;
+        }
+
+        let (state, waits) = (state.clone(), waits.clone());
+        let apply = Closure::wrap(Box::new(
+            move |target: Function, this: JsValue, args: Array| -> Result<JsValue, JsValue> {
+                wait(&waits, &state, &target, &this, &args)
+            },
+        )
+            as Box<dyn FnMut(Function, JsValue, Array) -> Result<JsValue, JsValue>>)
+        .into_js_value();
+        let handler = Object::new();
+        Reflect::set(&handler, &"apply".into(), &apply)?;
+        let wrapped: JsValue = Proxy::new(&original, &handler).into();
+        let wrapped = match &suspending {
+            Some(suspending) => Reflect::construct(suspending, &Array::of1(&wrapped))?,
+            None => wrapped,
+        };
+        Reflect::set(&namespace, &import.name().into(), &wrapped)?;
+    }
+    Ok(suspending.is_some())
+}
+
+/// An import that can wait: it calls `target` with `this` and `args`, or
+/// returns a promise of its result for the guest to wait on
+type Wait =
+    fn(&SharedWaits, &Arc<WasiState>, &Function, &JsValue, &Array) -> Result<JsValue, JsValue>;
+
+/// `fd_read`, which waits while the stdin is empty and the previous stage
+/// may still write to it
+fn read(
+    waits: &SharedWaits,
+    state: &Arc<WasiState>,
+    target: &Function,
+    this: &JsValue,
+    args: &Array,
+) -> Result<JsValue, JsValue> {
+    let link = waiting_link(waits, args, STDIN);
+    if let Some(link) = &link {
+        if link.buffered() == 0 && link.writing() {
+            return retry(waits, state, &link.changed(), target, this, args, read);
+        }
+    }
+    let errno = target.apply(this, args)?;
+    // The previous stage may wait for room in the pipe
+    if let Some(link) = link {
+        link.wake();
+    }
+    Ok(errno)
+}
+
+/// `fd_write`, which waits while the pipe to the next stage is full, and
+/// fails with `EPIPE` once the next stage exited
+fn write(
+    waits: &SharedWaits,
+    state: &Arc<WasiState>,
+    target: &Function,
+    this: &JsValue,
+    args: &Array,
+) -> Result<JsValue, JsValue> {
+    let link = waiting_link(waits, args, STDOUT);
+    if let Some(link) = &link {
+        if !link.reading() {
+            return Ok(EPIPE.into());
+        }
+        if link.buffered() >= PIPE_CAPACITY {
+            return retry(waits, state, &link.changed(), target, this, args, write);
+        }
+    }
+    let errno = target.apply(this, args)?;
+    // The next stage may wait for input
+    if let Some(link) = link {
+        link.wake();
+    }
+    Ok(errno)
+}
+
+/// `sock_accept`, which waits for a connection when the listener blocks, and
+/// fails with `EDEADLK` when the guest can't wait
+fn accept(
+    waits: &SharedWaits,
+    state: &Arc<WasiState>,
+    target: &Function,
+    this: &JsValue,
+    args: &Array,
+) -> Result<JsValue, JsValue> {
+    let errno = target.apply(this, args)?;
+    if errno.as_f64() != Some(EAGAIN as f64) {
+        return Ok(errno);
+    }
+    let blocking = sandbox::pointer(&args.get(0))
+        .and_then(|fd| state.fs.get_fd(fd).ok())
+        .map_or(false, |fd| fd.flags.bits() & FDFLAGS_NONBLOCK == 0);
+    let (network, suspendable) = {
+        let waits = waits.borrow();
+        (waits.network.clone(), waits.suspendable)
+    };
+    match network {
+        Some(_) if !blocking => Ok(errno),
+        Some(network) if suspendable => retry(
+            waits,
+            state,
+            &network.connected(),
+            target,
+            this,
+            args,
+            accept,
+        ),
+        // No other guest can connect while this one runs
+        Some(_) => Ok(EDEADLK.into()),
+        None => Ok(errno),
+    }
+}
+
+/// The pipe of the stdio `fd` if the call is on it and the guest can wait
+fn waiting_link(waits: &SharedWaits, args: &Array, fd: u32) -> Option<Link> {
+    let waits = waits.borrow();
+    if !waits.suspendable || sandbox::pointer(&args.get(0)) != Some(fd) {
+        return None;
+    }
+    match fd {
+        STDIN => waits.stdin.clone(),
+        _ => waits.stdout.clone(),
+    }
+}
+
+/// Calls `wait` again once `changed` resolves, returning the promise of its
+/// result
+fn retry(
+    waits: &SharedWaits,
+    state: &Arc<WasiState>,
+    changed: &Promise,
+    target: &Function,
+    this: &JsValue,
+    args: &Array,
+    wait: Wait,
+) -> Result<JsValue, JsValue> {
+    let (waits, state) = (waits.clone(), state.clone());
+    let (target, this, args) = (target.clone(), this.clone(), args.clone());
+    Ok(idb::then(changed, move |_| {
+        wait(&waits, &state, &target, &this, &args)
+    })?
+    .into())
+}
+/// Calls `f` with the value or the rejection of `promise` once it settles,
+/// returning the promise of its result
+pub(crate) fn settle(
+    promise: &Promise,
+    f: impl FnOnce(Result<JsValue, JsValue>) -> Result<JsValue, JsValue> + 'static,
+) -> Result<Promise, JsValue> {
+    // Only one of the handlers is ever called
+    let f = Rc::new(RefCell::new(Some(f)));
+    let handler = |fulfilled: bool| {
+        let f = f.clone();
+        Closure::once_into_js(move |value: JsValue| -> JsValue {
+            let f = match f.borrow_mut().take() {
+                Some(f) => f,
+                None => return JsValue::UNDEFINED,
+            };
+            let result = if fulfilled { Ok(value) } else { Err(value) };
+            f(result).unwrap_or_else(|e| wasm_bindgen::throw_val(e))
+        })
+    };
+    Ok(call_method(promise, "then", &[handler(true), handler(false)])?.unchecked_into())
+}
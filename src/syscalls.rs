@@ -0,0 +1,513 @@
+//! The WASI functions handled here rather than by wasmer-wasi.
+//!
+//! The guest's `MemFS` handles some of them itself, because wasmer-wasi would
+//! only apply them to its own inode table, out of sight of the filesystem and
+//! of JS, or would lose their errors. The others let the guest wait for the
+//! processes it spawns, which wasmer-wasi doesn't support.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use js_sys::{Array, Function, Object, Proxy, Reflect, Uint8Array, WebAssembly};
+use wasm_bindgen::prelude::*;
+use wasmer::Module;
+use wasmer_vbus::VirtualBusError;
+use wasmer_vfs::FsError;
+use wasmer_wasi::state::Kind;
+use wasmer_wasi::WasiState;
+
+use crate::fs::MemFS;
+use crate::process::Bus;
+use crate::sandbox::{GuestMemory, NAMESPACES};
+
+const EACCES: u32 = 2;
+const EBADF: u32 = 8;
+const EEXIST: u32 = 20;
+const EFAULT: u32 = 21;
+const EINVAL: u32 = 28;
+const EIO: u32 = 29;
+const ENOENT: u32 = 44;
+const ENOSPC: u32 = 51;
+const ENOTDIR: u32 = 54;
+
+/// The bus errnos of the errors of running a command
+const BUS_EWAPM: u32 = 3;
+const BUS_EABI: u32 = 6;
+const BUS_EABORTED: u32 = 7;
+const BUS_EBADREQUEST: u32 = 12;
+const BUS_EINTERNAL: u32 = 14;
+const BUS_EMEMVIOLATION: u32 = 18;
+const BUS_EUNKNOWN: u32 = 19;
+
+/// A `busevent` is a tag followed by a union aligned on 8 bytes. An exit event
+/// holds the handle of the process and its exit code.
+const BUS_EVENT_SIZE: u32 = 64;
+const BUS_EVENT_EXIT: u8 = 1;
+const BUS_EVENT_UNION: u32 = 8;
+
+/// The `fst_flags` of the functions setting times
+const FSTFLAGS_ATIM: u32 = 1 << 0;
+const FSTFLAGS_ATIM_NOW: u32 = 1 << 1;
+const FSTFLAGS_MTIM: u32 = 1 << 2;
+const FSTFLAGS_MTIM_NOW: u32 = 1 << 3;
+
+/// The functions replacing those of the WASI `imports` that `module` imports
+/// and that are handled here, as a new imports object. Only the `MemFS`
+/// handles filesystem functions.
+pub(crate) fn imports(
+    imports: &Object,
+    module: &Module,
+    state: Arc<WasiState>,
+    bus: &Bus,
+    memory: &GuestMemory,
+) -> Result<Object, JsValue> {
+    let replaced = Object::new();
+    let fs = state.fs.fs_backing.downcast_ref::<MemFS>().cloned();
+    for import in module.imports() {
+        if !NAMESPACES.contains(&import.module()) {
+            continue;
+        }
+        let namespace = Reflect::get(imports, &import.module().into())?;
+        if !namespace.is_object() {
+            continue;
+        }
+        let original = Reflect::get(&namespace, &import.name().into())?;
+        let original = match original.dyn_into::<Function>() {
+            Ok(original) => original,
+            Err(_) => continue,
+        };
+        let (state, memory) = (state.clone(), memory.clone());
+        let function = match (import.name(), fs.clone()) {
+            ("proc_spawn", _) => proc_spawn(bus.clone(), memory, original)?,
+            ("proc_exec", _) => {
+                let proc_exit = Reflect::get(&namespace, &"proc_exit".into())?;
+                match proc_exit.dyn_into::<Function>() {
+                    Ok(proc_exit) => proc_exec(bus.clone(), memory, proc_exit),
+                    Err(_) => continue,
+                }
+            }
+            ("bus_poll", _) => bus_poll(bus.clone(), memory),
+            ("path_symlink", Some(fs)) => path_symlink(fs, state, memory),
+            ("path_readlink", Some(fs)) => path_readlink(fs, state, memory),
+            ("fd_filestat_set_times", Some(fs)) => fd_filestat_set_times(fs, state, original),
+            ("path_filestat_set_times", Some(fs)) => {
+                path_filestat_set_times(fs, state, memory, original)
+            }
+            ("fd_write", Some(fs)) => fd_write(fs, original),
+            ("fd_pwrite", Some(fs)) => fd_pwrite(fs, original),
+            _ => continue,
+        };
+        let replaced_namespace = match Reflect::get(&replaced, &import.module().into())? {
+            namespace if namespace.is_object() => namespace,
+            _ => {
+                let namespace: JsValue = Object::new().into();
+                Reflect::set(&replaced, &import.module().into(), &namespace)?;
+                namespace
+            }
+        };
+        Reflect::set(&replaced_namespace, &import.name().into(), &function)?;
+    }
+    Ok(replaced)
+}
+
+/// `path_symlink(target, target_len, fd, path, path_len) -> errno`
+fn path_symlink(fs: MemFS, state: Arc<WasiState>, memory: GuestMemory) -> JsValue {
+    Closure::wrap(Box::new(
+        move |target: u32, target_len: u32, fd: u32, path: u32, path_len: u32| -> u32 {
+            let memory = memory.borrow();
+            let memory = match memory.as_ref() {
+                Some(memory) => memory,
+                None => return EIO,
+            };
+            let (target, path) = match (
+                read_string(memory, target, target_len),
+                read_string(memory, path, path_len),
+            ) {
+                (Some(target), Some(path)) => (target, path),
+                _ => return EFAULT,
+            };
+            let dir = match fd_path(&state, fd) {
+                Ok(dir) => dir,
+                Err(errno) => return errno,
+            };
+            match fs.symlink(Path::new(&target), &dir.join(path)) {
+                Ok(()) => 0,
+                Err(e) => errno(e),
+            }
+        },
+    ) as Box<dyn FnMut(u32, u32, u32, u32, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `path_readlink(fd, path, path_len, buf, buf_len, buf_used) -> errno`
+fn path_readlink(fs: MemFS, state: Arc<WasiState>, memory: GuestMemory) -> JsValue {
+    Closure::wrap(Box::new(
+        move |fd: u32, path: u32, path_len: u32, buf: u32, buf_len: u32, buf_used: u32| -> u32 {
+            let memory = memory.borrow();
+            let memory = match memory.as_ref() {
+                Some(memory) => memory,
+                None => return EIO,
+            };
+            let path = match read_string(memory, path, path_len) {
+                Some(path) => path,
+                None => return EFAULT,
+            };
+            let dir = match fd_path(&state, fd) {
+                Ok(dir) => dir,
+                Err(errno) => return errno,
+            };
+            let target = match fs.readlink(&dir.join(path)) {
+                Ok(target) => target,
+                Err(e) => return errno(e),
+            };
+            // Like `readlink`, the target is truncated to fit the buffer
+            let target = target.to_string_lossy();
+            let len = target.len().min(buf_len as usize) as u32;
+            let written = write_bytes(memory, buf, &target.as_bytes()[..len as usize])
+                && write_bytes(memory, buf_used, &len.to_le_bytes());
+            if written {
+                0
+            } else {
+                EFAULT
+            }
+        },
+    ) as Box<dyn FnMut(u32, u32, u32, u32, u32, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `fd_filestat_set_times(fd, atim, mtim, fst_flags) -> errno`
+///
+/// wasmer-wasi updates the times it reports to the guest, and the `MemFS`
+/// gets them too.
+fn fd_filestat_set_times(fs: MemFS, state: Arc<WasiState>, original: Function) -> JsValue {
+    Closure::wrap(Box::new(
+        move |fd: u32, atim: u64, mtim: u64, fst_flags: u32| -> u32 {
+            let args = Array::of4(&fd.into(), &atim.into(), &mtim.into(), &fst_flags.into());
+            let errno = call(&original, &args);
+            if errno != 0 {
+                return errno;
+            }
+            let path = match fd_path(&state, fd) {
+                Ok(path) => path,
+                // Not a file of the filesystem, like stdio
+                Err(_) => return 0,
+            };
+            set_times(&fs, &path, atim, mtim, fst_flags)
+        },
+    ) as Box<dyn FnMut(u32, u64, u64, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `path_filestat_set_times(fd, flags, path, path_len, atim, mtim, fst_flags) -> errno`
+fn path_filestat_set_times(
+    fs: MemFS,
+    state: Arc<WasiState>,
+    memory: GuestMemory,
+    original: Function,
+) -> JsValue {
+    Closure::wrap(Box::new(
+        move |fd: u32,
+              flags: u32,
+              path: u32,
+              path_len: u32,
+              atim: u64,
+              mtim: u64,
+              fst_flags: u32|
+              -> u32 {
+            let args = [
+                JsValue::from(fd),
+                JsValue::from(flags),
+                JsValue::from(path),
+                JsValue::from(path_len),
+                JsValue::from(atim),
+                JsValue::from(mtim),
+                JsValue::from(fst_flags),
+            ]
+            .iter()
+            .collect::<Array>();
+            let errno = call(&original, &args);
+            if errno != 0 {
+                return errno;
+            }
+            let memory = memory.borrow();
+            let memory = match memory.as_ref() {
+                Some(memory) => memory,
+                None => return EIO,
+            };
+            let path = match read_string(memory, path, path_len) {
+                Some(path) => path,
+                None => return EFAULT,
+            };
+            let dir = match fd_path(&state, fd) {
+                Ok(dir) => dir,
+                Err(errno) => return errno,
+            };
+            set_times(&fs, &dir.join(path), atim, mtim, fst_flags)
+        },
+    )
+        as Box<dyn FnMut(u32, u32, u32, u32, u64, u64, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `fd_write(fd, iovs, iovs_len, nwritten) -> errno`
+///
+/// wasmer-wasi gives `EIO` for every failed write, which is `ENOSPC` when the
+/// write went past the limits of the `MemFS`.
+fn fd_write(fs: MemFS, original: Function) -> JsValue {
+    Closure::wrap(Box::new(
+        move |fd: u32, iovs: u32, iovs_len: u32, nwritten: u32| -> u32 {
+            fs.take_full();
+            let args = Array::of4(&fd.into(), &iovs.into(), &iovs_len.into(), &nwritten.into());
+            full_errno(&fs, call(&original, &args))
+        },
+    ) as Box<dyn FnMut(u32, u32, u32, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `fd_pwrite(fd, iovs, iovs_len, offset, nwritten) -> errno`
+fn fd_pwrite(fs: MemFS, original: Function) -> JsValue {
+    Closure::wrap(Box::new(
+        move |fd: u32, iovs: u32, iovs_len: u32, offset: u64, nwritten: u32| -> u32 {
+            fs.take_full();
+            let args = Array::of5(
+                &fd.into(),
+                &iovs.into(),
+                &iovs_len.into(),
+                &offset.into(),
+                &nwritten.into(),
+            );
+            full_errno(&fs, call(&original, &args))
+        },
+    ) as Box<dyn FnMut(u32, u32, u32, u64, u32) -> u32>)
+    .into_js_value()
+}
+
+/// `proc_spawn(name, name_len, chroot, args, args_len, preopen, preopen_len,
+/// stdin, stdout, stderr, working_dir, working_dir_len, ret_handles) -> bus_errno`
+///
+/// The process runs until it exits in wasmer-wasi's `proc_spawn`, and its exit
+/// code is kept with the handle it returns, for `bus_poll`. There are too many
+/// arguments for a closure, so the original function is wrapped in a proxy.
+fn proc_spawn(bus: Bus, memory: GuestMemory, original: Function) -> Result<JsValue, JsValue> {
+    let apply = Closure::wrap(Box::new(
+        move |target: Function, this: JsValue, args: Array| -> Result<JsValue, JsValue> {
+            let errno = target.apply(&this, &args)?;
+            if errno.as_f64() == Some(0.0) {
+                let memory = memory.borrow();
+                let handles = args.get(12).as_f64().map(|handles| handles as i32 as u32);
+                // The handle of the process comes first
+                if let Some(bid) = memory
+                    .as_ref()
+                    .zip(handles)
+                    .and_then(|(memory, handles)| read_u32(memory, handles))
+                {
+                    bus.spawned(bid);
+                }
+            }
+            Ok(errno)
+        },
+    )
+        as Box<dyn FnMut(Function, JsValue, Array) -> Result<JsValue, JsValue>>)
+    .into_js_value();
+    let handler = Object::new();
+    Reflect::set(&handler, &"apply".into(), &apply)?;
+    Ok(Proxy::new(&original, &handler).into())
+}
+
+/// `proc_exec(name, name_len, args, args_len)`
+///
+/// Runs the command with the stdio of the guest, then exits the guest with its
+/// exit code through `proc_exit`. The arguments are separated by newlines. It
+/// only returns when the command can't run, with the bus errno.
+fn proc_exec(bus: Bus, memory: GuestMemory, proc_exit: Function) -> JsValue {
+    Closure::wrap(Box::new(
+        move |name: u32, name_len: u32, args: u32, args_len: u32| -> Result<u32, JsValue> {
+            let (name, args) = {
+                let memory = memory.borrow();
+                let memory = match memory.as_ref() {
+                    Some(memory) => memory,
+                    None => return Ok(BUS_EINTERNAL),
+                };
+                match (
+                    read_string(memory, name, name_len),
+                    read_string(memory, args, args_len),
+                ) {
+                    (Some(name), Some(args)) => (name, args),
+                    _ => return Ok(BUS_EMEMVIOLATION),
+                }
+            };
+            let args = args
+                .split(&['\n', '\r'][..])
+                .filter(|arg| !arg.is_empty())
+                .map(String::from)
+                .collect();
+            match bus.exec(&name, args) {
+                Ok(exit_code) => {
+                    // Traps with the exit of the guest
+                    proc_exit.call1(&JsValue::UNDEFINED, &exit_code.into())?;
+                    Ok(0)
+                }
+                Err(e) => Ok(bus_errno(e)),
+            }
+        },
+    )
+        as Box<dyn FnMut(u32, u32, u32, u32) -> Result<u32, JsValue>>)
+    .into_js_value()
+}
+
+/// `bus_poll(timeout, events, nevents, malloc, malloc_len, ret_nevents) -> bus_errno`
+///
+/// Reports the exit of the processes spawned since the last poll. They all
+/// exited by the time `proc_spawn` returned, so it never waits.
+fn bus_poll(bus: Bus, memory: GuestMemory) -> JsValue {
+    Closure::wrap(Box::new(
+        move |_timeout: u64,
+              events: u32,
+              nevents: u32,
+              _malloc: u32,
+              _malloc_len: u32,
+              ret_nevents: u32|
+              -> u32 {
+            let memory = memory.borrow();
+            let memory = match memory.as_ref() {
+                Some(memory) => memory,
+                None => return BUS_EINTERNAL,
+            };
+            let fits = events
+                .checked_add(nevents.saturating_mul(BUS_EVENT_SIZE))
+                .map_or(false, |end| {
+                    end <= Uint8Array::new(&memory.buffer()).length()
+                });
+            if !fits {
+                return BUS_EMEMVIOLATION;
+            }
+            let exits = bus.poll_exits(nevents as usize);
+            for (i, (bid, exit_code)) in exits.iter().enumerate() {
+                let mut event = [0; BUS_EVENT_SIZE as usize];
+                event[0] = BUS_EVENT_EXIT;
+                let exit = BUS_EVENT_UNION as usize;
+                event[exit..exit + 4].copy_from_slice(&bid.to_le_bytes());
+                event[exit + 4..exit + 8].copy_from_slice(&exit_code.to_le_bytes());
+                write_bytes(memory, events + i as u32 * BUS_EVENT_SIZE, &event);
+            }
+            if write_bytes(memory, ret_nevents, &(exits.len() as u32).to_le_bytes()) {
+                0
+            } else {
+                BUS_EMEMVIOLATION
+            }
+        },
+    ) as Box<dyn FnMut(u64, u32, u32, u32, u32, u32) -> u32>)
+    .into_js_value()
+}
+
+/// The bus errno the guest gets for `error`
+fn bus_errno(error: VirtualBusError) -> u32 {
+    match error {
+        // There is no command by that name
+        VirtualBusError::NotFound => BUS_EWAPM,
+        VirtualBusError::InvalidABI => BUS_EABI,
+        VirtualBusError::Aborted => BUS_EABORTED,
+        VirtualBusError::BadRequest => BUS_EBADREQUEST,
+        VirtualBusError::InternalError => BUS_EINTERNAL,
+        _ => BUS_EUNKNOWN,
+    }
+}
+
+/// The errno of a write, which is `ENOSPC` rather than `EIO` when it went
+/// past the limits
+fn full_errno(fs: &MemFS, errno: u32) -> u32 {
+    if errno == EIO && fs.take_full() {
+        ENOSPC
+    } else {
+        errno
+    }
+}
+
+/// Calls the `original` function of wasmer-wasi, returning its errno
+fn call(original: &Function, args: &Array) -> u32 {
+    match original.apply(&JsValue::UNDEFINED, args) {
+        Ok(errno) => errno.as_f64().map_or(EIO, |errno| errno as u32),
+        Err(_) => EIO,
+    }
+}
+
+/// Sets the times of the file at `path` as the `fst_flags` say, returning the
+/// errno
+fn set_times(fs: &MemFS, path: &Path, atim: u64, mtim: u64, fst_flags: u32) -> u32 {
+    let now = (js_sys::Date::now() * 1_000_000.0) as u64;
+    let time = |set, set_now, time| {
+        if fst_flags & set_now != 0 {
+            Some(now)
+        } else if fst_flags & set != 0 {
+            Some(time)
+        } else {
+            None
+        }
+    };
+    let accessed = time(FSTFLAGS_ATIM, FSTFLAGS_ATIM_NOW, atim);
+    let modified = time(FSTFLAGS_MTIM, FSTFLAGS_MTIM_NOW, mtim);
+    match fs.set_times(path, accessed, modified) {
+        Ok(()) => 0,
+        Err(e) => errno(e),
+    }
+}
+
+/// The path in the filesystem of the file or directory opened as `fd`
+pub(crate) fn fd_path(state: &WasiState, fd: u32) -> Result<PathBuf, u32> {
+    let fd = state.fs.get_fd(fd).map_err(|_| EBADF)?;
+    let inodes = state.inodes.read().unwrap();
+    let kind = inodes.arena[fd.inode].read();
+    match &*kind {
+        Kind::Dir { path, .. } | Kind::File { path, .. } => Ok(path.clone()),
+        Kind::Root { .. } => Ok(PathBuf::from("/")),
+        _ => Err(EBADF),
+    }
+}
+
+/// Reads the string of `len` bytes at `ptr`, or `None` if it isn't in the
+/// memory
+fn read_string(memory: &WebAssembly::Memory, ptr: u32, len: u32) -> Option<String> {
+    let bytes = Uint8Array::new(&memory.buffer());
+    let end = ptr.checked_add(len)?;
+    if end > bytes.length() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes.subarray(ptr, end).to_vec()).into_owned())
+}
+
+/// Reads the `u32` at `ptr`, or `None` if it isn't in the memory
+fn read_u32(memory: &WebAssembly::Memory, ptr: u32) -> Option<u32> {
+    let bytes = Uint8Array::new(&memory.buffer());
+    let end = ptr.checked_add(4)?;
+    if end > bytes.length() {
+        return None;
+    }
+    let mut value = [0; 4];
+    bytes.subarray(ptr, end).copy_to(&mut value);
+    Some(u32::from_le_bytes(value))
+}
+
+/// Writes `data` at `ptr`, returning whether it fits in the memory
+fn write_bytes(memory: &WebAssembly::Memory, ptr: u32, data: &[u8]) -> bool {
+    let bytes = Uint8Array::new(&memory.buffer());
+    match ptr.checked_add(data.len() as u32) {
+        Some(end) if end <= bytes.length() => {
+            bytes.subarray(ptr, end).copy_from(data);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The errno the guest gets for `error`
+fn errno(error: FsError) -> u32 {
+    match error {
+        FsError::AlreadyExists => EEXIST,
+        FsError::EntityNotFound => ENOENT,
+        FsError::BaseNotDirectory => ENOTDIR,
+        FsError::PermissionDenied => EACCES,
+        FsError::InvalidInput => EINVAL,
+        FsError::WriteZero => ENOSPC,
+        _ => EIO,
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use wasmer_wasi::WasiTtyState;
+
+/// The size in pixels assumed for a character cell, as WASI programs can also
+/// ask for the size of the terminal in pixels
+const CELL_WIDTH: u32 = 8;
+const CELL_HEIGHT: u32 = 16;
+
+/// An emulated terminal that the stdio of a WASI instance is attached to
+#[derive(Debug)]
+pub(crate) struct Tty {
+    state: Arc<Mutex<WasiTtyState>>,
+    /// The line being edited in cooked mode
+    line: Vec<u8>,
+}
+
+impl Tty {
+    pub(crate) fn new(columns: u32, rows: u32, stdin: bool, stdout: bool, stderr: bool) -> Self {
+        let state = WasiTtyState {
+            cols: columns,
+            rows,
+            width: columns.saturating_mul(CELL_WIDTH),
+            height: rows.saturating_mul(CELL_HEIGHT),
+            stdin_tty: stdin,
+            stdout_tty: stdout,
+            stderr_tty: stderr,
+            echo: true,
+            line_buffered: true,
+        };
+        Tty {
+            state: Arc::new(Mutex::new(state)),
+            line: vec![],
+        }
+    }
+
+    /// The terminal state shared with the WASI runtime
+    pub(crate) fn state(&self) -> Arc<Mutex<WasiTtyState>> {
+        self.state.clone()
+    }
+
+    /// Makes stdin a pipe rather than the terminal, once another instance
+    /// writes to it
+    pub(crate) fn detach_stdin(&self) {
+        self.state.lock().unwrap().stdin_tty = false;
+    }
+
+    pub(crate) fn resize(&self, columns: u32, rows: u32) {
+        resize(&self.state, columns, rows)
+    }
+
+    /// Subscribes to resize events with `on_resize`, a function like
+    /// `Terminal.onResize` of xterm.js that is called with a listener
+    /// receiving `{cols, rows}` or `{columns, rows}` objects
+    pub(crate) fn listen_resize(&self, on_resize: &js_sys::Function) -> Result<(), JsValue> {
+        let state = self.state.clone();
+        let listener = Closure::wrap(Box::new(move |event: JsValue| {
+            let get = |key: &str| {
+                js_sys::Reflect::get(&event, &key.into())
+                    .ok()
+                    .and_then(|value| value.as_f64())
+            };
+            if let (Some(columns), Some(rows)) =
+                (get("cols").or_else(|| get("columns")), get("rows"))
+            {
+                resize(&state, columns as u32, rows as u32);
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        on_resize.call1(&JsValue::UNDEFINED, &listener.into_js_value())?;
+        Ok(())
+    }
+
+    /// Feeds the input typed by the user to `stdin`, applying the line
+    /// discipline the guest asked for.
+    ///
+    /// In cooked mode the input is only made available line by line, and
+    /// backspace erases the last character of the line. In raw mode the input
+    /// is passed through as is. When echo is on and stdout is the terminal,
+    /// the input is written back to `stdout`. When stdin isn't the terminal,
+    /// the input is passed through without echo.
+    pub(crate) fn input(
+        &mut self,
+        data: &[u8],
+        stdin: &mut impl Write,
+        stdout: &mut impl Write,
+    ) -> io::Result<()> {
+        let (stdin_tty, echo, line_buffered) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.stdin_tty,
+                state.echo && state.stdout_tty,
+                state.line_buffered,
+            )
+        };
+
+        if !stdin_tty {
+            return stdin.write_all(data);
+        }
+
+        if !line_buffered {
+            // The guest switched to raw mode while a line was being edited
+            if !self.line.is_empty() {
+                stdin.write_all(&std::mem::take(&mut self.line))?;
+            }
+            stdin.write_all(data)?;
+            if echo {
+                stdout.write_all(data)?;
+            }
+            return Ok(());
+        }
+
+        for &byte in data {
+            match byte {
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if self.pop_char() && echo {
+                        stdout.write_all(b"\x08 \x08")?;
+                    }
+                }
+                b'\r' | b'\n' => {
+                    self.line.push(b'\n');
+                    stdin.write_all(&std::mem::take(&mut self.line))?;
+                    if echo {
+                        stdout.write_all(b"\r\n")?;
+                    }
+                }
+                _ => {
+                    self.line.push(byte);
+                    if echo {
+                        stdout.write_all(&[byte])?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the last UTF-8 character of the line, if any
+    fn pop_char(&mut self) -> bool {
+        while let Some(byte) = self.line.pop() {
+            // Stop once the first byte of the character is removed
+            if byte & 0b1100_0000 != 0b1000_0000 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn resize(state: &Mutex<WasiTtyState>, columns: u32, rows: u32) {
+    let mut state = state.lock().unwrap();
+    state.cols = columns;
+    state.rows = rows;
+    state.width = columns.saturating_mul(CELL_WIDTH);
+    state.height = rows.saturating_mul(CELL_HEIGHT);
+}
@@ -1,24 +1,119 @@
 use crate::fs::MemFS;
+use crate::http_fs::HttpFS;
+use crate::loopback::Network;
+use crate::net::{JsNetwork, Networking};
+use crate::path;
+use crate::process::{Bus, Commands, Inherited};
+use crate::runtime::Runtime;
+use crate::sandbox::{self, GuestMemory, Sandbox};
+use crate::stdio::{
+    Encoding, LoggedOutput, OutputLog, OutputPipe, OutputStream, SharedStdio, StdioCursor,
+};
+use crate::streams::{self, ReadableStream, WritableStream};
+use crate::suspend::{self, SharedWaits, Waits};
+use crate::syscalls;
+use crate::tty::Tty;
 
 use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use wasmer::{Imports, Instance, Module, Store};
+use wasmer::{ExternType, ImportType, Imports, Instance, Module, RuntimeError, Store, Type};
+use wasmer_vfs::{FileSystem, VirtualFile};
+use wasmer_vnet::VirtualNetworking;
 use wasmer_wasi::Pipe;
 use wasmer_wasi::{WasiError, WasiFunctionEnv, WasiState};
 
 #[wasm_bindgen(typescript_custom_section)]
 const WASI_CONFIG_TYPE_DEFINITION: &str = r#"
+/** A file in the WASI filesystem that a stdio stream is redirected to. */
+export type StdioFile = {
+    /** The path of the file inside of `fs`. */
+    readonly file: string;
+    /** Append to the file instead of truncating it (only for `stdout` and `stderr`). */
+    readonly append?: boolean;
+};
+
 /** Options used when configuring a new WASI instance.  */
 export type WasiConfig = {
     /** The command-line arguments passed to the WASI executable. */
     readonly args?: string[];
     /** Additional environment variables made available to the WASI executable. */
     readonly env?: Record<string, string>;
-    /** Preopened directories. */
+    /**
+     * Preopened directories, from the names the guest sees to directories of
+     * `fs`. The guest can't leave them: paths that go above the directory they
+     * are relative to, or whose symlinks lead out of the preopen of that
+     * directory, fail with `ENOTCAPABLE`. Each preopen is the root of the
+     * absolute symlink targets found in it.
+     */
     readonly preopens?: Record<string, string>;
-    /** The in-memory filesystem that should be used. */
-    readonly fs?: MemFS;
+    /**
+     * The absolute path of the working directory of the guest, which must be a
+     * directory of `fs`. It is preopened as `.` and set as `PWD`.
+     */
+    readonly cwd?: string;
+    /** The filesystem that should be used, an empty `MemFS` by default. */
+    readonly fs?: MemFS | HttpFS;
+    /**
+     * How to handle function imports that are provided neither by WASI nor by
+     * the user imports when calling `instantiate`. With `getImports`, the
+     * placeholders of the functions WASI doesn't provide are added to the
+     * returned imports, where the user imports can replace them.
+     *
+     * - `'error'` (default): fail, listing every unresolved import.
+     * - `'trap'`: generate a placeholder that traps with the import name when called.
+     * - `'zero'`: generate a placeholder that returns zeros (`0n` for `i64` results).
+     */
+    readonly stubMissingImports?: 'trap' | 'zero' | 'error';
+    /** Read stdin from a file in `fs` instead of `setStdinBuffer`. */
+    readonly stdin?: StdioFile;
+    /** Write stdout to a file in `fs` instead of `getStdoutBuffer`. */
+    readonly stdout?: StdioFile;
+    /** Write stderr to a file in `fs` instead of `getStderrBuffer`. */
+    readonly stderr?: StdioFile;
+    /** Write stderr into stdout, like `2>&1`. */
+    readonly mergeStderr?: boolean;
+    /**
+     * Record stdout and stderr writes in the order they happen, see
+     * `getOutputLog`. The writes still go to the stdout and stderr buffers
+     * too, so the output is kept twice until both are read.
+     */
+    readonly outputLog?: boolean;
+    /**
+     * Lets the guest use WASIX sockets, either to open TCP connections through
+     * a transport, or to connect to the other instances sharing a `Network`.
+     * There is no networking by default.
+     *
+     * A transport only supports the WASIX `sock_connect` of TCP sockets: it
+     * can't listen or carry UDP, and `MSG_PEEK` fails as unsupported. A
+     * `Network` supports listening too. A blocking `sock_accept` waits for a
+     * connection when the guest runs with `startAsync` and JSPI, and fails
+     * with `EDEADLK` otherwise.
+     */
+    readonly network?: NetworkTransport | Network;
+    /** Attach the stdio that isn't redirected to files to an emulated terminal. */
+    readonly tty?: TtyConfig;
+};
+
+/** Options of the emulated terminal. */
+export type TtyConfig = {
+    /** The number of columns of the terminal (80 by default). */
+    readonly columns?: number;
+    /** The number of rows of the terminal (24 by default). */
+    readonly rows?: number;
+    /**
+     * Subscribes to the resize events of the terminal, like `Terminal.onResize`
+     * of xterm.js. It is called with a listener that takes `{cols, rows}`.
+     */
+    readonly onResize?: (listener: (size: {cols: number, rows: number}) => void) => void;
+};
+
+/** A write to stdout or stderr, as returned by `getOutputLog`. */
+export type OutputLogEntry = {
+    readonly stream: 'stdout' | 'stderr';
+    readonly data: Uint8Array;
 };
 "#;
 
@@ -28,21 +123,118 @@ extern "C" {
     pub type WasiConfig;
 }
 
+/// Opens the file a stdio stream is redirected to with the `config[name]`
+/// option, if any
+fn open_stdio_file(
+    config: &WasiConfig,
+    name: &str,
+    fs: &dyn FileSystem,
+) -> Result<Option<Box<dyn VirtualFile + Send + Sync>>, JsValue> {
+    let stdio = js_sys::Reflect::get(config, &name.into())?;
+    if stdio.is_undefined() {
+        return Ok(None);
+    }
+    let path = js_sys::Reflect::get(&stdio, &"file".into())?
+        .as_string()
+        .ok_or_else(|| js_sys::Error::new(&format!("The `{}.file` must be a string", name)))?;
+    let append = js_sys::Reflect::get(&stdio, &"append".into())?
+        .as_bool()
+        .unwrap_or(false);
+
+    let mut open_options = fs.new_open_options();
+    if name == "stdin" {
+        open_options.read(true);
+    } else {
+        open_options
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append);
+    }
+    let file = open_options.open(&path).map_err(|e| {
+        js_sys::Error::new(&format!("Couldn't open `{}` for {}: {}`", path, name, e))
+    })?;
+    Ok(Some(file))
+}
+
+/// The exit code of a guest whose start function returned `result`
+fn exit_code(result: Result<(), RuntimeError>) -> Result<u32, JsValue> {
+    match result {
+        Ok(()) => Ok(0),
+        Err(err) => match err.downcast::<WasiError>() {
+            // We should exit with the provided exit code
+            Ok(WasiError::Exit(exit_code)) => Ok(exit_code),
+            Ok(err) => Err(js_sys::Error::new(&format!(
+                "Unexpected WASI error while running start function: {}",
+                err
+            ))
+            .into()),
+            Err(err) => Err(js_sys::Error::new(&format!(
+                "Error while running start function: {}",
+                err
+            ))
+            .into()),
+        },
+    }
+}
+
+/// The namespace `name` of the `imports`, added if it is missing
+fn namespace(imports: &js_sys::Object, name: &str) -> Result<JsValue, JsValue> {
+    match js_sys::Reflect::get(imports, &name.into())? {
+        namespace if namespace.is_object() => Ok(namespace),
+        _ => {
+            let namespace: JsValue = js_sys::Object::new().into();
+            js_sys::Reflect::set(imports, &name.into(), &namespace)?;
+            Ok(namespace)
+        }
+    }
+}
+
+/// What to do with function imports that nobody provides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StubMissingImports {
+    Error,
+    Trap,
+    Zero,
+}
+
 #[wasm_bindgen]
 pub struct WASI {
     store: Store,
-    stdout: Pipe,
+    stdout: OutputPipe,
     stdin: Pipe,
-    stderr: Pipe,
+    stderr: OutputPipe,
     wasi_env: WasiFunctionEnv,
     module: Option<Module>,
     instance: Option<Instance>,
+    stub_missing_imports: StubMissingImports,
+    output_log: Option<OutputLog>,
+    tty: Option<Tty>,
+    memory: GuestMemory,
+    /// The preopens the guest is confined to
+    sandbox: Arc<Sandbox>,
+    /// The bus that spawns the processes of the guest
+    bus: Bus,
+    /// Whether stdin and stdout are redirected to files, rather than going
+    /// through `stdin` and `stdout`
+    stdin_is_file: bool,
+    stdout_is_file: bool,
+    commands: Commands,
+    /// The pipes the guest waits on while it runs with `startAsync`
+    waits: SharedWaits,
+    /// Whether the imports can wait, which needs JSPI
+    suspending: bool,
 }
 
-#[wasm_bindgen]
 impl WASI {
-    #[wasm_bindgen(constructor)]
-    pub fn new(config: WasiConfig) -> Result<WASI, JsValue> {
+    /// Creates an instance whose guest spawns the `commands`, or the commands
+    /// registered on it when `None`, and uses the `inherited` stdio of the
+    /// guest that spawned it rather than its own
+    pub(crate) fn with_commands(
+        config: WasiConfig,
+        commands: Option<Commands>,
+        inherited: Inherited,
+    ) -> Result<WASI, JsValue> {
         let args: Vec<String> = {
             let args = js_sys::Reflect::get(&config, &"args".into())?;
             if args.is_undefined() {
@@ -58,7 +250,7 @@ impl WASI {
                     .collect::<Result<Vec<String>, JsValue>>()?
             }
         };
-        let env: Vec<(String, String)> = {
+        let mut env: Vec<(String, String)> = {
             let env = js_sys::Reflect::get(&config, &"env".into())?;
             if env.is_undefined() {
                 vec![]
@@ -80,7 +272,7 @@ impl WASI {
             }
         };
 
-        let preopens: Vec<(String, String)> =
+        let mut preopens: Vec<(String, String)> =
             {
                 let preopens = js_sys::Reflect::get(&config, &"preopens".into())?;
                 if preopens.is_undefined() {
@@ -103,31 +295,228 @@ impl WASI {
                 }
             };
 
-        let fs = {
+        let fs_object = {
             let fs = js_sys::Reflect::get(&config, &"fs".into())?;
             if fs.is_undefined() {
-                MemFS::new()?
+                JsValue::from(MemFS::new(None)?)
             } else {
-                MemFS::from_js(fs)?
+                fs
+            }
+        };
+        let mem_fs = MemFS::from_js(fs_object.clone()).ok();
+        let fs: Box<dyn FileSystem> = match &mem_fs {
+            Some(fs) => Box::new(fs.clone()),
+            None => Box::new(
+                HttpFS::from_js(fs_object.clone())
+                    .map_err(|_| js_sys::Error::new("The `fs` must be a `MemFS` or an `HttpFS`"))?,
+            ),
+        };
+        // The processes that the guest spawns start in the same directory
+        let mut guest_cwd = "/".to_string();
+        let cwd = js_sys::Reflect::get(&config, &"cwd".into())?;
+        if !cwd.is_undefined() {
+            let cwd = match cwd.as_string() {
+                Some(cwd) if cwd.starts_with('/') => {
+                    path::normalize(Path::new("/"), Path::new(&cwd))
+                }
+                _ => return Err(js_sys::Error::new("The `cwd` must be an absolute path").into()),
+            };
+            if !fs
+                .metadata(&cwd)
+                .map_or(false, |metadata| metadata.is_dir())
+            {
+                return Err(js_sys::Error::new(&format!(
+                    "The `cwd` `{}` is not a directory of the filesystem",
+                    cwd.display()
+                ))
+                .into());
+            }
+            let cwd = cwd.to_string_lossy().into_owned();
+            // wasi-libc resolves relative paths with the last preopen whose
+            // name matches, so `.` goes after the others
+            preopens.retain(|(alias, _)| alias != ".");
+            preopens.push((".".to_string(), cwd.clone()));
+            if !env.iter().any(|(key, _)| key == "PWD") {
+                env.push(("PWD".to_string(), cwd.clone()));
+            }
+            guest_cwd = cwd;
+        }
+        let stub_missing_imports = {
+            let stub = js_sys::Reflect::get(&config, &"stubMissingImports".into())?;
+            if stub.is_undefined() {
+                StubMissingImports::Error
+            } else {
+                match stub.as_string().as_deref() {
+                    Some("error") => StubMissingImports::Error,
+                    Some("trap") => StubMissingImports::Trap,
+                    Some("zero") => StubMissingImports::Zero,
+                    _ => {
+                        return Err(js_sys::Error::new(
+                            "`stubMissingImports` must be one of 'trap', 'zero' or 'error'",
+                        )
+                        .into())
+                    }
+                }
             }
         };
         let mut store = Store::default();
-        let stdout = Pipe::default();
+        let stdout = OutputPipe::default();
         let stdin = Pipe::default();
-        let stderr = Pipe::default();
+        let stderr = OutputPipe::default();
+        let stdout_file = open_stdio_file(&config, "stdout", &*fs)?;
+        let stdin_file = open_stdio_file(&config, "stdin", &*fs)?;
+        let stderr_file = open_stdio_file(&config, "stderr", &*fs)?;
+        let merge_stderr = js_sys::Reflect::get(&config, &"mergeStderr".into())?
+            .as_bool()
+            .unwrap_or(false);
+        if merge_stderr && (stdout_file.is_some() || stderr_file.is_some()) {
+            return Err(js_sys::Error::new(
+                "`mergeStderr` can't be used when stdout or stderr are redirected to a file",
+            )
+            .into());
+        }
+        let stdin_is_tty = stdin_file.is_none();
+        let stdout_is_tty = stdout_file.is_none();
+        let stderr_is_tty = stderr_file.is_none();
+        let stdout_handle = stdout_file.unwrap_or_else(|| Box::new(stdout.clone()));
+        let stdin_handle = stdin_file.unwrap_or_else(|| Box::new(stdin.clone()));
+        let stderr_handle = stderr_file.unwrap_or_else(|| {
+            if merge_stderr {
+                Box::new(stdout.clone())
+            } else {
+                Box::new(stderr.clone())
+            }
+        });
+        let output_log = js_sys::Reflect::get(&config, &"outputLog".into())?
+            .as_bool()
+            .unwrap_or(false)
+            .then(OutputLog::default);
+        let (stdout_handle, stderr_handle) = match &output_log {
+            Some(log) => (
+                Box::new(LoggedOutput::new(
+                    OutputStream::Stdout,
+                    stdout_handle,
+                    log.clone(),
+                )) as Box<dyn VirtualFile + Send + Sync>,
+                Box::new(LoggedOutput::new(
+                    OutputStream::Stderr,
+                    stderr_handle,
+                    log.clone(),
+                )) as Box<dyn VirtualFile + Send + Sync>,
+            ),
+            None => (stdout_handle, stderr_handle),
+        };
+        let tty = {
+            let tty_config = js_sys::Reflect::get(&config, &"tty".into())?;
+            if tty_config.is_undefined() {
+                None
+            } else {
+                let size = |key: &str, default: u32| -> Result<u32, JsValue> {
+                    Ok(js_sys::Reflect::get(&tty_config, &key.into())?
+                        .as_f64()
+                        .map(|value| value as u32)
+                        .unwrap_or(default))
+                };
+                let columns = size("columns", 80)?;
+                let rows = size("rows", 24)?;
+                let tty = Tty::new(columns, rows, stdin_is_tty, stdout_is_tty, stderr_is_tty);
+                let on_resize = js_sys::Reflect::get(&tty_config, &"onResize".into())?;
+                if !on_resize.is_undefined() {
+                    let on_resize: js_sys::Function = on_resize.dyn_into()?;
+                    tty.listen_resize(&on_resize)?;
+                }
+                // Most programs written against WASI preview1 can only learn
+                // the terminal size from the environment
+                if !env
+                    .iter()
+                    .any(|(key, _)| key == "COLUMNS" || key == "LINES")
+                {
+                    env.push(("COLUMNS".to_string(), columns.to_string()));
+                    env.push(("LINES".to_string(), rows.to_string()));
+                }
+                Some(tty)
+            }
+        };
+        // The processes that the guest spawns inherit the same handles
+        let stdin_handle = inherited
+            .stdin
+            .unwrap_or_else(|| SharedStdio::new(stdin_handle));
+        let stdout_handle = inherited
+            .stdout
+            .unwrap_or_else(|| SharedStdio::new(stdout_handle));
+        let stderr_handle = inherited
+            .stderr
+            .unwrap_or_else(|| SharedStdio::new(stderr_handle));
+        let tty_state = tty.as_ref().map(Tty::state).or(inherited.tty);
+        // The network the guest waits on for connections, if it is shared
+        let mut loopback = None;
+        let networking: Option<Box<dyn VirtualNetworking>> = {
+            let network = js_sys::Reflect::get(&config, &"network".into())?;
+            if network.is_undefined() {
+                None
+            } else {
+                match Network::from_js(network) {
+                    Ok(network) => {
+                        loopback = Some(network.clone());
+                        Some(Box::new(Networking::new(network)))
+                    }
+                    Err(transport) => Some(Box::new(Networking::new(JsNetwork::new(
+                        transport.unchecked_into(),
+                    )?))),
+                }
+            }
+        };
+        let commands = commands.unwrap_or_else(|| Commands::new(fs_object));
+        let bus = Bus::new(
+            commands.clone(),
+            env.clone(),
+            guest_cwd,
+            Inherited {
+                stdin: Some(stdin_handle.clone()),
+                stdout: Some(stdout_handle.clone()),
+                stderr: Some(stderr_handle.clone()),
+                tty: tty_state.clone(),
+            },
+        );
+        // The guest resolves its paths in a copy of the `MemFS` confined to
+        // the preopens, with their symlinks resolved
+        let root = |dir: &Path| match &mem_fs {
+            Some(mem_fs) => mem_fs
+                .canonicalize(dir)
+                .unwrap_or_else(|_| path::normalize(Path::new("/"), dir)),
+            None => path::normalize(Path::new("/"), dir),
+        };
+        let sandbox = Arc::new(Sandbox::new(
+            preopens
+                .iter()
+                .map(|(_, dir)| root(Path::new(dir)))
+                .collect(),
+        ));
+        let fs: Box<dyn FileSystem> = match &mem_fs {
+            Some(mem_fs) => Box::new(mem_fs.for_guest(sandbox.clone())),
+            None => fs,
+        };
         let wasi_env = WasiState::new(args.get(0).unwrap_or(&"".to_string()))
             .args(if !args.is_empty() { &args[1..] } else { &[] })
             .envs(env)
-            .set_fs(Box::new(fs))
-            .stdout(Box::new(stdout.clone()))
-            .stdin(Box::new(stdin.clone()))
-            .stderr(Box::new(stderr.clone()))
+            .set_fs(fs)
+            .stdout(Box::new(stdout_handle))
+            .stdin(Box::new(stdin_handle))
+            .stderr(Box::new(stderr_handle))
+            .runtime(Runtime::new(tty_state, networking, Box::new(bus.clone())))
             .map_dirs(preopens)
             .map_err(|e| js_sys::Error::new(&format!("Couldn't preopen the dir: {}`", e)))?
             // .map_dirs(vec![(".".to_string(), "/".to_string())])
             // .preopen_dir("/").map_err(|e| js_sys::Error::new(&format!("Couldn't preopen the dir: {}`", e)))?
             .finalize(&mut store)
             .map_err(|e| js_sys::Error::new(&format!("Failed to create the WasiState: {}`", e)))?;
+        // Each preopen confines the paths given with its fd
+        let state = wasi_env.data(&store).state.clone();
+        for &fd in state.fs.preopen_fds.read().unwrap().iter() {
+            if let Ok(dir) = syscalls::fd_path(&state, fd) {
+                sandbox.track(fd, root(&dir));
+            }
+        }
 
         Ok(WASI {
             store,
@@ -137,18 +526,111 @@ impl WASI {
             wasi_env,
             module: None,
             instance: None,
+            stub_missing_imports,
+            output_log,
+            tty,
+            memory: GuestMemory::default(),
+            sandbox,
+            bus,
+            stdin_is_file: !stdin_is_tty,
+            stdout_is_file: !stdout_is_tty,
+            commands,
+            waits: Waits::shared(loopback),
+            suspending: false,
         })
     }
 
+    /// The start function of the instance, instantiated with `instance` if
+    /// given
+    fn start_function(
+        &mut self,
+        instance: Option<js_sys::WebAssembly::Instance>,
+    ) -> Result<wasmer::Function, JsValue> {
+        if let Some(instance) = instance {
+            self.instantiate(instance.into(), None)?;
+        } else if self.instance.is_none() {
+            return Err(
+                js_sys::Error::new("You need to provide an instance as argument to `start`, or call `wasi.instantiate` with the `WebAssembly.Instance` manually").into(),
+            );
+        }
+        Ok(self
+            .instance
+            .as_ref()
+            .unwrap()
+            .exports
+            .get_function("_start")
+            .map_err(|_e| js_sys::Error::new("The _start function is not present"))?
+            .clone())
+    }
+
+    /// Runs the `start` function without letting the guest wait
+    fn run(&mut self, start: &wasmer::Function) -> Result<u32, JsValue> {
+        self.stdout.reopen();
+        self.stderr.reopen();
+        suspend::started(&self.waits, false);
+        let result = start.call(&mut self.store, &[]);
+        self.stdout.close();
+        self.stderr.close();
+        suspend::exited(&self.waits);
+        exit_code(result.map(|_| ()))
+    }
+
+    /// The pipe feeding the stdin, when it isn't redirected to a file
+    pub(crate) fn stdin_pipe(&self) -> Pipe {
+        self.stdin.clone()
+    }
+
+    /// The pipe collecting the stdout, when it isn't redirected to a file
+    pub(crate) fn stdout_pipe(&self) -> OutputPipe {
+        self.stdout.clone()
+    }
+
+    /// The pipe collecting the stderr, when it isn't redirected to a file
+    pub(crate) fn stderr_pipe(&self) -> OutputPipe {
+        self.stderr.clone()
+    }
+}
+
+#[wasm_bindgen]
+impl WASI {
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: WasiConfig) -> Result<WASI, JsValue> {
+        WASI::with_commands(config, None, Inherited::default())
+    }
+
+    /// Let the guest spawn `module` as `name` with WASIX `proc_spawn` and
+    /// `proc_exec`. The processes share the filesystem and the commands of
+    /// this instance, and start in its working directory by default. The stdio
+    /// they inherit goes through the stdio of the guest, with its files, output
+    /// log and terminal. The guest gets the exit codes of the processes it
+    /// spawns with `bus_poll`.
+    #[wasm_bindgen(js_name = registerCommand)]
+    pub fn register_command(&mut self, name: String, module: js_sys::WebAssembly::Module) {
+        self.commands.register(name, module);
+    }
+
     #[wasm_bindgen(getter)]
     pub fn fs(&mut self) -> Result<MemFS, JsValue> {
         let state = self.wasi_env.data_mut(&mut self.store).state();
-        let mem_fs = state
-            .fs
-            .fs_backing
+        let fs_backing = &state.fs.fs_backing;
+        if fs_backing.downcast_ref::<HttpFS>().is_some() {
+            return Err(js_sys::Error::new(
+                "The filesystem is an `HttpFS`, which the `httpFs` getter gives",
+            )
+            .into());
+        }
+        let mem_fs = fs_backing
             .downcast_ref::<MemFS>()
             .ok_or_else(|| js_sys::Error::new("Failed to downcast to MemFS"))?;
-        Ok(mem_fs.clone())
+        Ok(mem_fs.for_host())
+    }
+
+    /// The `HttpFS` of the instance, or `undefined` if its filesystem is a
+    /// `MemFS`
+    #[wasm_bindgen(getter, js_name = httpFs)]
+    pub fn http_fs(&mut self) -> Option<HttpFS> {
+        let state = self.wasi_env.data_mut(&mut self.store).state();
+        state.fs.fs_backing.downcast_ref::<HttpFS>().cloned()
     }
 
     #[wasm_bindgen(js_name = getImports)]
@@ -163,22 +645,160 @@ impl WASI {
         })?;
         let module: Module = module.into();
         let import_object = self.get_wasi_imports(&module)?;
+        let imports = import_object.as_jsobject(&self.store);
+        // The other imports may still come from the user, but the functions
+        // that are missing get stubbed unless stubbing is disabled
+        if self.stub_missing_imports != StubMissingImports::Error {
+            for import in module.imports() {
+                if import_object.exists(import.module(), import.name())
+                    || !matches!(import.ty(), ExternType::Function(_))
+                {
+                    continue;
+                }
+                let namespace = namespace(&imports, import.module())?;
+                js_sys::Reflect::set(&namespace, &import.name().into(), &self.stub(&import))?;
+            }
+        }
+        let state = self.wasi_env.data(&self.store).state.clone();
+        self.suspending = suspend::suspend(&imports, &module, state, &self.waits)?;
 
         self.module = Some(module);
 
-        Ok(import_object.as_jsobject(&self.store))
+        Ok(imports)
     }
 
     fn get_wasi_imports(&mut self, module: &Module) -> Result<Imports, JsValue> {
-        let import_object = self
+        let mut import_object = self
             .wasi_env
             .import_object(&mut self.store, module)
             .map_err(|e| {
                 js_sys::Error::new(&format!("Failed to create the Import Object: {}`", e))
             })?;
+        // The functions handled here rather than by wasmer-wasi go in first,
+        // so their paths get confined too
+        let syscalls = syscalls::imports(
+            &import_object.as_jsobject(&self.store),
+            module,
+            self.wasi_env.data(&self.store).state.clone(),
+            &self.bus,
+            &self.memory,
+        )?;
+        let syscalls = Imports::new_from_js_object(&mut self.store, module, syscalls)
+            .map_err(|e| js_sys::Error::new(&format!("Failed to add the syscalls: {}`", e)))?;
+        import_object.extend(&syscalls);
+        let confined = sandbox::confine(
+            &import_object.as_jsobject(&self.store),
+            module,
+            self.wasi_env.data(&self.store).state.clone(),
+            &self.memory,
+            &self.sandbox,
+        )?;
+        let confined = Imports::new_from_js_object(&mut self.store, module, confined)
+            .map_err(|e| js_sys::Error::new(&format!("Failed to confine the imports: {}`", e)))?;
+        import_object.extend(&confined);
         Ok(import_object)
     }
 
+    /// Looks for imports of `module` that neither WASI nor the user imports
+    /// provide, and either fails listing them or adds placeholder functions
+    /// for them to the user imports, depending on `stubMissingImports`.
+    fn stub_imports(
+        &self,
+        module: &Module,
+        import_object: &Imports,
+        imports: Option<js_sys::Object>,
+    ) -> Result<Option<js_sys::Object>, JsValue> {
+        let is_user_import = |namespace: &str, name: &str| -> Result<bool, JsValue> {
+            let imports = match &imports {
+                Some(imports) => imports,
+                None => return Ok(false),
+            };
+            let namespace = js_sys::Reflect::get(imports, &namespace.into())?;
+            if !namespace.is_object() {
+                return Ok(false);
+            }
+            Ok(!js_sys::Reflect::get(&namespace, &name.into())?.is_undefined())
+        };
+
+        let mut missing = vec![];
+        for import in module.imports() {
+            if import_object.exists(import.module(), import.name())
+                || is_user_import(import.module(), import.name())?
+            {
+                continue;
+            }
+            missing.push(import);
+        }
+        if missing.is_empty() {
+            return Ok(imports);
+        }
+
+        let unstubbable = missing
+            .iter()
+            .filter(|import| {
+                self.stub_missing_imports == StubMissingImports::Error
+                    || !matches!(import.ty(), ExternType::Function(_))
+            })
+            .map(|import| format!("`{}.{}`", import.module(), import.name()))
+            .collect::<Vec<_>>();
+        if !unstubbable.is_empty() {
+            return Err(js_sys::Error::new(&format!(
+                "Failed to instantiate WASI: the module requires imports that were not provided: {}",
+                unstubbable.join(", ")
+            ))
+            .into());
+        }
+
+        let imports = imports.unwrap_or_else(js_sys::Object::new);
+        for import in missing {
+            let namespace = namespace(&imports, import.module())?;
+            js_sys::Reflect::set(&namespace, &import.name().into(), &self.stub(&import))?;
+        }
+        Ok(Some(imports))
+    }
+
+    /// The placeholder function for the function `import`, which either
+    /// traps or returns zeros, depending on `stubMissingImports`
+    fn stub(&self, import: &ImportType) -> JsValue {
+        match self.stub_missing_imports {
+            StubMissingImports::Trap => {
+                let message = format!(
+                    "Called the unresolved import `{}.{}`",
+                    import.module(),
+                    import.name()
+                );
+                Closure::wrap(Box::new(move || -> JsValue {
+                    wasm_bindgen::throw_val(js_sys::WebAssembly::RuntimeError::new(&message).into())
+                }) as Box<dyn FnMut() -> JsValue>)
+                .into_js_value()
+            }
+            StubMissingImports::Zero => {
+                let results = match import.ty() {
+                    ExternType::Function(ty) => ty.results().to_vec(),
+                    _ => vec![],
+                };
+                // The engine converts the results to their types, except for
+                // `i64` which must be a `BigInt`
+                let zeros = results
+                    .iter()
+                    .map(|ty| match ty {
+                        Type::I64 => js_sys::BigInt::from(0).into(),
+                        Type::ExternRef | Type::FuncRef => JsValue::NULL,
+                        _ => JsValue::from(0),
+                    })
+                    .collect::<Vec<_>>();
+                let result = match &zeros[..] {
+                    [] => JsValue::UNDEFINED,
+                    [zero] => zero.clone(),
+                    zeros => zeros.iter().collect::<js_sys::Array>().into(),
+                };
+                Closure::wrap(Box::new(move || result.clone()) as Box<dyn FnMut() -> JsValue>)
+                    .into_js_value()
+            }
+            StubMissingImports::Error => unreachable!(),
+        }
+    }
+
     pub fn instantiate(
         &mut self,
         module_or_instance: JsValue,
@@ -186,8 +806,9 @@ impl WASI {
     ) -> Result<js_sys::WebAssembly::Instance, JsValue> {
         let instance = if module_or_instance.has_type::<js_sys::WebAssembly::Module>() {
             let js_module: js_sys::WebAssembly::Module = module_or_instance.unchecked_into();
-            let module: Module = js_module.into();
+            let module: Module = js_module.clone().into();
             let import_object = self.get_wasi_imports(&module)?;
+            let imports = self.stub_imports(&module, &import_object, imports)?;
             let imports = if let Some(base_imports) = imports {
                 let mut imports =
                     Imports::new_from_js_object(&mut self.store, &module, base_imports).map_err(
@@ -199,8 +820,22 @@ impl WASI {
                 import_object
             };
 
-            let instance = Instance::new(&mut self.store, &module, &imports)
-                .map_err(|e| js_sys::Error::new(&format!("Failed to instantiate WASI: {}`", e)))?;
+            // The imports that can wait aren't wasmer functions once wrapped,
+            // so they can't go through the `Imports`
+            let js_imports = imports.as_jsobject(&self.store);
+            let state = self.wasi_env.data(&self.store).state.clone();
+            self.suspending = suspend::suspend(&js_imports, &module, state, &self.waits)?;
+            let js_instance =
+                js_sys::WebAssembly::Instance::new(&js_module, &js_imports).map_err(|e| {
+                    js_sys::Error::new(&format!(
+                        "Failed to instantiate WASI: {}`",
+                        String::from(e.unchecked_into::<js_sys::Error>().message())
+                    ))
+                })?;
+            let instance =
+                Instance::from_module_and_instance(&mut self.store, &module, js_instance).map_err(
+                    |e| js_sys::Error::new(&format!("Can't get the Wasmer Instance: {:?}", e)),
+                )?;
             self.module = Some(module);
             instance
         } else if module_or_instance.has_type::<js_sys::WebAssembly::Instance>() {
@@ -225,6 +860,8 @@ impl WASI {
             .set_memory(instance.exports.get_memory("memory").unwrap().clone());
 
         let raw_instance = instance.raw(&self.store).clone();
+        let memory = js_sys::Reflect::get(&raw_instance.exports(), &"memory".into())?;
+        *self.memory.borrow_mut() = memory.dyn_into().ok();
         self.instance = Some(instance);
         Ok(raw_instance)
     }
@@ -235,53 +872,62 @@ impl WASI {
         &mut self,
         instance: Option<js_sys::WebAssembly::Instance>,
     ) -> Result<u32, JsValue> {
-        if let Some(instance) = instance {
-            self.instantiate(instance.into(), None)?;
-        } else if self.instance.is_none() {
-            return Err(
-                js_sys::Error::new("You need to provide an instance as argument to `start`, or call `wasi.instantiate` with the `WebAssembly.Instance` manually").into(),
-            );
-        }
-        let start = self
-            .instance
-            .as_ref()
-            .unwrap()
-            .exports
-            .get_function("_start")
-            .map_err(|_e| js_sys::Error::new("The _start function is not present"))?;
-        let result = start.call(&mut self.store, &[]);
+        let start = self.start_function(instance)?;
+        self.run(&start)
+    }
 
-        match result {
-            Ok(_) => Ok(0),
-            Err(err) => {
-                match err.downcast::<WasiError>() {
-                    Ok(WasiError::Exit(exit_code)) => {
-                        // We should exit with the provided exit code
-                        Ok(exit_code)
-                    }
-                    Ok(err) => {
-                        return Err(js_sys::Error::new(&format!(
-                            "Unexpected WASI error while running start function: {}",
-                            err
-                        ))
-                        .into())
-                    }
-                    Err(err) => {
-                        return Err(js_sys::Error::new(&format!(
-                            "Error while running start function: {}",
-                            err
-                        ))
-                        .into())
-                    }
-                }
+    /// Start the WASI Instance like `start`, but return a promise of the
+    /// status code, and let the guest wait for the instances it is piped to
+    /// and from with `pipeTo` as they run
+    /// Note: the guest only waits on engines with JSPI
+    /// (`WebAssembly.Suspending`), otherwise it runs like with `start`
+    #[wasm_bindgen(js_name = startAsync)]
+    pub fn start_async(
+        &mut self,
+        instance: Option<js_sys::WebAssembly::Instance>,
+    ) -> Result<js_sys::Promise, JsValue> {
+        let start = self.start_function(instance)?;
+        // The imports only wait when the start function is promising
+        let promising = if self.suspending {
+            let raw_instance = self.instance.as_ref().unwrap().raw(&self.store);
+            suspend::promising(&js_sys::Reflect::get(
+                &raw_instance.exports(),
+                &"_start".into(),
+            )?)
+        } else {
+            None
+        };
+        let promising = match promising {
+            Some(promising) => promising,
+            None => {
+                return Ok(match self.run(&start) {
+                    Ok(code) => js_sys::Promise::resolve(&code.into()),
+                    Err(e) => js_sys::Promise::reject(&e),
+                });
             }
-        }
+        };
+        self.stdout.reopen();
+        self.stderr.reopen();
+        suspend::started(&self.waits, true);
+        let promise = match promising.call0(&JsValue::UNDEFINED) {
+            Ok(promise) => promise.unchecked_into(),
+            Err(e) => js_sys::Promise::reject(&e),
+        };
+        let (stdout, stderr, waits) =
+            (self.stdout.clone(), self.stderr.clone(), self.waits.clone());
+        suspend::settle(&promise, move |result| {
+            stdout.close();
+            stderr.close();
+            suspend::exited(&waits);
+            exit_code(result.map(|_| ()).map_err(RuntimeError::from)).map(JsValue::from)
+        })
     }
 
     // Stdio methods below
 
     /// Get the stdout buffer
-    /// Note: this method flushes the stdout
+    /// Note: this method flushes the stdout, and is always empty when stdout
+    /// is redirected to a file
     #[wasm_bindgen(js_name = getStdoutBuffer)]
     pub fn get_stdout_buffer(&mut self) -> Result<Vec<u8>, JsValue> {
         let mut buf = Vec::new();
@@ -291,22 +937,25 @@ impl WASI {
         Ok(buf)
     }
 
-    /// Get the stdout data as a string
-    /// Note: this method flushes the stdout
+    /// Get the stdout data as a string, decoded with `encoding` (`'utf-8'` by
+    /// default, `'utf-8-lossy'` or `'latin1'`)
+    /// Note: this method flushes the stdout, except for a trailing incomplete
+    /// UTF-8 sequence which is kept until the rest of it is written
     #[wasm_bindgen(js_name = getStdoutString)]
-    pub fn get_stdout_string(&mut self) -> Result<String, JsValue> {
-        let mut stdout_str = String::new();
-        self.stdout.read_to_string(&mut stdout_str).map_err(|e| {
+    pub fn get_stdout_string(&mut self, encoding: Option<String>) -> Result<String, JsValue> {
+        let encoding = Encoding::from_js(encoding)?;
+        self.stdout.read_string(encoding).map_err(|e| {
             js_sys::Error::new(&format!(
                 "Could not convert the stdout bytes to a String: {}`",
                 e
             ))
-        })?;
-        Ok(stdout_str)
+            .into()
+        })
     }
 
     /// Get the stderr buffer
-    /// Note: this method flushes the stderr
+    /// Note: this method flushes the stderr, and is always empty when stderr
+    /// is redirected to a file
     #[wasm_bindgen(js_name = getStderrBuffer)]
     pub fn get_stderr_buffer(&mut self) -> Result<Vec<u8>, JsValue> {
         let mut buf = Vec::new();
@@ -316,26 +965,204 @@ impl WASI {
         Ok(buf)
     }
 
-    /// Get the stderr data as a string
-    /// Note: this method flushes the stderr
+    /// Get the stderr data as a string, decoded with `encoding` (`'utf-8'` by
+    /// default, `'utf-8-lossy'` or `'latin1'`)
+    /// Note: this method flushes the stderr, except for a trailing incomplete
+    /// UTF-8 sequence which is kept until the rest of it is written
     #[wasm_bindgen(js_name = getStderrString)]
-    pub fn get_stderr_string(&mut self) -> Result<String, JsValue> {
-        let mut stderr_str = String::new();
-        self.stderr.read_to_string(&mut stderr_str).map_err(|e| {
+    pub fn get_stderr_string(&mut self, encoding: Option<String>) -> Result<String, JsValue> {
+        let encoding = Encoding::from_js(encoding)?;
+        self.stderr.read_string(encoding).map_err(|e| {
             js_sys::Error::new(&format!(
                 "Could not convert the stderr bytes to a String: {}`",
                 e
             ))
-        })?;
-        Ok(stderr_str)
+            .into()
+        })
+    }
+
+    /// Get the stdout and stderr writes in the order they happened
+    /// Note: this method flushes the output log, but not the stdout and stderr
+    /// buffers, which keep their own copy of the output
+    #[wasm_bindgen(js_name = getOutputLog)]
+    pub fn get_output_log(&mut self) -> Result<js_sys::Array, JsValue> {
+        self.output_log
+            .as_ref()
+            .ok_or_else(|| {
+                js_sys::Error::new(
+                    "The output log is disabled, set `outputLog: true` in the WASI config",
+                )
+            })?
+            .take()
+    }
+
+    /// Get the stdout data without flushing it
+    #[wasm_bindgen(js_name = peekStdout)]
+    pub fn peek_stdout(&self) -> Vec<u8> {
+        self.stdout.peek()
+    }
+
+    /// Get at most `max_bytes` of the stdout data
+    /// Note: this method flushes the returned bytes from the stdout
+    #[wasm_bindgen(js_name = readStdout)]
+    pub fn read_stdout(&mut self, max_bytes: usize) -> Vec<u8> {
+        self.stdout.read_up_to(max_bytes)
+    }
+
+    /// Get the stdout data starting at `offset` without flushing it.
+    /// Offsets count every byte ever written to stdout, so readers can
+    /// follow the output with `stdoutOffset`.
+    /// Note: it fails once the bytes at `offset` were flushed, unless a
+    /// cursor keeps them, see `stdoutCursor`
+    #[wasm_bindgen(js_name = readStdoutFrom)]
+    pub fn read_stdout_from(&self, offset: u64) -> Result<Vec<u8>, JsValue> {
+        self.stdout
+            .read_from(offset)
+            .map_err(|e| js_sys::Error::new(&format!("Could not read the stdout: {}", e)).into())
+    }
+
+    /// The offset right after the last byte written to stdout
+    #[wasm_bindgen(getter = stdoutOffset)]
+    pub fn stdout_offset(&self) -> u64 {
+        self.stdout.end_offset()
+    }
+
+    /// A cursor following the stdout from the data that wasn't flushed yet.
+    /// The data stays available to the cursor when other readers flush it,
+    /// until the cursor is freed.
+    #[wasm_bindgen(js_name = stdoutCursor)]
+    pub fn stdout_cursor(&self) -> StdioCursor {
+        self.stdout.cursor()
+    }
+
+    /// Get the stderr data without flushing it
+    #[wasm_bindgen(js_name = peekStderr)]
+    pub fn peek_stderr(&self) -> Vec<u8> {
+        self.stderr.peek()
+    }
+
+    /// Get at most `max_bytes` of the stderr data
+    /// Note: this method flushes the returned bytes from the stderr
+    #[wasm_bindgen(js_name = readStderr)]
+    pub fn read_stderr(&mut self, max_bytes: usize) -> Vec<u8> {
+        self.stderr.read_up_to(max_bytes)
+    }
+
+    /// Get the stderr data starting at `offset` without flushing it.
+    /// Offsets count every byte ever written to stderr, so readers can
+    /// follow the output with `stderrOffset`.
+    /// Note: it fails once the bytes at `offset` were flushed, unless a
+    /// cursor keeps them, see `stderrCursor`
+    #[wasm_bindgen(js_name = readStderrFrom)]
+    pub fn read_stderr_from(&self, offset: u64) -> Result<Vec<u8>, JsValue> {
+        self.stderr
+            .read_from(offset)
+            .map_err(|e| js_sys::Error::new(&format!("Could not read the stderr: {}", e)).into())
+    }
+
+    /// The offset right after the last byte written to stderr
+    #[wasm_bindgen(getter = stderrOffset)]
+    pub fn stderr_offset(&self) -> u64 {
+        self.stderr.end_offset()
+    }
+
+    /// A cursor following the stderr from the data that wasn't flushed yet.
+    /// The data stays available to the cursor when other readers flush it,
+    /// until the cursor is freed.
+    #[wasm_bindgen(js_name = stderrCursor)]
+    pub fn stderr_cursor(&self) -> StdioCursor {
+        self.stderr.cursor()
+    }
+
+    /// A stream of the stdout data, which ends once the start function returns
+    /// Note: the stream flushes the stdout as it reads it
+    #[wasm_bindgen(getter = stdoutStream)]
+    pub fn stdout_stream(&self) -> Result<ReadableStream, JsValue> {
+        let stdout = self.stdout.clone();
+        streams::readable_stream(move || Ok(stdout.read_chunk()))
+    }
+
+    /// A stream of the stderr data, which ends once the start function returns
+    /// Note: the stream flushes the stderr as it reads it
+    #[wasm_bindgen(getter = stderrStream)]
+    pub fn stderr_stream(&self) -> Result<ReadableStream, JsValue> {
+        let stderr = self.stderr.clone();
+        streams::readable_stream(move || Ok(stderr.read_chunk()))
+    }
+
+    /// A stream that writes to the stdin
+    /// Note: the data doesn't go through the line discipline of the `tty`
+    #[wasm_bindgen(getter = stdinStream)]
+    pub fn stdin_stream(&self) -> Result<WritableStream, JsValue> {
+        let mut stdin = self.stdin.clone();
+        streams::writable_stream(
+            move |data| {
+                stdin
+                    .write_all(data)
+                    .map_err(|e| js_sys::Error::new(&format!("Error writing stdin: {}`", e)).into())
+            },
+            || Ok(()),
+        )
+    }
+
+    /// Connect the stdout to the stdin of `next`, like `this | next`, so that
+    /// `next` reads everything this instance writes from now on
+    /// Note: the stdout data that wasn't read yet goes to `next` too. Like in a
+    /// shell, the stdin of `next` is then a pipe rather than its `tty`: the
+    /// data skips the line discipline, and so does `setStdinBuffer`.
+    #[wasm_bindgen(js_name = pipeTo)]
+    pub fn pipe_to(&mut self, next: &mut WASI) -> Result<(), JsValue> {
+        if self.stdout_is_file {
+            return Err(
+                js_sys::Error::new("Can't pipe the stdout, it is redirected to a file").into(),
+            );
+        }
+        if next.stdin_is_file {
+            return Err(js_sys::Error::new(
+                "Can't pipe into the stdin, it is redirected to a file",
+            )
+            .into());
+        }
+        if let Some(tty) = &next.tty {
+            tty.detach_stdin();
+        }
+        suspend::link(&self.waits, &next.waits, next.stdin.clone());
+        self.stdout
+            .forward_to(next.stdin.clone())
+            .map_err(|e| js_sys::Error::new(&format!("Error writing stdin: {}`", e)).into())
     }
 
     /// Set the stdin buffer
+    /// Note: with a `tty`, the input goes through its line discipline first,
+    /// unless another instance pipes into the stdin
     #[wasm_bindgen(js_name = setStdinBuffer)]
     pub fn set_stdin_buffer(&mut self, buf: &[u8]) -> Result<(), JsValue> {
-        self.stdin
-            .write_all(buf)
-            .map_err(|e| js_sys::Error::new(&format!("Error writing stdin: {}`", e)))?;
+        // The echo is output like any other, so it is logged too
+        let mut echo: Box<dyn Write> = match &self.output_log {
+            Some(log) => Box::new(LoggedOutput::new(
+                OutputStream::Stdout,
+                Box::new(self.stdout.clone()),
+                log.clone(),
+            )),
+            None => Box::new(self.stdout.clone()),
+        };
+        match &mut self.tty {
+            Some(tty) => tty.input(buf, &mut self.stdin, &mut echo),
+            None => self.stdin.write_all(buf),
+        }
+        .map_err(|e| js_sys::Error::new(&format!("Error writing stdin: {}`", e)))?;
+        Ok(())
+    }
+
+    /// Resize the emulated terminal
+    #[wasm_bindgen(js_name = resizeTty)]
+    pub fn resize_tty(&mut self, columns: u32, rows: u32) -> Result<(), JsValue> {
+        self.tty
+            .as_ref()
+            .ok_or_else(|| {
+                js_sys::Error::new("There is no terminal, set `tty` in the WASI config")
+            })?
+            .resize(columns, rows);
         Ok(())
     }
 
@@ -345,3 +1172,25 @@ impl WASI {
         self.set_stdin_buffer(input.as_bytes())
     }
 }
+
+/// Run the instantiated `stages` with the stdout of each piped to the stdin
+/// of the next like `a | b | c`, and return a promise of their exit codes
+///
+/// Note: the stages start with `startAsync`, so with JSPI each one waits for
+/// the input of the previous stage and for the next to read its output, and
+/// they stream to each other. Without JSPI a stage runs until it exits before
+/// the next starts, and reads the end of its stdin once it read everything
+/// the previous stages wrote.
+#[wasm_bindgen]
+pub fn pipeline(stages: js_sys::Array) -> Result<js_sys::Promise, JsValue> {
+    let stages = stages.to_vec();
+    // The stages are used through their JS wrappers, which borrow them
+    for pair in stages.windows(2) {
+        streams::call_method(&pair[0], "pipeTo", &[pair[1].clone()])?;
+    }
+    let codes = stages
+        .iter()
+        .map(|stage| streams::call_method(stage, "startAsync", &[]))
+        .collect::<Result<js_sys::Array, JsValue>>()?;
+    Ok(js_sys::Promise::all(&codes))
+}
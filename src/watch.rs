@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+use crate::js_handle::JsHandle;
+
+/// The ids given to the watchers
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// A change made to a filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FsEvent {
+    Create(PathBuf),
+    Modify(PathBuf),
+    Remove(PathBuf),
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+impl FsEvent {
+    fn to_object(&self) -> Result<js_sys::Object, JsValue> {
+        let (kind, path, from) = match self {
+            FsEvent::Create(path) => ("create", path, None),
+            FsEvent::Modify(path) => ("modify", path, None),
+            FsEvent::Remove(path) => ("remove", path, None),
+            FsEvent::Rename { from, to } => ("rename", to, Some(from)),
+        };
+        let event = js_sys::Object::new();
+        js_sys::Reflect::set(&event, &"type".into(), &kind.into())?;
+        js_sys::Reflect::set(&event, &"path".into(), &path.to_str().into())?;
+        if let Some(from) = from {
+            js_sys::Reflect::set(&event, &"from".into(), &from.to_str().into())?;
+        }
+        Ok(event)
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            FsEvent::Create(path) | FsEvent::Modify(path) | FsEvent::Remove(path) => {
+                vec![path.as_path()]
+            }
+            FsEvent::Rename { from, to } => vec![from.as_path(), to.as_path()],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Watcher {
+    id: u32,
+    path: PathBuf,
+    recursive: bool,
+    callback: js_sys::Function,
+}
+
+impl Watcher {
+    fn matches(&self, event: &FsEvent) -> bool {
+        event.paths().into_iter().any(|path| {
+            if self.recursive {
+                path.starts_with(&self.path)
+            } else {
+                path == self.path || path.parent() == Some(self.path.as_path())
+            }
+        })
+    }
+}
+
+/// The watchers of a filesystem, shared by all of its clones
+#[derive(Debug, Clone)]
+pub(crate) struct Watchers {
+    handle: Arc<JsHandle<Vec<Watcher>>>,
+}
+
+impl Default for Watchers {
+    fn default() -> Self {
+        Watchers {
+            handle: Arc::new(JsHandle::new(vec![])),
+        }
+    }
+}
+
+impl Watchers {
+    /// Calls `callback` with the events under `path`, which must be resolved
+    pub(crate) fn watch(
+        &self,
+        path: PathBuf,
+        recursive: bool,
+        callback: js_sys::Function,
+    ) -> FSWatcher {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.handle.with(|watchers| {
+            watchers.push(Watcher {
+                id,
+                path,
+                recursive,
+                callback,
+            })
+        });
+        FSWatcher {
+            watchers: self.clone(),
+            id,
+        }
+    }
+
+    /// Sends `event` to the watchers it concerns
+    pub(crate) fn notify(&self, event: FsEvent) {
+        // The callbacks are collected first, so they can add or close watchers
+        let callbacks = self
+            .handle
+            .with(|watchers| {
+                watchers
+                    .iter()
+                    .filter(|watcher| watcher.matches(&event))
+                    .map(|watcher| watcher.callback.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if callbacks.is_empty() {
+            return;
+        }
+        let event = match event.to_object() {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            // The change already happened, so a throwing callback can't undo
+            // it and there is nobody to report the error to
+            let _ = callback.call1(&JsValue::NULL, &event);
+        }
+    }
+}
+
+/// A watcher created with `MemFS.watch`
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct FSWatcher {
+    watchers: Watchers,
+    id: u32,
+}
+
+#[wasm_bindgen]
+impl FSWatcher {
+    /// Stop receiving events
+    pub fn close(&self) {
+        self.watchers
+            .handle
+            .with(|watchers| watchers.retain(|watcher| watcher.id != self.id));
+    }
+}
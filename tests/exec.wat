@@ -0,0 +1,17 @@
+(module
+    ;; Replaces itself with the command "fail" with WASIX `proc_exec`, and
+    ;; exits with 127 when it can't
+    (import "wasix_32v1" "proc_exec" (func $proc_exec (param i32 i32 i32 i32)))
+    (import "wasix_32v1" "proc_exit" (func $proc_exit (param i32)))
+
+    ;; 16: the name, 32: the arguments, separated by newlines
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 16) "fail")
+    (data (i32.const 32) "x")
+
+    (func $main (export "_start")
+        (call $proc_exec (i32.const 16) (i32.const 4) (i32.const 32) (i32.const 1))
+        (call $proc_exit (i32.const 127)))
+)
@@ -0,0 +1,10 @@
+(module
+    ;; Exits with 3
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (func $main (export "_start")
+        (call $proc_exit (i32.const 3)))
+)
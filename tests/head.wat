@@ -0,0 +1,17 @@
+(module
+    ;; Reads at most 4096 bytes from stdin once, and writes them to stdout
+    (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The iovec of the 4096 bytes at 64
+    (data (i32.const 0) "\40\00\00\00\00\10\00\00")
+
+    (func $main (export "_start")
+        (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 16)))
+        ;; Only the bytes read are written
+        (i32.store (i32.const 4) (i32.load (i32.const 16)))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20))))
+)
@@ -1,5 +1,5 @@
 const fs = require('fs');
-const { init, WASI, MemFS } = require('../dist/Library.cjs.js');
+const { init, WASI, MemFS, HttpFS, Network, Shell, pipeline } = require('../dist/Library.cjs.js');
 
 
 async function initWasi(moduleBytes, config, imports = {}) {
@@ -9,6 +9,42 @@ async function initWasi(moduleBytes, config, imports = {}) {
   return wasi;
 }
 
+// Instantiates tests/write.wasm, returning the instance and a function that
+// makes the guest write `bytes` to `fd`
+async function initWriter(config) {
+  let wasi = new WASI(config);
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/write.wasm'));
+  let instance = await wasi.instantiate(module, {});
+  let write = (fd, bytes) => {
+    new Uint8Array(instance.exports.memory.buffer).set(bytes, 16);
+    return instance.exports.write(fd, 16, bytes.length);
+  };
+  return [wasi, write];
+}
+
+// Instantiates tests/paths.wasm, returning its exports, a function that
+// writes a path at `offset` giving its pointer and length, and the fd of the
+// preopen `name`, found like wasi-libc does with `fd_prestat_get`
+async function initPaths(config, name) {
+  let wasi = new WASI(config);
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/paths.wasm'));
+  let exports = (await wasi.instantiate(module, {})).exports;
+  let write = (offset, path) => {
+    let bytes = new TextEncoder().encode(path);
+    new Uint8Array(exports.memory.buffer).set(bytes, offset);
+    return [offset, bytes.length];
+  };
+  let read = (offset, length) =>
+    new TextDecoder().decode(new Uint8Array(exports.memory.buffer, offset, length));
+  for (let fd = 3; exports.prestat(fd) === 0; fd++) {
+    let length = new Uint32Array(exports.memory.buffer, 4, 1)[0];
+    if (exports.dir_name(fd, 256, length) === 0 && read(256, length) === name) {
+      return [exports, write, read, fd];
+    }
+  }
+  throw new Error(`No preopen named ${name}`);
+}
+
 beforeAll(async () => {
   await init();
 });
@@ -191,3 +227,1320 @@ test('get imports', async() => {
   //   "clock_time_get"
   // ]);
 })
+
+test('missing imports are reported', async() => {
+  let moduleBytes = fs.readFileSync(__dirname + '/test.wasm');
+  const module = await WebAssembly.compile(moduleBytes);
+  let wasi = new WASI({});
+  expect(() => wasi.instantiate(module, {})).toThrow("`module.external`");
+});
+
+test('missing imports can be stubbed with a trap', async() => {
+  let moduleBytes = fs.readFileSync(__dirname + '/test.wasm');
+  let wasi = await initWasi(moduleBytes, {stubMissingImports: 'trap'});
+  expect(() => wasi.start()).toThrow("Called the unresolved import `module.external`");
+});
+
+test('missing imports can be stubbed with zero', async() => {
+  let moduleBytes = fs.readFileSync(__dirname + '/test.wasm');
+  let wasi = await initWasi(moduleBytes, {stubMissingImports: 'zero'});
+  expect(wasi.start()).toBe(0);
+});
+
+test('zero stubs match the result types', async() => {
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/stubs.wasm'));
+  let wasi = new WASI({stubMissingImports: 'zero'});
+  let instance = await wasi.instantiate(module, {});
+  expect(instance.exports.big()).toBe(0n);
+  expect(instance.exports.small()).toBe(0);
+});
+
+test('getImports stubs the missing functions', async() => {
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/stubs.wasm'));
+  let wasi = new WASI({stubMissingImports: 'zero'});
+  let imports = wasi.getImports(module);
+  imports.env.small = x => x + 1;
+  let instance = await WebAssembly.instantiate(module, imports);
+  expect(instance.exports.big()).toBe(0n);
+  expect(instance.exports.small()).toBe(8);
+
+  let trapping = new WASI({stubMissingImports: 'trap'});
+  instance = await WebAssembly.instantiate(module, trapping.getImports(module));
+  expect(() => instance.exports.big()).toThrow("Called the unresolved import `env.big`");
+});
+
+test('stdout can be redirected to a file', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/logs');
+  let contents = fs.readFileSync(__dirname + '/demo.wasm');
+  let wasi = await initWasi(contents, {fs: wfs, stdout: {file: '/logs/out.txt'}});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe("");
+  expect(wfs.open('/logs/out.txt', {read: true}).readString()).toBe("hello world\n");
+
+  let appended = await initWasi(contents, {fs: wfs, stdout: {file: '/logs/out.txt', append: true}});
+  appended.start();
+  expect(wfs.open('/logs/out.txt', {read: true}).readString()).toBe("hello world\nhello world\n");
+});
+
+test('stdin can be read from a file', async() => {
+  let wfs = new MemFS();
+  let input = wfs.open('/in.txt', {write: true, create: true});
+  input.writeString("Hello World!");
+  let contents = fs.readFileSync(__dirname + '/pipe_reverse.wasm');
+  let wasi = await initWasi(contents, {fs: wfs, stdin: {file: '/in.txt'}});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe("!dlroW olleH\n");
+});
+
+test('pipeline connects stdout to the next stdin', async() => {
+  let hello = await initWasi(fs.readFileSync(__dirname + '/demo.wasm'), {});
+  let reverse = await initWasi(fs.readFileSync(__dirname + '/pipe_reverse.wasm'), {});
+  expect(await pipeline([hello, reverse])).toEqual([0, 0]);
+  expect(hello.getStdoutString()).toBe("");
+  expect(reverse.getStdoutString()).toBe("\ndlrow olleh\n");
+});
+
+// The stages only wait for each other with JSPI
+(typeof WebAssembly.Suspending === 'function' ? test : test.skip)('pipeline streams the stages', async() => {
+  let yes = await initWasi(fs.readFileSync(__dirname + '/yes.wasm'), {});
+  let head = await initWasi(fs.readFileSync(__dirname + '/head.wasm'), {});
+  // `yes` waits for `head` to read before writing more than the pipe holds,
+  // and its writes fail once `head` exited
+  expect(await pipeline([yes, head])).toEqual([1, 0]);
+  expect(head.getStdoutString()).toBe("y\n".repeat(2048));
+});
+
+test('startAsync without a pipe runs like start', async() => {
+  let wasi = await initWasi(fs.readFileSync(__dirname + '/demo.wasm'), {});
+  expect(await wasi.startAsync()).toBe(0);
+  expect(wasi.getStdoutString()).toBe("hello world\n");
+});
+
+test('piping into a tty makes stdin a pipe', async() => {
+  let hello = await initWasi(fs.readFileSync(__dirname + '/demo.wasm'), {});
+  let reverse = await initWasi(fs.readFileSync(__dirname + '/pipe_reverse.wasm'), {tty: {}});
+  hello.pipeTo(reverse);
+  // Without the line discipline, nothing is echoed or erased
+  reverse.setStdinString("x\x7f");
+  expect(reverse.getStdoutString()).toBe("");
+  expect(await pipeline([hello, reverse])).toEqual([0, 0]);
+  expect(reverse.getStdoutString()).toContain("olleh\x7fx");
+
+  let tty = new WASI({tty: {}});
+  let guest = (await tty.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/tty.wasm')), {})).exports;
+  new WASI({}).pipeTo(tty);
+  expect([guest.isatty(0), guest.isatty(1)]).toEqual([0, 1]);
+});
+
+test('stdin redirected to a file can not be piped into', async() => {
+  let wfs = new MemFS();
+  wfs.open('/in.txt', {write: true, create: true});
+  let hello = await initWasi(fs.readFileSync(__dirname + '/demo.wasm'), {});
+  let reverse = await initWasi(fs.readFileSync(__dirname + '/pipe_reverse.wasm'), {fs: wfs, stdin: {file: '/in.txt'}});
+  expect(() => hello.pipeTo(reverse)).toThrow("redirected to a file");
+});
+
+test('output log records writes in order', async() => {
+  let contents = fs.readFileSync(__dirname + '/demo.wasm');
+  let wasi = await initWasi(contents, {outputLog: true});
+  wasi.start();
+  let log = wasi.getOutputLog();
+  expect(log.map(e => e.stream)).toEqual(['stdout']);
+  expect(new TextDecoder().decode(log[0].data)).toBe("hello world\n");
+  expect(wasi.getOutputLog()).toEqual([]);
+  expect(wasi.getStdoutString()).toBe("hello world\n");
+});
+
+test('output log keeps stdout and stderr interleaved', async() => {
+  let [wasi, write] = await initWriter({outputLog: true});
+  let encoder = new TextEncoder();
+  for (let [fd, text] of [[1, "out 1\n"], [2, "err 1\n"], [2, "err 2\n"], [1, "out 2\n"]]) {
+    expect(write(fd, encoder.encode(text))).toBe(0);
+  }
+  let decoder = new TextDecoder();
+  expect(wasi.getOutputLog().map(e => [e.stream, decoder.decode(e.data)])).toEqual([
+    ['stdout', "out 1\n"],
+    ['stderr', "err 1\nerr 2\n"],
+    ['stdout', "out 2\n"],
+  ]);
+  expect(wasi.getStdoutString()).toBe("out 1\nout 2\n");
+  expect(wasi.getStderrString()).toBe("err 1\nerr 2\n");
+});
+
+test('stderr can be merged into stdout', async() => {
+  let [wasi, write] = await initWriter({mergeStderr: true, outputLog: true});
+  let encoder = new TextEncoder();
+  for (let [fd, text] of [[1, "out 1\n"], [2, "err 1\n"], [1, "out 2\n"]]) {
+    write(fd, encoder.encode(text));
+  }
+  expect(wasi.getStdoutString()).toBe("out 1\nerr 1\nout 2\n");
+  expect(wasi.getStderrString()).toBe("");
+  // The log still tells the streams apart
+  expect(wasi.getOutputLog().map(e => e.stream)).toEqual(['stdout', 'stderr', 'stdout']);
+
+  let wfs = new MemFS();
+  expect(() => new WASI({fs: wfs, mergeStderr: true, stdout: {file: '/out.txt'}})).toThrow("`mergeStderr` can't be used");
+});
+
+test('output log records the tty echo', async() => {
+  let contents = fs.readFileSync(__dirname + '/pipe_reverse.wasm');
+  let wasi = await initWasi(contents, {tty: {}, outputLog: true});
+  wasi.setStdinString("hi\n");
+  let log = wasi.getOutputLog();
+  expect(log.map(e => e.stream)).toEqual(['stdout']);
+  expect(new TextDecoder().decode(log[0].data)).toBe("hi\r\n");
+});
+
+test('output log must be enabled', async() => {
+  let wasi = new WASI({});
+  expect(() => wasi.getOutputLog()).toThrow("The output log is disabled");
+});
+
+test('stdout can be peeked and read incrementally', async() => {
+  let contents = fs.readFileSync(__dirname + '/demo.wasm');
+  let wasi = await initWasi(contents, {});
+  expect(wasi.stdoutOffset).toBe(0n);
+  wasi.start();
+  let decoder = new TextDecoder();
+  expect(wasi.stdoutOffset).toBe(12n);
+  expect(decoder.decode(wasi.peekStdout())).toBe("hello world\n");
+  expect(decoder.decode(wasi.readStdoutFrom(6n))).toBe("world\n");
+  expect(decoder.decode(wasi.readStdout(6))).toBe("hello ");
+  expect(() => wasi.readStdoutFrom(0n)).toThrow("outside of the buffered output");
+  expect(decoder.decode(wasi.readStdoutFrom(6n))).toBe("world\n");
+  expect(wasi.getStdoutString()).toBe("world\n");
+  expect(wasi.stdoutOffset).toBe(12n);
+});
+
+test('stdio cursors keep the output other readers flush', async() => {
+  let [wasi, write] = await initWriter({});
+  let encoder = new TextEncoder();
+  let decoder = new TextDecoder();
+  write(1, encoder.encode("hello "));
+  let cursor = wasi.stdoutCursor();
+  let late = wasi.stdoutCursor();
+  expect(cursor.offset).toBe(0n);
+  expect(decoder.decode(cursor.read())).toBe("hello ");
+  write(1, encoder.encode("world\n"));
+  expect(wasi.getStdoutString()).toBe("hello world\n");
+  // The flushed bytes are kept for the cursors that didn't read them
+  expect(decoder.decode(wasi.readStdoutFrom(6n))).toBe("world\n");
+  expect(decoder.decode(cursor.read())).toBe("world\n");
+  expect(decoder.decode(cursor.read())).toBe("");
+  expect(cursor.offset).toBe(12n);
+  expect(decoder.decode(late.read())).toBe("hello world\n");
+  expect(() => wasi.readStdoutFrom(0n)).toThrow("were consumed");
+  late.free();
+  write(1, encoder.encode("again\n"));
+  expect(wasi.readStdout(2).length).toBe(2);
+  expect(decoder.decode(cursor.read())).toBe("again\n");
+  cursor.free();
+  expect(() => wasi.readStdoutFrom(12n)).toThrow("outside of the buffered output");
+  expect(wasi.getStdoutString()).toBe("ain\n");
+});
+
+test('stdio strings decode the bytes written', async() => {
+  let [wasi, write] = await initWriter({});
+  // An incomplete UTF-8 sequence is kept until the rest of it arrives
+  expect(write(1, [0x63, 0x61, 0x66, 0xc3])).toBe(0);
+  expect(wasi.getStdoutString()).toBe("caf");
+  write(1, [0xa9]);
+  expect(wasi.getStdoutString()).toBe("é");
+  write(2, [0xf0, 0x9f]);
+  expect(wasi.getStderrString('utf-8-lossy')).toBe("");
+  write(2, [0x98, 0x80]);
+  expect(wasi.getStderrString('utf-8-lossy')).toBe("😀");
+
+  // Invalid bytes fail in UTF-8, and are replaced when lossy
+  write(1, [0x61, 0xff, 0x62]);
+  expect(() => wasi.getStdoutString()).toThrow("Could not convert the stdout bytes to a String");
+  expect(wasi.getStdoutString('utf-8-lossy')).toBe("a\ufffdb");
+
+  write(1, [0xe9, 0x74, 0xe9]);
+  expect(wasi.getStdoutString('latin1')).toBe("été");
+});
+
+test('stdout string encodings', async() => {
+  let contents = fs.readFileSync(__dirname + '/demo.wasm');
+  let wasi = await initWasi(contents, {});
+  wasi.start();
+  expect(() => wasi.getStdoutString('utf-16')).toThrow("Unknown encoding `utf-16`");
+  expect(wasi.getStdoutString('latin1')).toBe("hello world\n");
+  wasi.start();
+  expect(wasi.getStdoutString('utf-8-lossy')).toBe("hello world\n");
+});
+
+test('tty applies the line discipline to stdin', async() => {
+  let contents = fs.readFileSync(__dirname + '/pipe_reverse.wasm');
+  let resize = null;
+  let wasi = await initWasi(contents, {tty: {columns: 100, rows: 30, onResize: (listener) => { resize = listener; }}});
+  wasi.setStdinString("Hellp\x7fo");
+  expect(wasi.getStdoutString()).toBe("Hellp\b \bo");
+  wasi.setStdinString("\r");
+  expect(wasi.getStdoutString()).toBe("\r\n");
+  expect(typeof resize).toBe('function');
+  resize({cols: 120, rows: 40});
+  wasi.resizeTty(90, 20);
+  wasi.start();
+  expect(wasi.getStdoutString()).toContain("olleH");
+});
+
+test('tty presents stdio as a terminal', async() => {
+  const CHARACTER_DEVICE = 2;
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/tty.wasm'));
+  let resize = null;
+  let wasi = new WASI({tty: {columns: 100, rows: 30, onResize: (listener) => { resize = listener; }}});
+  let guest = (await wasi.instantiate(module, {})).exports;
+  for (let fd of [0, 1, 2]) {
+    expect(guest.filetype(fd)).toBe(CHARACTER_DEVICE);
+    expect(guest.isatty(fd)).toBe(1);
+  }
+  expect([guest.tty(0), guest.tty(4)]).toEqual([100, 30]);
+  expect(typeof resize).toBe('function');
+  resize({cols: 120, rows: 40});
+  expect([guest.tty(0), guest.tty(4)]).toEqual([120, 40]);
+  wasi.resizeTty(90, 20);
+  expect([guest.tty(0), guest.tty(4)]).toEqual([90, 20]);
+  // The size in pixels saturates
+  wasi.resizeTty(0x20000000, 0x10000000);
+  expect([guest.tty(0), guest.tty(8), guest.tty(12)]).toEqual([0x20000000, -1, -1]);
+});
+
+test('tty only echoes when stdout is the terminal', async() => {
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/tty.wasm'));
+  let wfs = new MemFS();
+  let wasi = new WASI({fs: wfs, tty: {}, stdout: {file: '/out.txt'}});
+  let guest = (await wasi.instantiate(module, {})).exports;
+  expect([guest.isatty(0), guest.isatty(1), guest.isatty(2)]).toEqual([1, 0, 1]);
+  wasi.setStdinString("hi\n");
+  expect(wasi.getStdoutString()).toBe("");
+  expect(wfs.open('/out.txt', {read: true}).readString()).toBe("");
+});
+
+test('resizing without a tty fails', async() => {
+  let wasi = new WASI({});
+  expect(() => wasi.resizeTty(80, 24)).toThrow("There is no terminal");
+});
+
+test('virtual files support positional io', async() => {
+  let wfs = new MemFS();
+  let file = wfs.open('/file', {read: true, write: true, create: true});
+  file.writeString("0123456789");
+  let decoder = new TextDecoder();
+  expect(file.seek(2n)).toBe(2n);
+  expect(decoder.decode(file.read(3))).toBe("234");
+  expect(file.seek(-2, 'end')).toBe(8n);
+  expect(decoder.decode(file.read(5))).toBe("89");
+  expect(file.seek(-4, 'current')).toBe(6n);
+  expect(decoder.decode(file.readAt(1n, 2))).toBe("12");
+  expect(file.writeAt(0n, new TextEncoder().encode("ab"))).toBe(2);
+  expect(decoder.decode(file.read())).toBe("6789");
+  file.seek(0);
+  expect(file.readString()).toBe("ab23456789");
+  expect(() => file.seek(0, 'middle')).toThrow("Unknown seek origin `middle`");
+});
+
+// Streams are only global since Node 18, before they come from `stream/web`
+if (typeof ReadableStream === 'undefined') {
+  Object.assign(globalThis, require('stream/web'));
+}
+
+async function readAll(readable) {
+  let reader = readable.getReader();
+  let chunks = [];
+  for (let chunk = await reader.read(); !chunk.done; chunk = await reader.read()) {
+    chunks.push(...chunk.value);
+  }
+  return new TextDecoder().decode(new Uint8Array(chunks));
+}
+
+test('virtual files can be streamed', async() => {
+  let wfs = new MemFS();
+  let writer = wfs.open('/file', {write: true, create: true}).writable().getWriter();
+  await writer.write(new TextEncoder().encode("streamed "));
+  await writer.write(new TextEncoder().encode("contents"));
+  await writer.close();
+  let readable = wfs.open('/file', {read: true}).readable();
+  expect(await readAll(readable)).toBe("streamed contents");
+});
+
+test('stdio can be streamed', async() => {
+  let contents = fs.readFileSync(__dirname + '/pipe_reverse.wasm');
+  let wasi = await initWasi(contents, {});
+  let writer = wasi.stdinStream.getWriter();
+  await writer.write(new TextEncoder().encode("Hello World!"));
+  await writer.close();
+  let stdout = readAll(wasi.stdoutStream);
+  wasi.start();
+  expect(await stdout).toBe("!dlroW olleH\n");
+});
+
+test('stdio streams follow every start', async() => {
+  let contents = fs.readFileSync(__dirname + '/demo.wasm');
+  let wasi = await initWasi(contents, {});
+  wasi.start();
+  expect(await readAll(wasi.stdoutStream)).toBe("hello world\n");
+  // The stream waits for the next run instead of ending right away
+  let stdout = readAll(wasi.stdoutStream);
+  await new Promise(resolve => setTimeout(resolve, 20));
+  wasi.start();
+  expect(await stdout).toBe("hello world\n");
+});
+
+test('memfs symlinks', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/data');
+  wfs.open('/data/file', {write: true, create: true}).writeString("contents");
+  wfs.symlink('/data', '/link');
+  wfs.symlink('file', '/data/relative');
+  expect(wfs.readlink('/link')).toBe('/data');
+  expect(wfs.readlink('/link/relative')).toBe('file');
+  expect(() => wfs.readlink('/data/file')).toThrow("is not a symlink");
+  expect(wfs.open('/link/relative', {read: true}).readString()).toBe("contents");
+  expect(wfs.metadata('/link').filetype.dir).toBe(true);
+  expect(wfs.symlinkMetadata('/link').filetype.symlink).toBe(true);
+  expect(wfs.readDir('/').map(e => [e.path, e.metadata.filetype.symlink])).toEqual([['/data', false], ['/link', true]]);
+  wfs.rename('/data', '/moved');
+  expect(() => wfs.open('/link/file', {read: true})).toThrow();
+  expect(wfs.readlink('/moved/relative')).toBe('file');
+  wfs.removeFile('/link');
+  expect(wfs.readDir('/').map(e => e.path)).toEqual(['/moved']);
+});
+
+test('memfs hard links', async() => {
+  let wfs = new MemFS();
+  wfs.open('/file', {write: true, create: true}).writeString("contents");
+  wfs.link('/file', '/other');
+  wfs.open('/other', {write: true, append: true}).writeString(" appended");
+  expect(wfs.open('/file', {read: true}).readString()).toBe("contents appended");
+  wfs.removeFile('/file');
+  expect(wfs.open('/other', {read: true}).readString()).toBe("contents appended");
+  expect(wfs.readDir('/').map(e => e.path)).toEqual(['/other']);
+});
+
+test('memfs replaces symlinks like files', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/dir');
+  wfs.open('/file', {write: true, create: true}).writeString("contents");
+  wfs.symlink('/file', '/link');
+  wfs.symlink('/file', '/other');
+  wfs.rename('/other', '/link');
+  expect(wfs.readlink('/link')).toBe('/file');
+  expect(() => wfs.readlink('/other')).toThrow();
+  wfs.open('/new', {write: true, create: true}).writeString("new");
+  wfs.rename('/new', '/link');
+  expect(() => wfs.readlink('/link')).toThrow("is not a symlink");
+  expect(wfs.symlinkMetadata('/link').filetype.symlink).toBe(false);
+  expect(wfs.open('/link', {read: true}).readString()).toBe("new");
+  expect(wfs.open('/file', {read: true}).readString()).toBe("contents");
+  wfs.symlink('/file', '/other');
+  expect(() => wfs.rename('/dir', '/other')).toThrow();
+  wfs.removeFile('/other');
+  wfs.open('/other', {write: true, create: true});
+  expect(wfs.symlinkMetadata('/other').filetype.symlink).toBe(false);
+});
+
+test('guest symlinks are memfs symlinks', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/data');
+  wfs.open('/data/file', {write: true, create: true}).writeString("contents");
+  let [guest, write, read, fd] = await initPaths({fs: wfs, preopens: {'/data': '/data'}}, '/data');
+  const EINVAL = 28;
+
+  expect(guest.symlink(...write(64, 'file'), fd, ...write(128, 'link'))).toBe(0);
+  expect(wfs.readlink('/data/link')).toBe('file');
+  expect(wfs.open('/data/link', {read: true}).readString()).toBe("contents");
+
+  wfs.symlink('/data/file', '/data/host');
+  expect(guest.readlink(fd, ...write(64, 'host'), 128, 64)).toBe(0);
+  let length = new Uint32Array(guest.memory.buffer, 8, 1)[0];
+  expect(read(128, length)).toBe('/data/file');
+  // The target is truncated to the buffer
+  expect(guest.readlink(fd, ...write(64, 'host'), 128, 5)).toBe(0);
+  expect(new Uint32Array(guest.memory.buffer, 8, 1)[0]).toBe(5);
+  expect(guest.readlink(fd, ...write(64, 'file'), 128, 64)).toBe(EINVAL);
+});
+
+test('guests see through memfs symlinks', async () => {
+  let wfs = new MemFS();
+  wfs.createDir('/a');
+  wfs.symlink('/a', '/b');
+  let contents = fs.readFileSync(__dirname + '/mapdir.wasm');
+  let wasi = await initWasi(contents, {fs: wfs});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe(`"./a"\n"./b"\n`);
+});
+
+test('wasi cwd', async () => {
+  let wfs = new MemFS();
+  wfs.createDir('/work');
+  wfs.createDir('/work/src');
+  wfs.open('/work/notes', {write: true, create: true});
+  wfs.createDir('/other');
+  let contents = fs.readFileSync(__dirname + '/mapdir.wasm');
+  let wasi = await initWasi(contents, {fs: wfs, cwd: '/work/'});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe(`"./notes"\n"./src"\n`);
+
+  expect(() => new WASI({fs: wfs, cwd: '/missing'})).toThrow();
+  expect(() => new WASI({fs: wfs, cwd: '/work/notes'})).toThrow();
+  expect(() => new WASI({fs: wfs, cwd: 'work'})).toThrow();
+});
+
+test('preopens confine the guest', async () => {
+  let wfs = new MemFS();
+  wfs.open('/secret', {write: true, create: true}).writeString("password");
+  wfs.createDir('/sandbox');
+  wfs.open('/sandbox/secret', {write: true, create: true}).writeString("password");
+  wfs.createDir('/sandbox/data');
+  wfs.createDir('/sandbox/data/sub');
+  wfs.open('/sandbox/data/file.txt', {write: true, create: true});
+  let [guest, write, read, fd] = await initPaths({fs: wfs, preopens: {'/data': '/sandbox/data'}}, '/data');
+  const ENOTCAPABLE = 76;
+
+  for (let path of ['../../secret', '../secret', 'sub/../../secret', '/secret']) {
+    expect(guest.open(fd, ...write(64, path))).toBe(ENOTCAPABLE);
+  }
+  expect(guest.open(fd, ...write(64, 'sub/../file.txt'))).toBe(0);
+
+  expect(guest.symlink(...write(64, '../../secret'), fd, ...write(128, 'link'))).toBe(ENOTCAPABLE);
+  expect(guest.symlink(...write(64, '../file.txt'), fd, ...write(128, 'link'))).toBe(ENOTCAPABLE);
+  expect(guest.symlink(...write(64, '../file.txt'), fd, ...write(128, 'sub/link'))).toBe(0);
+  expect(guest.open(fd, ...write(64, 'sub/link'))).toBe(0);
+});
+
+test('preopens confine the guest through symlinks', async () => {
+  let wfs = new MemFS();
+  wfs.open('/secret', {write: true, create: true}).writeString("password");
+  wfs.createDir('/sandbox');
+  wfs.open('/sandbox/secret', {write: true, create: true}).writeString("password");
+  wfs.createDir('/sandbox/data');
+  wfs.createDir('/sandbox/data/sub');
+  wfs.open('/sandbox/data/file.txt', {write: true, create: true});
+  let [guest, write, read, fd] = await initPaths({fs: wfs, preopens: {'/data': '/sandbox/data'}}, '/data');
+  const ENOTCAPABLE = 76;
+
+  // Links made by the guest stay in the preopen once resolved
+  expect(guest.symlink(...write(64, '..'), fd, ...write(128, 'sub/x'))).toBe(0);
+  expect(guest.open(fd, ...write(64, 'sub/x/file.txt'))).toBe(0);
+  expect(guest.open(fd, ...write(64, 'sub/x/../secret'))).not.toBe(0);
+  expect(guest.symlink(...write(64, 'sub/x/..'), fd, ...write(128, 'y'))).toBe(0);
+  expect(guest.open(fd, ...write(64, 'y/secret'))).toBe(ENOTCAPABLE);
+  expect(guest.open(fd, ...write(64, 'y/sandbox/secret'))).toBe(ENOTCAPABLE);
+
+  // And so do the links made by the host
+  wfs.symlink('/', '/sandbox/data/root');
+  wfs.symlink('../../secret', '/sandbox/data/relative');
+  wfs.symlink('/file.txt', '/sandbox/data/inside');
+  expect(guest.open(fd, ...write(64, 'root/../secret'))).toBe(ENOTCAPABLE);
+  expect(guest.open(fd, ...write(64, 'relative'))).toBe(ENOTCAPABLE);
+  expect(guest.open(fd, ...write(64, 'inside'))).toBe(0);
+
+  // Absolute targets are relative to the preopen
+  expect(guest.open(fd, ...write(64, 'root/file.txt'))).toBe(0);
+  expect(guest.symlink(...write(64, '/sub'), fd, ...write(128, 'absolute'))).toBe(0);
+  expect(guest.open(fd, ...write(64, 'absolute/../file.txt'))).toBe(0);
+  expect(guest.symlink(...write(64, '/../secret'), fd, ...write(128, 'above'))).toBe(ENOTCAPABLE);
+});
+
+test('preopens confine the guest to the preopen of each fd', async () => {
+  let wfs = new MemFS();
+  wfs.createDir('/sandbox');
+  wfs.createDir('/sandbox/a');
+  wfs.createDir('/sandbox/b');
+  wfs.open('/sandbox/b/file.txt', {write: true, create: true});
+  wfs.symlink('../b', '/sandbox/a/sibling');
+  let [guest, write, read, a] = await initPaths({fs: wfs, preopens: {'/a': '/sandbox/a', '/b': '/sandbox/b'}}, '/a');
+  let b = a + 1;
+  expect(guest.prestat(b)).toBe(0);
+  expect(guest.dir_name(b, 256, 2)).toBe(0);
+  expect(read(256, 2)).toBe('/b');
+  const ENOTCAPABLE = 76;
+
+  expect(guest.open(b, ...write(64, 'file.txt'))).toBe(0);
+  expect(guest.open(a, ...write(64, 'sibling/file.txt'))).toBe(ENOTCAPABLE);
+  expect(guest.open(a, ...write(64, 'sibling'))).toBe(ENOTCAPABLE);
+});
+
+test('memfs permissions', async() => {
+  let wfs = new MemFS({uid: 1000, gid: 1000, enforcePermissions: true});
+  wfs.createDir('/bin');
+  wfs.open('/bin/tool', {write: true, create: true}).writeString("#!");
+  expect(wfs.metadata('/bin').mode).toBe(0o755);
+  expect(wfs.metadata('/bin/tool').mode).toBe(0o644);
+  expect(wfs.metadata('/bin/tool').uid).toBe(1000);
+  expect(wfs.access('/bin/tool', 1)).toBe(false);
+  wfs.chmod('/bin/tool', 0o755);
+  expect(wfs.access('/bin/tool', 1)).toBe(true);
+  expect(wfs.readDir('/bin')[0].metadata.mode).toBe(0o755);
+
+  wfs.chmod('/bin/tool', 0o444);
+  expect(wfs.access('/bin/tool', 2)).toBe(false);
+  expect(() => wfs.open('/bin/tool', {write: true})).toThrow();
+  wfs.chmod('/bin', 0o555);
+  expect(() => wfs.createDir('/bin/sub')).toThrow();
+  expect(() => wfs.chown('/bin', 0, 0)).toThrow();
+  expect(wfs.access('/missing', 0)).toBe(false);
+});
+
+test('memfs set times', async() => {
+  let wfs = new MemFS();
+  let file = wfs.open('/file', {read: true, write: true, create: true});
+  wfs.setTimes('/file', {accessed: 1000n, modified: 2000n});
+  expect(wfs.metadata('/file').accessed).toBe(1000n);
+  expect(wfs.metadata('/file').modified).toBe(2000n);
+  expect(file.lastModified()).toBe(2000n);
+
+  wfs.setTimes('/file', {accessed: 3000});
+  expect(wfs.metadata('/file').accessed).toBe(3000n);
+  expect(wfs.metadata('/file').modified).toBe(2000n);
+
+  // Writing to the file updates its modification time again
+  file.writeString("hello");
+  expect(wfs.metadata('/file').modified).not.toBe(2000n);
+  expect(() => wfs.setTimes('/missing', {modified: 0})).toThrow();
+});
+
+test('memfs times follow renamed files', async() => {
+  let wfs = new MemFS();
+  let file = wfs.open('/file', {read: true, write: true, create: true});
+  wfs.rename('/file', '/moved');
+  wfs.setTimes('/moved', {modified: 2000n});
+  expect(file.lastModified()).toBe(2000n);
+  file.writeString("hello");
+  expect(wfs.metadata('/moved').modified).not.toBe(2000n);
+});
+
+test('guests set memfs times', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/data');
+  wfs.open('/data/file', {write: true, create: true}).writeString("contents");
+  wfs.symlink('file', '/data/link');
+  let [guest, write, read, fd] = await initPaths({fs: wfs, preopens: {'/data': '/data'}}, '/data');
+  const ATIM = 1, MTIM = 4, MTIM_NOW = 8;
+
+  expect(guest.open(fd, ...write(64, 'file'))).toBe(0);
+  let file = new Uint32Array(guest.memory.buffer, 12, 1)[0];
+  expect(guest.set_times(file, 1000n, 2000n, ATIM | MTIM)).toBe(0);
+  expect(wfs.metadata('/data/file').accessed).toBe(1000n);
+  expect(wfs.metadata('/data/file').modified).toBe(2000n);
+
+  // Only the times in the flags change
+  expect(guest.path_set_times(fd, ...write(64, 'link'), 0n, 3000n, MTIM)).toBe(0);
+  expect(wfs.metadata('/data/file').accessed).toBe(1000n);
+  expect(wfs.metadata('/data/file').modified).toBe(3000n);
+
+  let before = BigInt(Date.now() - 1000) * 1000000n;
+  expect(guest.path_set_times(fd, ...write(64, 'file'), 0n, 0n, MTIM_NOW)).toBe(0);
+  expect(wfs.metadata('/data/file').modified > before).toBe(true);
+});
+
+test('memfs watch', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/src');
+  let events = [];
+  let watcher = wfs.watch('/src', {}, event => events.push(event));
+  let recursive = [];
+  wfs.watch('/', {recursive: true}, event => recursive.push(event));
+
+  let file = wfs.open('/src/main.c', {write: true, create: true});
+  file.writeString("int main;");
+  wfs.rename('/src/main.c', '/src/lib.c');
+  wfs.createDir('/src/nested');
+  wfs.createDir('/src/nested/deeper');
+  wfs.removeFile('/src/lib.c');
+  expect(events).toEqual([
+    {type: 'create', path: '/src/main.c'},
+    {type: 'modify', path: '/src/main.c'},
+    {type: 'rename', path: '/src/lib.c', from: '/src/main.c'},
+    {type: 'create', path: '/src/nested'},
+    {type: 'remove', path: '/src/lib.c'},
+  ]);
+  expect(recursive.length).toBe(6);
+
+  watcher.close();
+  wfs.createDir('/src/other');
+  expect(events.length).toBe(5);
+  expect(recursive.length).toBe(7);
+});
+
+test('memfs serialize', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/src');
+  wfs.open('/src/main.c', {write: true, create: true}).writeString("int main;");
+  wfs.symlink('/src/main.c', '/main.c');
+  wfs.link('/src/main.c', '/src/copy.c');
+  wfs.chmod('/src', 0o700);
+  wfs.setTimes('/src/main.c', {modified: 1234n});
+
+  let bytes = wfs.serialize();
+  expect(bytes).toBeInstanceOf(Uint8Array);
+  let restored = MemFS.deserialize(bytes);
+  expect(restored.open('/main.c', {read: true}).readString()).toBe("int main;");
+  expect(restored.readlink('/main.c')).toBe('/src/main.c');
+  restored.open('/src/copy.c', {write: true, append: true}).writeString(" // linked");
+  expect(restored.open('/src/main.c', {read: true}).readString()).toBe("int main; // linked");
+  expect(restored.metadata('/src').mode).toBe(0o700);
+  expect(restored.metadata('/main.c').modified).toBe(1234n);
+
+  expect(() => MemFS.deserialize(new Uint8Array([1, 2, 3]))).toThrow();
+  expect(() => MemFS.deserialize(bytes.slice(0, bytes.length - 1))).toThrow();
+});
+
+// A small stand-in for IndexedDB, with only what `MemFS` uses
+function fakeIndexedDB() {
+  let databases = {};
+  let request = (run) => {
+    let req = {};
+    setTimeout(() => {
+      try {
+        req.result = run(req);
+        req.onsuccess && req.onsuccess();
+      } catch (e) {
+        req.error = e;
+        req.onerror && req.onerror();
+      }
+    });
+    return req;
+  };
+  return {
+    open(name) {
+      return request((req) => {
+        let stores = databases[name];
+        let db = {
+          createObjectStore(store) { stores[store] = new Map(); },
+          transaction(store) {
+            let data = stores[store];
+            return {
+              objectStore: () => ({
+                put: (value, key) => request(() => { data.set(key, value.slice()); return key; }),
+                get: (key) => request(() => data.get(key)),
+              }),
+            };
+          },
+          close() {},
+        };
+        if (!stores) {
+          stores = databases[name] = {};
+          req.result = db;
+          req.onupgradeneeded && req.onupgradeneeded();
+        }
+        return db;
+      });
+    },
+  };
+}
+
+test('memfs indexeddb', async() => {
+  global.indexedDB = fakeIndexedDB();
+  try {
+    let wfs = new MemFS();
+    wfs.open('/notes.txt', {write: true, create: true}).writeString("remember");
+    await wfs.saveToIndexedDB('workspace');
+
+    let restored = await MemFS.loadFromIndexedDB('workspace');
+    expect(restored.open('/notes.txt', {read: true}).readString()).toBe("remember");
+    await expect(MemFS.loadFromIndexedDB('missing')).rejects.toThrow();
+  } finally {
+    delete global.indexedDB;
+  }
+});
+
+test('memfs fork', async() => {
+  let image = new MemFS();
+  image.createDir('/bin');
+  image.open('/bin/tool', {write: true, create: true}).writeString("v1");
+  image.setTimes('/bin/tool', {modified: 42n});
+
+  let first = image.fork();
+  let second = image.fork();
+  expect(first.open('/bin/tool', {read: true}).readString()).toBe("v1");
+  expect(first.open('/bin/tool', {read: true}).size()).toBe(2n);
+  expect(first.metadata('/bin/tool').modified).toBe(42n);
+
+  first.open('/bin/tool', {write: true, append: true}).writeString("-patched");
+  first.createDir('/tmp');
+  expect(first.open('/bin/tool', {read: true}).readString()).toBe("v1-patched");
+  expect(second.open('/bin/tool', {read: true}).readString()).toBe("v1");
+  expect(image.open('/bin/tool', {read: true}).readString()).toBe("v1");
+  expect(() => image.metadata('/tmp')).toThrow();
+
+  image.open('/bin/tool', {write: true, truncate: true}).writeString("v2");
+  expect(image.fork().open('/bin/tool', {read: true}).readString()).toBe("v2");
+  expect(second.open('/bin/tool', {read: true}).readString()).toBe("v1");
+});
+
+test('memfs quotas', async() => {
+  let wfs = new MemFS({maxBytes: 10, maxFiles: 3});
+  wfs.createDir('/data');
+  let file = wfs.open('/data/log', {write: true, create: true});
+  expect(file.writeString("12345678")).toBe(8);
+  expect(wfs.usage()).toEqual({files: 2, bytes: 8, maxFiles: 3, maxBytes: 10});
+
+  expect(() => file.writeString("abc")).toThrow();
+  file.seek(0);
+  expect(file.writeString("abcdefgh")).toBe(8);
+  expect(() => file.setLength(11n)).toThrow();
+
+  wfs.symlink('/data/log', '/log');
+  expect(() => wfs.createDir('/more')).toThrow();
+  wfs.removeFile('/log');
+  wfs.removeFile('/data/log');
+  expect(wfs.usage()).toEqual({files: 1, bytes: 0, maxFiles: 3, maxBytes: 10});
+  expect(new MemFS().usage()).toEqual({files: 0, bytes: 0});
+});
+
+test('memfs quotas for the guest', async() => {
+  let wfs = new MemFS({maxBytes: 4});
+  let [wasi, write] = await initWriter({fs: wfs, stdout: {file: '/stdout.txt'}});
+  const ENOSPC = 51;
+  expect(write(1, new TextEncoder().encode("hello world\n"))).toBe(ENOSPC);
+  expect(wfs.usage().bytes).toBe(0);
+  expect(write(1, new TextEncoder().encode("hey"))).toBe(0);
+  expect(wfs.open('/stdout.txt', {read: true}).readString()).toBe("hey");
+  expect(wfs.usage().bytes).toBe(3);
+});
+
+test('memfs snapshots keep to the quotas', async() => {
+  let wfs = new MemFS({maxBytes: 10, maxFiles: 2});
+  wfs.createDir('/data');
+  wfs.open('/data/log', {write: true, create: true}).writeString("12345678");
+  let fork = wfs.fork();
+  expect(fork.usage()).toEqual({files: 2, bytes: 8, maxFiles: 2, maxBytes: 10});
+  expect(() => fork.createDir('/more')).toThrow();
+
+  let bytes = wfs.serialize();
+  expect(MemFS.deserialize(bytes, {maxBytes: 8}).usage().bytes).toBe(8);
+  expect(() => MemFS.deserialize(bytes, {maxBytes: 7})).toThrow("doesn't fit");
+  expect(() => MemFS.deserialize(bytes, {maxFiles: 1})).toThrow("doesn't fit");
+});
+
+test('http fs', async() => {
+  let served = {"https://example.com/input.txt": new TextEncoder().encode("Hello World!")};
+  let requests = [];
+  let httpFs = new HttpFS({
+    files: [
+      {path: '/data/input.txt', size: 12, url: 'https://example.com/input.txt'},
+      {path: '/data/nested/big.bin', size: 100, url: 'https://example.com/big.bin'},
+    ],
+  }, {
+    blockSize: 4,
+    fetch: (url, start, end) => {
+      requests.push([url, start, end]);
+      return served[url].slice(start, end);
+    },
+  });
+
+  let file = httpFs.open('/data/input.txt');
+  expect(requests).toEqual([]);
+  expect(file.readAt(5n, 2)).toEqual(new Uint8Array([87, 111]));
+  expect(requests).toEqual([["https://example.com/input.txt", 4, 8]]);
+  expect(file.readString()).toBe("Hello World!");
+  expect(requests.length).toBe(3);
+  expect(httpFs.cachedBytes).toBe(12);
+  expect(() => httpFs.open('/data/missing')).toThrow();
+
+  let contents = fs.readFileSync(__dirname + '/pipe_reverse.wasm');
+  let wasi = await initWasi(contents, {fs: httpFs, stdin: {file: '/data/input.txt'}});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe("!dlroW olleH\n");
+  expect(requests.length).toBe(3);
+  expect(wasi.httpFs).toBeInstanceOf(HttpFS);
+  expect(() => wasi.fs).toThrow("HttpFS");
+  expect((await initWasi(contents, {})).httpFs).toBeUndefined();
+});
+
+test('http fs blocks of a url listed with different sizes', async() => {
+  let served = new TextEncoder().encode("Hello World!");
+  let httpFs = new HttpFS({
+    files: [
+      {path: '/short.txt', size: 6, url: 'https://example.com/input.txt'},
+      {path: '/long.txt', size: 12, url: 'https://example.com/input.txt'},
+    ],
+  }, {blockSize: 4, fetch: (url, start, end) => served.slice(start, end)});
+  expect(httpFs.open('/short.txt').readString()).toBe("Hello ");
+  expect(httpFs.open('/long.txt').readString()).toBe("Hello World!");
+});
+
+test('http fs drops the blocks used least recently', async() => {
+  let served = new TextEncoder().encode("Hello World!");
+  let requests = [];
+  let httpFs = new HttpFS({files: [{path: '/input.txt', size: 12, url: 'https://example.com/input.txt'}]}, {
+    blockSize: 4,
+    maxCachedBytes: 8,
+    fetch: (url, start, end) => {
+      requests.push([start, end]);
+      return served.slice(start, end);
+    },
+  });
+  let file = httpFs.open('/input.txt');
+  expect(file.readAt(0n, 4)).toEqual(served.slice(0, 4));
+  expect(file.readAt(4n, 4)).toEqual(served.slice(4, 8));
+  expect(file.readAt(0n, 4)).toEqual(served.slice(0, 4));
+  expect(file.readAt(8n, 4)).toEqual(served.slice(8, 12));
+  expect(httpFs.cachedBytes).toBe(8);
+  // The second block was dropped, the first one was used since
+  expect(file.readAt(0n, 4)).toEqual(served.slice(0, 4));
+  expect(file.readAt(4n, 4)).toEqual(served.slice(4, 8));
+  expect(requests).toEqual([[0, 4], [4, 8], [8, 12], [4, 8]]);
+  // A read larger than the cache still gets all of its blocks
+  expect(file.readString()).toBe("Hello World!");
+  expect(httpFs.cachedBytes).toBe(8);
+  expect(() => new HttpFS({files: []}, {maxCachedBytes: -1})).toThrow("maxCachedBytes");
+});
+
+test('http fs fetches with XMLHttpRequest by default', async() => {
+  let requests = [];
+  globalThis.XMLHttpRequest = class {
+    open(method, url, async) {
+      this.request = [method, url, async];
+    }
+    setRequestHeader(name, value) {
+      this.request.push(value);
+    }
+    overrideMimeType() {}
+    send() {
+      requests.push(this.request);
+      this.status = 206;
+      // Bytes as characters, like with the `x-user-defined` charset
+      this.responseText = String.fromCharCode(0xf7ff, 0x41, 0xf780);
+    }
+  };
+  try {
+    let httpFs = new HttpFS({files: [{path: '/data.bin', size: 3, url: 'https://example.com/data.bin'}]});
+    expect(httpFs.open('/data.bin').read()).toEqual(new Uint8Array([0xff, 0x41, 0x80]));
+    expect(requests).toEqual([['GET', 'https://example.com/data.bin', false, 'bytes=0-2']]);
+  } finally {
+    delete globalThis.XMLHttpRequest;
+  }
+});
+
+test('http fs directories', async() => {
+  let httpFs = new HttpFS(JSON.stringify({
+    files: [
+      {path: '/a/one.txt', size: 1, url: 'one'},
+      {path: '/b', size: 1, url: 'b'},
+    ],
+  }), {fetch: () => new Uint8Array([0])});
+  let contents = fs.readFileSync(__dirname + '/mapdir.wasm');
+  let wasi = await initWasi(contents, {fs: httpFs});
+  wasi.start();
+  expect(wasi.getStdoutString()).toBe(`"./a"\n"./b"\n`);
+  expect(() => new HttpFS({files: [{path: '/a'}]})).toThrow();
+});
+
+test('memfs glob', async() => {
+  let wfs = new MemFS();
+  for (let dir of ['/src', '/src/util', '/src/.cache', '/build']) {
+    wfs.createDir(dir);
+  }
+  for (let file of ['/src/main.c', '/src/main.h', '/src/util/str.c', '/src/.cache/x.c', '/build/main.o', '/README']) {
+    wfs.open(file, {write: true, create: true});
+  }
+  wfs.symlink('/src', '/src/util/loop');
+
+  expect(wfs.glob('/src/*.c')).toEqual(['/src/main.c']);
+  expect(wfs.glob('src/main.?')).toEqual(['/src/main.c', '/src/main.h']);
+  expect(wfs.glob('/src/**/*.c')).toEqual(['/src/main.c', '/src/util/str.c']);
+  expect(wfs.glob('/{src,build}/main.{c,o}')).toEqual(['/build/main.o', '/src/main.c']);
+  expect(wfs.glob('/src/.cache/*')).toEqual(['/src/.cache/x.c']);
+  expect(wfs.glob('/*')).toEqual(['/README', '/build', '/src']);
+  expect(wfs.glob('/src/util/**')).toEqual(['/src/util', '/src/util/loop', '/src/util/str.c']);
+  expect(wfs.glob('/missing/*')).toEqual([]);
+});
+
+test('memfs find', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/out');
+  wfs.createDir('/out/logs');
+  wfs.open('/out/big.bin', {write: true, create: true}).write(new Uint8Array(100));
+  wfs.open('/out/logs/small.txt', {write: true, create: true}).writeString("hi");
+  wfs.symlink('/out/big.bin', '/out/latest');
+  wfs.setTimes('/out/logs/small.txt', {modified: 10n});
+
+  expect(wfs.find('/out')).toEqual(['/out', '/out/big.bin', '/out/latest', '/out/logs', '/out/logs/small.txt']);
+  expect(wfs.find('/out', {type: 'file'})).toEqual(['/out/big.bin', '/out/logs/small.txt']);
+  expect(wfs.find('/out', {type: 'dir'})).toEqual(['/out', '/out/logs']);
+  expect(wfs.find('/out', {type: 'symlink'})).toEqual(['/out/latest']);
+  expect(wfs.find('/out', {type: 'file', minSize: 50})).toEqual(['/out/big.bin']);
+  expect(wfs.find('/out', {type: 'file', modifiedAfter: 100n})).toEqual(['/out/big.bin']);
+  expect(() => wfs.find('/out', {type: 'socket'})).toThrow();
+  expect(() => wfs.find('/missing')).toThrow();
+});
+
+test('memfs path normalization', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('a');
+  wfs.createDir('/a//b/');
+  wfs.open('./a/b/../file', {write: true, create: true}).writeString("x");
+  expect(wfs.readDir('/a').map(entry => entry.path)).toEqual(['/a/b', '/a/file']);
+  expect(wfs.readDir('a/./b/..//').map(entry => entry.path)).toEqual(['/a/b', '/a/file']);
+  expect(wfs.open('/../../a/file', {read: true}).readString()).toBe("x");
+
+  // A trailing slash requires a directory
+  expect(() => wfs.open('/a/file/', {read: true})).toThrow();
+  expect(() => wfs.readDir('/a/file/.')).toThrow();
+  expect(() => wfs.open('/a/file/x', {read: true, write: true, create: true})).toThrow();
+
+  wfs.symlink('/a/b', '/a/link');
+  wfs.symlink('link/../file', '/a/relative');
+  wfs.link('/a/file', '/hard');
+  expect(wfs.realpath('/a/link')).toBe('/a/b');
+  expect(wfs.realpath('a/link/')).toBe('/a/b');
+  expect(wfs.realpath('/a/link/..')).toBe('/a');
+  expect(wfs.realpath('/a/relative')).toBe('/a/file');
+  expect(wfs.realpath('/hard')).toBe('/hard');
+  expect(wfs.realpath('/')).toBe('/');
+  expect(() => wfs.realpath('/a/missing')).toThrow();
+
+  let rooted = new MemFS({cwd: '/a/b'});
+  rooted.createDir('/a');
+  rooted.createDir('/a/b');
+  rooted.open('out.txt', {write: true, create: true});
+  expect(rooted.realpath('out.txt')).toBe('/a/b/out.txt');
+  expect(rooted.realpath('../..')).toBe('/');
+  expect(() => new MemFS({cwd: 'relative'})).toThrow();
+});
+
+test('networking through a js transport', async () => {
+  let connections = [];
+  let sent = [];
+  let transport = {
+    connect(address, port) {
+      connections.push([address, port]);
+      // A loopback server that answers every message with "pong"
+      let pending = [];
+      return {
+        send(data) {
+          sent.push(new TextDecoder().decode(data));
+          pending.push(new TextEncoder().encode("pong\n"));
+        },
+        recv() {
+          return pending.shift() || new Uint8Array();
+        },
+        close() {},
+      };
+    },
+  };
+  let contents = fs.readFileSync(__dirname + '/socket.wasm');
+  let wasi = await initWasi(contents, {network: transport});
+  let code = wasi.start();
+  expect(code).toBe(0);
+  expect(connections).toEqual([["127.0.0.1", 8080]]);
+  expect(sent).toEqual(["ping\n"]);
+  expect(wasi.getStdoutString()).toBe("pong\n");
+
+  expect(() => new WASI({network: {}})).toThrow();
+});
+
+test('js transport connections without data and at their end', async () => {
+  const EAGAIN = 6;
+  let received = [new Uint8Array(), new TextEncoder().encode("pong"), new Uint8Array(), null];
+  let calls = 0;
+  let transport = {
+    connect(address, port) {
+      return {
+        send(data) {},
+        recv() {
+          calls++;
+          return received.shift();
+        },
+        close() {},
+      };
+    },
+  };
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/loopback.wasm'));
+  let guest = (await new WASI({network: transport}).instantiate(module, {})).exports;
+  let read = len => new TextDecoder().decode(new Uint8Array(guest.memory.buffer, 256, len));
+
+  let socket = guest.connect(8080);
+  expect(socket).toBeGreaterThan(2);
+  expect(guest.recv(socket, 256, 64)).toBe(-EAGAIN);
+  expect(guest.recv(socket, 256, 64)).toBe(4);
+  expect(read(4)).toBe("pong");
+  expect(guest.recv(socket, 256, 64)).toBe(-EAGAIN);
+  // The end of the stream reads as nothing, without asking the transport again
+  expect(guest.recv(socket, 256, 64)).toBe(0);
+  expect(guest.recv(socket, 256, 64)).toBe(0);
+  expect(calls).toBe(4);
+});
+
+test('loopback network between instances', async () => {
+  const EADDRINUSE = 3;
+  const EAGAIN = 6;
+  const ECONNREFUSED = 14;
+  const EDEADLK = 16;
+  let network = new Network();
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/loopback.wasm'));
+  let server = (await new WASI({network}).instantiate(module, {})).exports;
+  let client = (await new WASI({network}).instantiate(module, {})).exports;
+  let write = (guest, text) => {
+    let bytes = new TextEncoder().encode(text);
+    new Uint8Array(guest.memory.buffer).set(bytes, 256);
+    return bytes.length;
+  };
+  let read = (guest, len) => new TextDecoder().decode(new Uint8Array(guest.memory.buffer, 256, len));
+
+  let listener = server.listen(8080);
+  expect(listener).toBeGreaterThan(2);
+  expect(network.listening).toEqual(['0.0.0.0:8080']);
+  expect(client.connect(9090)).toBe(-ECONNREFUSED);
+  // Outside of `startAsync` the guest can't wait for a connection
+  expect(server.accept(listener)).toBe(-EDEADLK);
+  expect(server.nonblocking(listener)).toBe(0);
+  expect(server.accept(listener)).toBe(-EAGAIN);
+
+  let clientSocket = client.connect(8080);
+  expect(clientSocket).toBeGreaterThan(2);
+  let serverSocket = server.accept(listener);
+  expect(serverSocket).toBeGreaterThan(2);
+
+  expect(client.send(clientSocket, 256, write(client, "ping"))).toBe(4);
+  expect(server.recv(serverSocket, 256, 64)).toBe(4);
+  expect(read(server, 4)).toBe("ping");
+  expect(server.send(serverSocket, 256, write(server, "pong!"))).toBe(5);
+  expect(client.recv(clientSocket, 256, 64)).toBe(5);
+  expect(read(client, 5)).toBe("pong!");
+
+  // Once the client is done writing, the server reads the end of the stream
+  expect(client.shutdown(clientSocket)).toBe(0);
+  expect(server.recv(serverSocket, 256, 64)).toBe(0);
+  expect(server.send(serverSocket, 256, write(server, "bye"))).toBe(3);
+  expect(client.recv(clientSocket, 256, 64)).toBe(3);
+
+  // Other networks don't see the listener
+  let other = (await new WASI({network: new Network()}).instantiate(module, {})).exports;
+  expect(other.connect(8080)).toBe(-ECONNREFUSED);
+  expect(server.listen(8080)).toBe(-EADDRINUSE);
+});
+
+test('loopback ephemeral ports skip the ports in use', async () => {
+  const EADDRINUSE = 3;
+  let network = new Network();
+  let module = await WebAssembly.compile(fs.readFileSync(__dirname + '/loopback.wasm'));
+  let server = (await new WASI({network}).instantiate(module, {})).exports;
+  let client = (await new WASI({network}).instantiate(module, {})).exports;
+  // The port of the address that `accept` stores at offset 16
+  let peerPort = () => new DataView(server.memory.buffer).getUint16(17, true);
+
+  let listener = server.listen(49152);
+  expect(listener).toBeGreaterThan(2);
+  expect(client.connect(49152)).toBeGreaterThan(2);
+  expect(server.accept(listener)).toBeGreaterThan(2);
+  expect(peerPort()).toBe(49153);
+  expect(server.listen(0)).toBeGreaterThan(2);
+  expect(network.listening).toEqual(['0.0.0.0:49152', '0.0.0.0:49154']);
+  // Nor can a socket bind to them explicitly
+  expect(server.listen(49152)).toBe(-EADDRINUSE);
+  expect(server.listen(49153)).toBe(-EADDRINUSE);
+});
+
+test('guests spawn registered commands', async() => {
+  let wfs = new MemFS();
+  let wasi = new WASI({fs: wfs});
+  wasi.registerCommand('hello', await WebAssembly.compile(fs.readFileSync(__dirname + '/demo.wasm')));
+  await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {});
+  expect(wasi.start()).toBe(0);
+  expect(wasi.getStdoutString()).toBe("hello world\n");
+});
+
+test('spawning an unregistered command fails', async() => {
+  let contents = fs.readFileSync(__dirname + '/spawn.wasm');
+  let wasi = await initWasi(contents, {});
+
+
+test('guests poll the exit codes of their processes', async() => {
+  let wasi = new WASI({});
+  wasi.registerCommand('fail', await WebAssembly.compile(fs.readFileSync(__dirname + '/exit.wasm')));
+  let guest = (await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {})).exports;
+  let u32 = offset => new DataView(guest.memory.buffer).getUint32(offset, true);
+  const BUS_EVENT_EXIT = 1;
+
+  new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('fail'), 128);
+  expect(guest.spawn(128, 4)).toBe(0);
+  let bid = u32(32);
+  expect(guest.poll()).toBe(0);
+  expect(u32(48)).toBe(1);
+  expect(new Uint8Array(guest.memory.buffer)[64]).toBe(BUS_EVENT_EXIT);
+  expect([u32(72), u32(76)]).toEqual([bid, 3]);
+  // Each exit is polled once
+  expect(guest.poll()).toBe(0);
+  expect(u32(48)).toBe(0);
+});
+
+test('guests exec registered commands', async() => {
+  let contents = fs.readFileSync(__dirname + '/exec.wasm');
+  let wasi = await initWasi(contents, {});
+  wasi.registerCommand('fail', await WebAssembly.compile(fs.readFileSync(__dirname + '/exit.wasm')));
+  expect(wasi.start()).toBe(3);
+
+  // The guest goes on when the command can't run
+  let unregistered = await initWasi(contents, {});
+  expect(unregistered.start()).toBe(127);
+});
+
+test('processes start in the working directory of the guest', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/work');
+  wfs.createDir('/work/src');
+  wfs.open('/work/notes', {write: true, create: true});
+  let wasi = new WASI({fs: wfs, cwd: '/work'});
+  wasi.registerCommand('mapdir', await WebAssembly.compile(fs.readFileSync(__dirname + '/mapdir.wasm')));
+  let guest = (await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {})).exports;
+  new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('mapdir'), 128);
+  expect(guest.spawn(128, 6)).toBe(0);
+  expect(wasi.getStdoutString()).toBe(`"./notes"\n"./src"\n`);
+});
+
+test('processes get the working directory as PWD', async() => {
+  let wfs = new MemFS();
+  wfs.createDir('/work');
+  let wasi = new WASI({fs: wfs, cwd: '/work', env: {PWD: '/'}});
+  wasi.registerCommand('envvar', await WebAssembly.compile(fs.readFileSync(__dirname + '/envvar.wasm')));
+  let guest = (await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {})).exports;
+  new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('envvar'), 128);
+  expect(guest.spawn(128, 6)).toBe(0);
+  let output = wasi.getStdoutString();
+  expect(output).toContain("PWD=/work\n");
+  expect(output).not.toContain("PWD=/\n");
+});
+
+test('processes write through the stdio of the guest', async() => {
+  let spawnBoth = async(config) => {
+    let wasi = new WASI(config);
+    wasi.registerCommand('both', await WebAssembly.compile(fs.readFileSync(__dirname + '/both.wasm')));
+    let guest = (await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {})).exports;
+    new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('both'), 128);
+    expect(guest.spawn(128, 4)).toBe(0);
+    return wasi;
+  };
+  let decoder = new TextDecoder();
+  let logged = await spawnBoth({outputLog: true});
+  expect(logged.getOutputLog().map(e => [e.stream, decoder.decode(e.data)])).toEqual([
+    ['stdout', "out\n"],
+    ['stderr', "err\n"],
+  ]);
+  expect((await spawnBoth({mergeStderr: true})).getStdoutString()).toBe("out\nerr\n");
+  let wfs = new MemFS();
+  let redirected = await spawnBoth({fs: wfs, stdout: {file: '/out.txt'}});
+  expect(wfs.open('/out.txt', {read: true}).readString()).toBe("out\n");
+  expect(redirected.getStdoutString()).toBe("");
+  expect(redirected.getStderrString()).toBe("err\n");
+});
+
+test('processes leave the stdin they do not read to the guest', async() => {
+  let wasi = new WASI({});
+  wasi.registerCommand('hello', await WebAssembly.compile(fs.readFileSync(__dirname + '/demo.wasm')));
+  wasi.registerCommand('head', await WebAssembly.compile(fs.readFileSync(__dirname + '/head.wasm')));
+  let guest = (await wasi.instantiate(await WebAssembly.compile(fs.readFileSync(__dirname + '/spawn.wasm')), {})).exports;
+  wasi.setStdinString("input\n");
+  new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('hello'), 128);
+  expect(guest.spawn(128, 5)).toBe(0);
+  new Uint8Array(guest.memory.buffer).set(new TextEncoder().encode('head'), 128);
+  expect(guest.spawn(128, 4)).toBe(0);
+  expect(wasi.getStdoutString()).toBe("hello world\ninput\n");
+});
+  expect(wasi.start()).not.toBe(0);
+  expect(wasi.getStdoutString()).toBe("");
+});
+
+async function initShell(options) {
+  let shell = new Shell(options);
+  for (let [name, file] of [['hello', 'demo.wasm'], ['reverse', 'pipe_reverse.wasm'], ['envvar', 'envvar.wasm'], ['both', 'both.wasm'], ['trap', 'trap.wasm']]) {
+    shell.registerCommand(name, await WebAssembly.compile(fs.readFileSync(__dirname + '/' + file)));
+  }
+  return shell;
+}
+
+test('shell pipes and redirections', async() => {
+  let shell = await initShell();
+  expect(await shell.run("hello | reverse > /out.txt")).toBe(0);
+  expect(shell.getStdoutString()).toBe("");
+  expect(await shell.run("hello >> /out.txt")).toBe(0);
+  expect(shell.fs.open('/out.txt', {read: true}).readString()).toBe("\ndlrow olleh\nhello world\n");
+  shell.fs.open('/in file.txt', {write: true, create: true}).writeString("Hello World!");
+  expect(await shell.run("reverse < 'in file.txt' 2> /err.txt")).toBe(0);
+  expect(shell.getStdoutString()).toBe("!dlroW olleH\n");
+});
+
+test('shell conditions and environment', async() => {
+  let wfs = new MemFS();
+  let shell = await initShell({fs: wfs, env: {DOG: "X"}});
+  expect(await shell.run("missing && hello")).toBe(127);
+  expect(shell.getStdoutString()).toBe("");
+  expect(shell.getStderrString()).toBe("sh: missing: command not found\n");
+  expect(await shell.run("missing || hello")).toBe(0);
+  expect(shell.getStdoutString()).toBe("hello world\n");
+  await shell.run("TEST=VALUE");
+  expect(await shell.run("TEST2=\"VALUE 2\" envvar > out.txt")).toBe(0);
+  let output = wfs.open('/out.txt', {read: true}).readString();
+  expect(output).toContain("DOG=X\n");
+  expect(output).toContain("TEST=VALUE\n");
+  expect(output).toContain("TEST2=VALUE 2\n");
+  expect(() => shell.run("hello |")).toThrow("Syntax error");
+  expect(() => shell.run("echo 'oops")).toThrow("Syntax error");
+});
+
+test('shell merges the stderr into the stdout', async() => {
+  let shell = await initShell();
+  expect(await shell.run("both > /log.txt 2>&1")).toBe(0);
+  expect(shell.fs.open('/log.txt', {read: true}).readString()).toBe("out\nerr\n");
+  expect(await shell.run("both >> /log.txt 2>&1")).toBe(0);
+  expect(shell.fs.open('/log.txt', {read: true}).readString()).toBe("out\nerr\nout\nerr\n");
+  expect(await shell.run("both > /log.txt 2>&1")).toBe(0);
+  expect(shell.fs.open('/log.txt', {read: true}).readString()).toBe("out\nerr\n");
+  expect([shell.getStdoutString(), shell.getStderrString()]).toEqual(["", ""]);
+
+  expect(await shell.run("both 2>&1 | reverse")).toBe(0);
+  expect(shell.getStdoutString()).toBe("\nrre\ntuo\n");
+  expect(await shell.run("both 2>&1")).toBe(0);
+  expect(shell.getStdoutString()).toBe("out\nerr\n");
+  // The last redirection of the stderr wins
+  expect(await shell.run("both 2>&1 2> /err.txt")).toBe(0);
+  expect(shell.getStdoutString()).toBe("out\n");
+  expect(shell.fs.open('/err.txt', {read: true}).readString()).toBe("err\n");
+  expect(() => shell.run("both 2>&1 > /log.txt")).toThrow("Syntax error");
+});
+
+test('shell sequences and escapes', async() => {
+  let wfs = new MemFS();
+  let shell = await initShell({fs: wfs});
+  expect(await shell.run("hello; missing; hello;")).toBe(0);
+  expect(shell.getStdoutString()).toBe("hello world\nhello world\n");
+  expect(shell.getStderrString()).toBe("sh: missing: command not found\n");
+  expect(await shell.run("hello ; missing")).toBe(127);
+
+  wfs.open('/in file.txt', {write: true, create: true}).writeString("a|b");
+  expect(await shell.run("reverse < in\\ file.txt")).toBe(0);
+  expect(shell.getStdoutString()).toBe("b|a\n");
+  expect(await shell.run("TEST=a\\ b\\;c\\\\ envvar > out.txt")).toBe(0);
+  expect(wfs.open('/out.txt', {read: true}).readString()).toContain("TEST=a b;c\\\n");
+  expect(() => shell.run("hello \\")).toThrow("Syntax error");
+});
+
+test('shell goes on after a command traps', async() => {
+  let shell = await initShell();
+  expect(await shell.run("trap; hello")).toBe(0);
+  expect(shell.getStdoutString()).toBe("out\nhello world\n");
+  expect(shell.getStderrString()).toMatch(/^sh: trap: /);
+  expect(await shell.run("trap && hello")).toBe(134);
+  expect(shell.getStdoutString()).toBe("out\n");
+  expect(await shell.run("trap || hello")).toBe(0);
+  expect(shell.getStdoutString()).toBe("out\nhello world\n");
+});
+
+test('shell goes on after a command fails to start', async() => {
+  let shell = await initShell();
+  shell.registerCommand('external', await WebAssembly.compile(fs.readFileSync(__dirname + '/test.wasm')));
+  expect(await shell.run("reverse < /missing.txt || hello")).toBe(0);
+  expect(shell.getStdoutString()).toBe("hello world\n");
+  expect(shell.getStderrString()).toMatch(/^sh: reverse: Couldn't open `\/missing.txt` for stdin: /);
+  expect(await shell.run("reverse < /missing.txt")).toBe(1);
+  expect(await shell.run("external")).toBe(126);
+  expect(shell.getStderrString()).toMatch(/^sh: reverse: .*\nsh: external: .*`module\.external`\n$/);
+  // The other commands of the pipeline still run
+  expect(await shell.run("missing | hello")).toBe(0);
+  expect(shell.getStdoutString()).toBe("hello world\n");
+  expect(await shell.run("hello | missing")).toBe(127);
+});
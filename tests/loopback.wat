@@ -0,0 +1,87 @@
+(module
+    ;; Thin wrappers around WASIX sockets, so that a test can drive a client
+    ;; and a server step by step. They return a negated errno on failure.
+    (import "wasix_32v1" "sock_open" (func $sock_open (param i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_bind" (func $sock_bind (param i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_listen" (func $sock_listen (param i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_accept" (func $sock_accept (param i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_connect" (func $sock_connect (param i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_send" (func $sock_send (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_recv" (func $sock_recv (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_shutdown" (func $sock_shutdown (param i32 i32) (result i32)))
+    (import "wasix_32v1" "fd_fdstat_set_flags" (func $fd_fdstat_set_flags (param i32 i32) (result i32)))
+
+    ;; 0: the returned fd or size, 4: the returned flags, 16: an address,
+    ;; 64: an iovec
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Opens an INET4 stream socket with the address `ip`:`port` at offset 16
+    (func $open (param $ip i32) (param $port i32) (result i32)
+        (i32.store8 (i32.const 16) (i32.const 1))
+        (i32.store16 (i32.const 17) (local.get $port))
+        (i32.store (i32.const 19) (local.get $ip))
+        (call $sock_open (i32.const 1) (i32.const 1) (i32.const 0) (i32.const 0)))
+
+    ;; Listens on 0.0.0.0:`port`, returning the fd of the socket
+    (func (export "listen") (param $port i32) (result i32)
+        (local $errno i32)
+        (local $fd i32)
+        (local.set $errno (call $open (i32.const 0) (local.get $port)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (local.set $fd (i32.load (i32.const 0)))
+        (local.set $errno (call $sock_bind (local.get $fd) (i32.const 16)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (local.set $errno (call $sock_listen (local.get $fd) (i32.const 16)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (local.get $fd))
+
+    ;; Connects to 127.0.0.1:`port`, returning the fd of the socket
+    (func (export "connect") (param $port i32) (result i32)
+        (local $errno i32)
+        (local $fd i32)
+        (local.set $errno (call $open (i32.const 0x0100007f) (local.get $port)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (local.set $fd (i32.load (i32.const 0)))
+        (local.set $errno (call $sock_connect (local.get $fd) (i32.const 16)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (local.get $fd))
+
+    ;; Accepts a connection, returning its fd
+    (func (export "accept") (param $fd i32) (result i32)
+        (local $errno i32)
+        (local.set $errno (call $sock_accept (local.get $fd) (i32.const 0) (i32.const 0) (i32.const 16)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load (i32.const 0)))
+
+    ;; Sends `len` bytes at `ptr`, returning how many were sent
+    (func (export "send") (param $fd i32) (param $ptr i32) (param $len i32) (result i32)
+        (local $errno i32)
+        (i32.store (i32.const 64) (local.get $ptr))
+        (i32.store (i32.const 68) (local.get $len))
+        (local.set $errno
+            (call $sock_send (local.get $fd) (i32.const 64) (i32.const 1) (i32.const 0) (i32.const 0)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load (i32.const 0)))
+
+    ;; Receives at most `len` bytes at `ptr`, returning how many were received
+    (func (export "recv") (param $fd i32) (param $ptr i32) (param $len i32) (result i32)
+        (local $errno i32)
+        (i32.store (i32.const 64) (local.get $ptr))
+        (i32.store (i32.const 68) (local.get $len))
+        (local.set $errno
+            (call $sock_recv
+                (local.get $fd) (i32.const 64) (i32.const 1) (i32.const 0) (i32.const 0) (i32.const 4)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load (i32.const 0)))
+
+    ;; Shuts down the writing side of the connection
+    (func (export "shutdown") (param $fd i32) (result i32)
+        (i32.sub (i32.const 0) (call $sock_shutdown (local.get $fd) (i32.const 2))))
+
+    (func (export "_start"))
+
+    ;; Makes the socket nonblocking
+    (func (export "nonblocking") (param $fd i32) (result i32)
+        (i32.sub (i32.const 0) (call $fd_fdstat_set_flags (local.get $fd) (i32.const 4))))
+)
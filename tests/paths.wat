@@ -0,0 +1,71 @@
+(module
+    ;; Calls the WASI functions taking paths with the arguments of the test,
+    ;; which writes the paths in memory
+    (import "wasi_snapshot_preview1" "fd_prestat_get"
+        (func $fd_prestat_get (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+        (func $fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_symlink"
+        (func $path_symlink (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_readlink"
+        (func $path_readlink (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_open"
+        (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_filestat_set_times"
+        (func $fd_filestat_set_times (param i32 i64 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_filestat_set_times"
+        (func $path_filestat_set_times (param i32 i32 i32 i32 i64 i64 i32) (result i32)))
+
+    ;; 0: the prestat, 8: the length of the link read, 12: the fd opened
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Gets the prestat of `fd` at offset 0, returning the errno
+    (func (export "prestat") (param $fd i32) (result i32)
+        (call $fd_prestat_get (local.get $fd) (i32.const 0)))
+
+    ;; Writes the name of the preopen `fd` at `ptr`, returning the errno
+    (func (export "dir_name") (param $fd i32) (param $ptr i32) (param $len i32) (result i32)
+        (call $fd_prestat_dir_name (local.get $fd) (local.get $ptr) (local.get $len)))
+
+    ;; Creates a symlink in the directory `fd`, returning the errno
+    (func (export "symlink")
+        (param $target i32) (param $target_len i32) (param $fd i32) (param $ptr i32) (param $len i32)
+        (result i32)
+        (call $path_symlink
+            (local.get $target) (local.get $target_len)
+            (local.get $fd) (local.get $ptr) (local.get $len)))
+
+    ;; Reads the symlink in the directory `fd` into `buf`, returning the errno.
+    ;; The length read is stored at offset 8.
+    (func (export "readlink")
+        (param $fd i32) (param $ptr i32) (param $len i32) (param $buf i32) (param $buf_len i32)
+        (result i32)
+        (call $path_readlink
+            (local.get $fd) (local.get $ptr) (local.get $len)
+            (local.get $buf) (local.get $buf_len) (i32.const 8)))
+
+    ;; Opens the file in the directory `fd` to read, write and set its times,
+    ;; returning the errno. The new fd is stored at offset 12.
+    (func (export "open") (param $fd i32) (param $ptr i32) (param $len i32) (result i32)
+        (call $path_open
+            (local.get $fd) (i32.const 0) (local.get $ptr) (local.get $len)
+            (i32.const 0) (i64.const 8388674) (i64.const 0) (i32.const 0) (i32.const 12)))
+
+    ;; Sets the times of the file opened as `fd`, returning the errno
+    (func (export "set_times")
+        (param $fd i32) (param $atim i64) (param $mtim i64) (param $flags i32) (result i32)
+        (call $fd_filestat_set_times
+            (local.get $fd) (local.get $atim) (local.get $mtim) (local.get $flags)))
+
+    ;; Sets the times of the file in the directory `fd`, following symlinks,
+    ;; returning the errno
+    (func (export "path_set_times")
+        (param $fd i32) (param $ptr i32) (param $len i32)
+        (param $atim i64) (param $mtim i64) (param $flags i32) (result i32)
+        (call $path_filestat_set_times
+            (local.get $fd) (i32.const 1) (local.get $ptr) (local.get $len)
+            (local.get $atim) (local.get $mtim) (local.get $flags)))
+
+    (func (export "_start"))
+)
@@ -0,0 +1,43 @@
+(module
+    ;; Connects to 127.0.0.1:8080 with WASIX sockets, sends "ping\n" and writes
+    ;; what it receives back to stdout
+    (import "wasix_32v1" "sock_open" (func $sock_open (param i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_connect" (func $sock_connect (param i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_send" (func $sock_send (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "sock_recv" (func $sock_recv (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "proc_exit" (func $proc_exit (param i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The address: the INET4 tag, then the port and the IP
+    (data (i32.const 16) "\01\90\1f\7f\00\00\01")
+    ;; The iovec of the data sent
+    (data (i32.const 32) "\40\00\00\00\05\00\00\00")
+    ;; The iovec of the buffer for the data received
+    (data (i32.const 40) "\80\00\00\00\40\00\00\00")
+    (data (i32.const 64) "ping\n")
+
+    ;; Exits with `errno` when it isn't 0
+    (func $check (param $errno i32)
+        (if (local.get $errno)
+            (then (call $proc_exit (local.get $errno)))))
+
+    (func $main (export "_start")
+        (local $fd i32)
+        ;; An INET4 stream socket, stored at offset 0
+        (call $check (call $sock_open (i32.const 1) (i32.const 1) (i32.const 0) (i32.const 0)))
+        (local.set $fd (i32.load (i32.const 0)))
+        (call $check (call $sock_connect (local.get $fd) (i32.const 16)))
+        (call $check
+            (call $sock_send (local.get $fd) (i32.const 32) (i32.const 1) (i32.const 0) (i32.const 48)))
+        (call $check
+            (call $sock_recv
+                (local.get $fd) (i32.const 40) (i32.const 1) (i32.const 0) (i32.const 48) (i32.const 52)))
+        ;; Write the received bytes to stdout
+        (i32.store (i32.const 56) (i32.const 128))
+        (i32.store (i32.const 60) (i32.load (i32.const 48)))
+        (call $check (call $fd_write (i32.const 1) (i32.const 56) (i32.const 1) (i32.const 48)))
+    )
+)
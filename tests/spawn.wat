@@ -0,0 +1,38 @@
+(module
+    ;; Spawns registered commands with WASIX `proc_spawn`, inheriting the
+    ;; stdio, and polls their exits with `bus_poll`. Started, it spawns the
+    ;; command "hello" and exits with the returned errno.
+    (import "wasix_32v1" "proc_spawn"
+        (func $proc_spawn
+            (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasix_32v1" "proc_exit" (func $proc_exit (param i32)))
+    (import "wasix_32v1" "bus_poll"
+        (func $bus_poll (param i64 i32 i32 i32 i32 i32) (result i32)))
+
+    ;; 16: the name, 32: the returned handles, 48: the number of events,
+    ;; 64: an event
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 16) "hello")
+
+    ;; Spawns the command whose name is at `ptr`, returning the errno
+    (func $spawn (export "spawn") (param $ptr i32) (param $len i32) (result i32)
+        (call $proc_spawn
+            ;; The name, without chroot, arguments or preopens
+            (local.get $ptr) (local.get $len) (i32.const 0)
+            (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+            ;; Inherit stdin, stdout and stderr
+            (i32.const 2) (i32.const 2) (i32.const 2)
+            ;; No working directory
+            (i32.const 0) (i32.const 0)
+            (i32.const 32)))
+
+    ;; Polls at most one event, returning the errno
+    (func (export "poll") (result i32)
+        (call $bus_poll
+            (i64.const 0) (i32.const 64) (i32.const 1) (i32.const 0) (i32.const 0) (i32.const 48)))
+
+    (func $main (export "_start")
+        (call $proc_exit (call $spawn (i32.const 16) (i32.const 5))))
+)
@@ -0,0 +1,14 @@
+(module
+    ;; Calls imports that nobody provides, one of them with an i64 result
+    (import "env" "big" (func $big (result i64)))
+    (import "env" "small" (func $small (param i32) (result i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (func (export "big") (result i64)
+        (call $big))
+    (func (export "small") (result i32)
+        (call $small (i32.const 7)))
+    (func (export "_start"))
+)
@@ -0,0 +1,16 @@
+(module
+    ;; Writes "out\n" to stdout, then traps
+    (import "wasi_snapshot_preview1" "fd_write"
+        (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    ;; 0: the iovec, 32: the data, 48: the number of bytes written
+    (memory 1)
+    (export "memory" (memory 0))
+
+    (data (i32.const 0) "\20\00\00\00\04\00\00\00")
+    (data (i32.const 32) "out\n")
+
+    (func $main (export "_start")
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 48)))
+        (unreachable))
+)
@@ -0,0 +1,33 @@
+(module
+    ;; Queries what the guest sees of its terminal
+    (import "wasix_32v1" "fd_fdstat_get" (func $fd_fdstat_get (param i32 i32) (result i32)))
+    (import "wasix_32v1" "tty_get" (func $tty_get (param i32) (result i32)))
+
+    ;; 0: the fdstat, 32: the tty state
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The file type of `fd`, or the negated errno
+    (func (export "filetype") (param $fd i32) (result i32)
+        (local $errno i32)
+        (local.set $errno (call $fd_fdstat_get (local.get $fd) (i32.const 0)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load8_u (i32.const 0)))
+
+    ;; The field of the tty state at `offset`: 0 for the columns, 4 for the
+    ;; rows, or the negated errno
+    (func (export "tty") (param $offset i32) (result i32)
+        (local $errno i32)
+        (local.set $errno (call $tty_get (i32.const 32)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load (i32.add (i32.const 32) (local.get $offset))))
+
+    ;; Whether stdin (0), stdout (1) or stderr (2) is a tty
+    (func (export "isatty") (param $fd i32) (result i32)
+        (local $errno i32)
+        (local.set $errno (call $tty_get (i32.const 32)))
+        (if (local.get $errno) (then (return (i32.sub (i32.const 0) (local.get $errno)))))
+        (i32.load8_u (i32.add (i32.const 48) (local.get $fd))))
+
+    (func (export "_start"))
+)
@@ -0,0 +1,18 @@
+(module
+    ;; Writes the bytes that a test puts in the memory to a file descriptor,
+    ;; so that the test controls every write
+    (import "wasi_snapshot_preview1" "fd_write"
+        (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+    ;; 0: the iovec, 8: the number of bytes written, 16: the data
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; Writes `len` bytes at `ptr` to `fd`, returning the errno
+    (func (export "write") (param $fd i32) (param $ptr i32) (param $len i32) (result i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+        (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8)))
+
+    (func (export "_start"))
+)
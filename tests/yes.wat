@@ -0,0 +1,22 @@
+(module
+    ;; Writes 64 times 4096 bytes of "y\n" to stdout, and exits with 1 as soon
+    ;; as a write fails
+    (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+
+    (memory 1)
+    (export "memory" (memory 0))
+
+    ;; The iovec of the 4096 bytes at 64
+    (data (i32.const 0) "\40\00\00\00\00\10\00\00")
+    (data (i32.const 64) "y\0ay\0a...") ;; "y\n" 2048 times
+
+    (func $main (export "_start")
+        (local $i i32)
+        (loop $write
+            (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 16))
+                (then (call $proc_exit (i32.const 1))))
+            (br_if $write (i32.ne
+                (local.tee $i (i32.add (local.get $i) (i32.const 1)))
+                (i32.const 64)))))
+)
@@ -1,7 +1,7 @@
 // @deno-types="./pkg/wasmer_wasi_js.d.ts"
 import baseInit, { WASI, InitInput } from "./pkg/wasmer_wasi_js.js";
 // @deno-types="./pkg/wasmer_wasi_js.d.ts"
-export { WASI, MemFS, JSVirtualFile, WasmerRuntimeError } from "./pkg/wasmer_wasi_js.js";
+export { WASI, MemFS, HttpFS, Network, Shell, JSVirtualFile, pipeline, WasmerRuntimeError } from "./pkg/wasmer_wasi_js.js";
 
 let inited: Promise<any> | null = null;
 export const init = async (input?: InitInput | Promise<InitInput>, force?: boolean) => {